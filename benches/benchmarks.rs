@@ -1,6 +1,6 @@
 #[macro_use]
 extern crate criterion;
-use criterion::{black_box, Criterion};
+use criterion::Criterion;
 use orderlib::orderlib::{Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Limit};
 
 pub fn symmetric_buy_sell(c: &mut Criterion) {
@@ -0,0 +1,284 @@
+//! An async handle to an `OrderBook` running as a tokio task.  Enabled by the
+//! `async` feature.
+//!
+//! The task owns the book and takes commands from a bounded queue.  When the queue
+//! is full, commands fail straight away with `GatewayError::Busy` rather than
+//! waiting, so callers decide how to back off.  Fills and depth changes are
+//! broadcast to subscribers through a bounded ring; a subscriber that falls more
+//! than its capacity behind loses the oldest events and is told how many.
+use crate::orderlib::{ExecutionReport, Fill, Level, Order, OrderBook, OrderSide, RejectReason};
+use std::fmt;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GatewayError {
+    /// The command queue is full.
+    Busy,
+    /// The book task has stopped.
+    Stopped,
+    Rejected(RejectReason),
+    /// A subscriber fell behind and this many events were dropped.
+    Lagged(u64),
+}
+
+impl fmt::Display for GatewayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GatewayError::Busy => write!(f, "command queue is full"),
+            GatewayError::Stopped => write!(f, "order book task has stopped"),
+            GatewayError::Rejected(reason) => write!(f, "order rejected: {}", reason),
+            GatewayError::Lagged(missed) => write!(f, "subscriber missed {} events", missed),
+        }
+    }
+}
+
+impl std::error::Error for GatewayError {}
+
+/// Queue sizes and how much depth to publish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GatewayConfig {
+    pub commands: usize,
+    pub events: usize,
+    /// Number of levels per side carried by `BookEvent::Depth`.
+    pub depth: usize,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            commands: 1024,
+            events: 1024,
+            depth: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BookEvent {
+    Fill(Fill),
+    /// The top `GatewayConfig::depth` levels of one side, sent whenever they change.
+    Depth {
+        side: OrderSide,
+        levels: Vec<Level>,
+    },
+}
+
+enum Command {
+    Submit(
+        Order,
+        oneshot::Sender<Result<ExecutionReport, RejectReason>>,
+    ),
+    Cancel(i64, oneshot::Sender<Result<Option<Order>, RejectReason>>),
+}
+
+/// A cloneable handle to the book task.  The task stops once every handle has
+/// been dropped.
+#[derive(Clone, Debug)]
+pub struct BookHandle {
+    commands: mpsc::Sender<Command>,
+    events: broadcast::Sender<BookEvent>,
+}
+
+impl BookHandle {
+    /// Spawns the book task on the current tokio runtime.
+    pub fn spawn(book: OrderBook, config: GatewayConfig) -> BookHandle {
+        let (commands, receiver) = mpsc::channel(config.commands);
+        let (events, _) = broadcast::channel(config.events);
+        tokio::spawn(run(book, receiver, events.clone(), config.depth));
+        BookHandle { commands, events }
+    }
+
+    pub async fn submit(&self, order: Order) -> Result<ExecutionReport, GatewayError> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Submit(order, reply))?;
+        let report = result.await.map_err(|_| GatewayError::Stopped)?;
+        report.map_err(GatewayError::Rejected)
+    }
+
+    pub async fn cancel(&self, order_number: i64) -> Result<Option<Order>, GatewayError> {
+        let (reply, result) = oneshot::channel();
+        self.send(Command::Cancel(order_number, reply))?;
+        let cancelled = result.await.map_err(|_| GatewayError::Stopped)?;
+        cancelled.map_err(GatewayError::Rejected)
+    }
+
+    /// Receives every event published after this call.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            events: self.events.subscribe(),
+        }
+    }
+
+    fn send(&self, command: Command) -> Result<(), GatewayError> {
+        self.commands.try_send(command).map_err(|err| match err {
+            mpsc::error::TrySendError::Full(_) => GatewayError::Busy,
+            mpsc::error::TrySendError::Closed(_) => GatewayError::Stopped,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    events: broadcast::Receiver<BookEvent>,
+}
+
+impl Subscription {
+    /// The next event.  After a `Lagged` error, the following call returns the
+    /// oldest event still available.
+    pub async fn recv(&mut self) -> Result<BookEvent, GatewayError> {
+        self.events.recv().await.map_err(|err| match err {
+            broadcast::error::RecvError::Closed => GatewayError::Stopped,
+            broadcast::error::RecvError::Lagged(missed) => GatewayError::Lagged(missed),
+        })
+    }
+}
+
+impl fmt::Debug for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Submit(order, _) => write!(f, "Submit({:?})", order),
+            Command::Cancel(number, _) => write!(f, "Cancel({})", number),
+        }
+    }
+}
+
+async fn run(
+    mut book: OrderBook,
+    mut commands: mpsc::Receiver<Command>,
+    events: broadcast::Sender<BookEvent>,
+    depth: usize,
+) {
+    let mut published = [
+        book.depth(OrderSide::Buy, depth),
+        book.depth(OrderSide::Sell, depth),
+    ];
+    while let Some(command) = commands.recv().await {
+        // Sends fail only when nobody is listening, which is fine.
+        match command {
+            Command::Submit(order, reply) => {
+                let result = book.try_add(order);
+                if let Ok(report) = &result {
+                    for fill in report.fills.iter() {
+                        let _ = events.send(BookEvent::Fill(fill.clone()));
+                    }
+                }
+                let _ = reply.send(result);
+            }
+            Command::Cancel(number, reply) => {
+                let _ = reply.send(book.cancel(number));
+            }
+        }
+        for (side, last) in [OrderSide::Buy, OrderSide::Sell]
+            .into_iter()
+            .zip(published.iter_mut())
+        {
+            let levels = book.depth(side, depth);
+            if levels != *last {
+                *last = levels.clone();
+                let _ = events.send(BookEvent::Depth { side, levels });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BookEvent, BookHandle, GatewayConfig, GatewayError};
+    use crate::orderlib::{
+        Level, Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Limit,
+        OrderType::Market, RejectReason, SessionState,
+    };
+
+    fn level(price: i64, size: i64) -> Level {
+        Level {
+            price,
+            size,
+            orders: 1,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_submit_cancel_and_events() {
+        let handle = BookHandle::spawn(OrderBook::new(), GatewayConfig::default());
+        let mut events = handle.subscribe();
+        let bid = handle
+            .submit(Order::new(Buy, 10, 100, Limit))
+            .await
+            .unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BookEvent::Depth {
+                side: Buy,
+                levels: vec![level(100, 10)]
+            }
+        );
+        let report = handle.submit(Order::new(Sell, 4, 0, Market)).await.unwrap();
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BookEvent::Fill(report.fills[0].clone())
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BookEvent::Depth {
+                side: Buy,
+                levels: vec![level(100, 6)]
+            }
+        );
+        let cancelled = handle.cancel(bid.order_number).await.unwrap().unwrap();
+        assert_eq!(cancelled.size, 6);
+        assert_eq!(handle.cancel(bid.order_number).await, Ok(None));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BookEvent::Depth {
+                side: Buy,
+                levels: vec![]
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rejection() {
+        let mut book = OrderBook::new();
+        book.transition(SessionState::Halted).unwrap();
+        let handle = BookHandle::spawn(book, GatewayConfig::default());
+        assert_eq!(
+            handle
+                .submit(Order::new(Buy, 1, 0, Market))
+                .await
+                .unwrap_err(),
+            GatewayError::Rejected(RejectReason::Session(SessionState::Halted, Market))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backpressure_and_lag() {
+        let config = GatewayConfig {
+            commands: 1,
+            events: 2,
+            depth: 1,
+        };
+        let handle = BookHandle::spawn(OrderBook::new(), config);
+        let mut events = handle.subscribe();
+        // On a current-thread runtime the task cannot drain the queue until we
+        // yield, so the second command finds it full.
+        let first = handle.submit(Order::new(Buy, 1, 100, Limit));
+        let second = handle.submit(Order::new(Buy, 1, 101, Limit));
+        let (first, second) = tokio::join!(first, second);
+        assert!(first.is_ok());
+        assert_eq!(second.unwrap_err(), GatewayError::Busy);
+        for price in 102..105 {
+            handle
+                .submit(Order::new(Buy, 1, price, Limit))
+                .await
+                .unwrap();
+        }
+        assert_eq!(events.recv().await.unwrap_err(), GatewayError::Lagged(2));
+        assert_eq!(
+            events.recv().await.unwrap(),
+            BookEvent::Depth {
+                side: Buy,
+                levels: vec![level(103, 1)]
+            }
+        );
+    }
+}
@@ -0,0 +1,561 @@
+//! Replays historical market data through an `OrderBook` while a `Strategy`
+//! trades against it, and reports how the strategy's orders fared.
+//!
+//! Historical orders and the strategy's orders share one book, so the strategy's
+//! resting orders queue behind whatever was already at their price and get filled
+//! only when historical executions reach them.  L3 data (individual orders) is
+//! replayed as is.  L2 data (aggregate level sizes) is turned into synthetic
+//! orders: size added to a level joins the back of its queue, and size removed is
+//! taken from the most recently added synthetic orders, so cancellations never
+//! improve the strategy's place in the queue.
+use crate::orderlib::{
+    BookSnapshot, ExecutionReport, Fill, Order, OrderBook, OrderSide, OrderType, QueuePosition,
+    RejectReason, SnapshotError,
+};
+use std::collections::{HashMap, HashSet};
+
+/// One change to the historical book.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Update {
+    /// L3: an order arrives.  It trades if it crosses, as it would have.
+    Add {
+        id: i64,
+        side: OrderSide,
+        size: i64,
+        price: i64,
+    },
+    /// L3: a historical order is cancelled.
+    Cancel { id: i64 },
+    /// L3: `size` of a resting historical order was executed by an aggressor not
+    /// in the data.  Replayed as an `Ioc` at that order's price, which fills
+    /// anything ahead of it first, including the strategy's orders.
+    Execute { id: i64, size: i64 },
+    /// L2: the historical size at a price level is now `size`.
+    Level {
+        side: OrderSide,
+        price: i64,
+        size: i64,
+    },
+    /// L2: a trade of `size` at `price`, with `side` being the aggressor's side.
+    Trade {
+        side: OrderSide,
+        price: i64,
+        size: i64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MarketEvent {
+    pub timestamp: i64,
+    pub update: Update,
+}
+
+/// The strategy's handle on the simulation while it is being called.
+pub struct Context<'a> {
+    book: &'a mut OrderBook,
+    own: &'a mut HashSet<i64>,
+    fills: &'a mut Vec<Fill>,
+    orders: &'a mut usize,
+    cancels: &'a mut usize,
+    timestamp: i64,
+}
+
+impl Context<'_> {
+    pub fn book(&self) -> &OrderBook {
+        self.book
+    }
+
+    /// Time of the event being processed.
+    pub fn timestamp(&self) -> i64 {
+        self.timestamp
+    }
+
+    /// Sends an order to the book at the current event time.
+    pub fn submit(&mut self, order: Order) -> Result<ExecutionReport, RejectReason> {
+        let report = self.book.try_add_at(order, self.timestamp)?;
+        self.own.insert(report.order_number);
+        *self.orders += 1;
+        self.fills.extend(report.fills.iter().cloned());
+        Ok(report)
+    }
+
+    /// Cancels one of the strategy's resting orders.
+    pub fn cancel(&mut self, order_number: i64) -> Option<Order> {
+        if !self.own.contains(&order_number) {
+            return None;
+        }
+        // The backtester's book has no journal, so cancels cannot fail.
+        let order = self.book.cancel(order_number).ok().flatten()?;
+        *self.cancels += 1;
+        Some(order)
+    }
+
+    pub fn queue_position(&self, order_number: i64) -> Option<QueuePosition> {
+        self.book.queue_position(order_number)
+    }
+}
+
+/// Trading logic under test.
+pub trait Strategy {
+    /// Called after each market event has been applied to the book.
+    fn on_event(&mut self, event: &MarketEvent, ctx: &mut Context);
+
+    /// Called when a historical event fills one of the strategy's resting orders.
+    fn on_fill(&mut self, _fill: &Fill, _ctx: &mut Context) {}
+}
+
+/// Results of a backtest.  Cash and PnL are in price ticks times size.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BacktestReport {
+    /// Every fill involving one of the strategy's orders, with `direction` set to
+    /// the strategy's side of the trade.
+    pub fills: Vec<Fill>,
+    pub orders: usize,
+    pub cancels: usize,
+    pub bought: i64,
+    pub sold: i64,
+    pub position: i64,
+    pub max_position: i64,
+    pub cash: i64,
+    /// Price the final position is valued at: the mid if both sides are quoted,
+    /// otherwise the last trade.
+    pub mark: Option<f64>,
+    /// Cash plus the final position at `mark`; `None` if there is a position but
+    /// nothing to value it at.
+    pub pnl: Option<f64>,
+}
+
+impl BacktestReport {
+    pub fn average_buy_price(&self) -> Option<f64> {
+        self.average(OrderSide::Buy, self.bought)
+    }
+
+    pub fn average_sell_price(&self) -> Option<f64> {
+        self.average(OrderSide::Sell, self.sold)
+    }
+
+    fn average(&self, side: OrderSide, size: i64) -> Option<f64> {
+        if size == 0 {
+            return None;
+        }
+        let notional: i64 = self
+            .fills
+            .iter()
+            .filter(|fill| fill.direction == side)
+            .map(|fill| fill.size * fill.price)
+            .sum();
+        Some(notional as f64 / size as f64)
+    }
+}
+
+/// Drives a book with historical events and a strategy.
+#[derive(Debug)]
+pub struct Backtester {
+    book: OrderBook,
+    ids: HashMap<i64, i64>,
+    levels: HashMap<(bool, i64), Vec<i64>>,
+}
+
+impl Default for Backtester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Backtester {
+    pub fn new() -> Backtester {
+        Backtester {
+            book: OrderBook::new(),
+            ids: HashMap::new(),
+            levels: HashMap::new(),
+        }
+    }
+
+    /// Starts from a known book, e.g. the opening state of the day being tested.
+    /// Orders in the snapshot can be referred to by L3 events through their
+    /// `order_id`.  Fails if the snapshot does not restore.
+    pub fn from_snapshot(snapshot: &BookSnapshot) -> Result<Backtester, SnapshotError> {
+        let ids = snapshot
+            .bids
+            .iter()
+            .chain(snapshot.offers.iter())
+            .map(|order| (order.order_id, order.order_number))
+            .collect();
+        Ok(Backtester {
+            book: OrderBook::restore(snapshot)?,
+            ids,
+            levels: HashMap::new(),
+        })
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn run<S, I>(&mut self, events: I, strategy: &mut S) -> BacktestReport
+    where
+        S: Strategy,
+        I: IntoIterator<Item = MarketEvent>,
+    {
+        let mut own: HashSet<i64> = HashSet::new();
+        let mut fills: Vec<Fill> = Vec::new();
+        let (mut orders, mut cancels) = (0, 0);
+        for event in events {
+            let historical = self.apply(&event);
+            let mut ctx = Context {
+                book: &mut self.book,
+                own: &mut own,
+                fills: &mut fills,
+                orders: &mut orders,
+                cancels: &mut cancels,
+                timestamp: event.timestamp,
+            };
+            for fill in historical.iter() {
+                if ctx.own.contains(&fill.passive_number) {
+                    ctx.fills.push(fill.clone());
+                    strategy.on_fill(fill, &mut ctx);
+                }
+            }
+            strategy.on_event(&event, &mut ctx);
+        }
+        self.report(&own, fills, orders, cancels)
+    }
+
+    // Applies one historical event and returns the fills it caused.
+    fn apply(&mut self, event: &MarketEvent) -> Vec<Fill> {
+        let at = event.timestamp;
+        match event.update {
+            Update::Add {
+                id,
+                side,
+                size,
+                price,
+            } => {
+                let mut order = Order::new(side, size, price, OrderType::Limit);
+                order.order_id = id;
+                match self.book.try_add_at(order, at) {
+                    Ok(report) => {
+                        self.ids.insert(id, report.order_number);
+                        report.fills
+                    }
+                    Err(_) => Vec::new(),
+                }
+            }
+            Update::Cancel { id } => {
+                if let Some(number) = self.ids.remove(&id) {
+                    let _ = self.book.cancel(number);
+                }
+                Vec::new()
+            }
+            Update::Execute { id, size } => {
+                let Some(resting) = self.ids.get(&id).and_then(|n| self.book.order(*n)) else {
+                    return Vec::new();
+                };
+                let side = opposite(resting.order_side);
+                self.aggress(side, resting.price, size, at)
+            }
+            Update::Level { side, price, size } => self.set_level(side, price, size, at),
+            Update::Trade { side, price, size } => self.aggress(side, price, size, at),
+        }
+    }
+
+    fn aggress(&mut self, side: OrderSide, price: i64, size: i64, at: i64) -> Vec<Fill> {
+        let order = Order::new(side, size, price, OrderType::Ioc);
+        self.book
+            .try_add_at(order, at)
+            .map(|report| report.fills)
+            .unwrap_or_default()
+    }
+
+    fn set_level(&mut self, side: OrderSide, price: i64, size: i64, at: i64) -> Vec<Fill> {
+        let key = (side == OrderSide::Buy, price);
+        let book = &self.book;
+        let synthetic = self.levels.entry(key).or_default();
+        // Forget the orders that have traded away while totalling the rest.
+        let mut current = 0;
+        synthetic.retain(|number| match book.order(*number) {
+            Some(order) => {
+                current += order.size;
+                true
+            }
+            None => false,
+        });
+        if size > current {
+            let order = Order::new(side, size - current, price, OrderType::Limit);
+            return match self.book.try_add_at(order, at) {
+                Ok(report) => {
+                    if report.remaining > 0 {
+                        synthetic.push(report.order_number);
+                    }
+                    report.fills
+                }
+                Err(_) => Vec::new(),
+            };
+        }
+        let mut excess = current - size;
+        while excess > 0 {
+            let Some(number) = synthetic.last().copied() else {
+                break;
+            };
+            let mut order = self.book.order(number).unwrap();
+            if order.size <= excess {
+                excess -= order.size;
+                let _ = self.book.cancel(number);
+                synthetic.pop();
+            } else {
+                order.size -= excess;
+                let _ = self.book.replace(order);
+                excess = 0;
+            }
+        }
+        Vec::new()
+    }
+
+    fn report(
+        &self,
+        own: &HashSet<i64>,
+        mut fills: Vec<Fill>,
+        orders: usize,
+        cancels: usize,
+    ) -> BacktestReport {
+        let (mut bought, mut sold, mut position, mut max_position, mut cash) =
+            (0, 0, 0i64, 0i64, 0);
+        for fill in fills.iter_mut() {
+            if !own.contains(&fill.aggressor_number) {
+                fill.direction = opposite(fill.direction);
+            }
+            match fill.direction {
+                OrderSide::Buy => {
+                    bought += fill.size;
+                    position += fill.size;
+                    cash -= fill.size * fill.price;
+                }
+                OrderSide::Sell => {
+                    sold += fill.size;
+                    position -= fill.size;
+                    cash += fill.size * fill.price;
+                }
+            }
+            max_position = max_position.max(position.abs());
+        }
+        let mark = self
+            .book
+            .mid()
+            .or(self.book.last_trade().map(|price| price as f64));
+        let pnl = match (position, mark) {
+            (0, _) => Some(cash as f64),
+            (_, Some(mark)) => Some(cash as f64 + position as f64 * mark),
+            (_, None) => None,
+        };
+        BacktestReport {
+            fills,
+            orders,
+            cancels,
+            bought,
+            sold,
+            position,
+            max_position,
+            cash,
+            mark,
+            pnl,
+        }
+    }
+}
+
+fn opposite(side: OrderSide) -> OrderSide {
+    match side {
+        OrderSide::Buy => OrderSide::Sell,
+        OrderSide::Sell => OrderSide::Buy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Backtester, Context, MarketEvent, Strategy, Update};
+    use crate::orderlib::{Fill, Order, OrderSide::*, OrderType::*};
+    use std::collections::HashSet;
+
+    // Joins the bid at 100 on the first event and does nothing else.
+    struct JoinBid {
+        order: Option<i64>,
+        ahead: Vec<(i64, usize)>,
+    }
+
+    impl Strategy for JoinBid {
+        fn on_event(&mut self, _event: &MarketEvent, ctx: &mut Context) {
+            match self.order {
+                None => {
+                    let report = ctx.submit(Order::new(Buy, 5, 100, Limit)).unwrap();
+                    self.order = Some(report.order_number);
+                }
+                Some(number) => {
+                    if let Some(position) = ctx.queue_position(number) {
+                        self.ahead
+                            .push((position.size_ahead, position.orders_ahead));
+                    }
+                }
+            }
+        }
+    }
+
+    fn event(timestamp: i64, update: Update) -> MarketEvent {
+        MarketEvent { timestamp, update }
+    }
+
+    #[test]
+    fn test_l3_queue_position() {
+        let events = vec![
+            event(
+                1,
+                Update::Add {
+                    id: 1,
+                    side: Buy,
+                    size: 10,
+                    price: 100,
+                },
+            ),
+            event(
+                2,
+                Update::Add {
+                    id: 2,
+                    side: Sell,
+                    size: 10,
+                    price: 101,
+                },
+            ),
+            event(
+                3,
+                Update::Add {
+                    id: 3,
+                    side: Buy,
+                    size: 4,
+                    price: 100,
+                },
+            ),
+            event(4, Update::Execute { id: 1, size: 6 }),
+            event(5, Update::Execute { id: 3, size: 6 }),
+        ];
+        let mut strategy = JoinBid {
+            order: None,
+            ahead: vec![],
+        };
+        let mut backtest = Backtester::new();
+        let report = backtest.run(events, &mut strategy);
+        // Joined behind order 1 at event 1; order 3 queued behind us.
+        assert_eq!(strategy.ahead, vec![(10, 1), (10, 1), (4, 1), (0, 0)]);
+        assert_eq!(report.orders, 1);
+        assert_eq!(report.bought, 2);
+        assert_eq!(report.position, 2);
+        assert_eq!(report.cash, -200);
+        assert_eq!(report.fills[0].direction, Buy);
+        assert_eq!(report.average_buy_price(), Some(100.0));
+        assert_eq!(report.average_sell_price(), None);
+        // Mid of 100 and 101.
+        assert_eq!(report.mark, Some(100.5));
+        assert_eq!(report.pnl, Some(1.0));
+    }
+
+    #[test]
+    fn test_unmarked_position_has_no_pnl() {
+        let backtest = Backtester::new();
+        let fill = Fill {
+            size: 2,
+            price: 100,
+            direction: Buy,
+            aggressor_id: 0,
+            passive_id: 0,
+            timestamp: 1,
+            fill_id: 1,
+            aggressor_number: 7,
+            passive_number: 8,
+            aggressor_account: 0,
+            passive_account: 0,
+            aggressor_fee: 0,
+            passive_fee: 0,
+        };
+        let report = backtest.report(&HashSet::from([7]), vec![fill], 1, 0);
+        assert_eq!((report.position, report.mark, report.pnl), (2, None, None));
+        let flat = backtest.report(&HashSet::new(), Vec::new(), 0, 0);
+        assert_eq!(flat.pnl, Some(0.0));
+    }
+
+    #[test]
+    fn test_l2_cancels_come_from_back_of_queue() {
+        let events = vec![
+            event(
+                1,
+                Update::Level {
+                    side: Buy,
+                    price: 100,
+                    size: 10,
+                },
+            ),
+            event(
+                2,
+                Update::Level {
+                    side: Buy,
+                    price: 100,
+                    size: 20,
+                },
+            ),
+            event(
+                3,
+                Update::Level {
+                    side: Buy,
+                    price: 100,
+                    size: 25,
+                },
+            ),
+            event(
+                4,
+                Update::Level {
+                    side: Buy,
+                    price: 100,
+                    size: 12,
+                },
+            ),
+            event(
+                5,
+                Update::Trade {
+                    side: Sell,
+                    price: 100,
+                    size: 13,
+                },
+            ),
+        ];
+        let mut strategy = JoinBid {
+            order: None,
+            ahead: vec![],
+        };
+        let mut backtest = Backtester::new();
+        let report = backtest.run(events, &mut strategy);
+        // Size added after we joined sits behind us and is cancelled first.
+        assert_eq!(strategy.ahead, vec![(10, 1), (10, 1), (10, 1), (0, 0)]);
+        assert_eq!(report.bought, 3);
+        assert_eq!(report.fills[0].price, 100);
+        assert_eq!(
+            backtest
+                .book()
+                .order(report.fills[0].passive_number)
+                .unwrap()
+                .size,
+            2
+        );
+    }
+
+    #[test]
+    fn test_from_snapshot_maps_order_ids() {
+        let mut book = crate::orderlib::OrderBook::new();
+        let mut order = Order::new(Sell, 10, 105, Limit);
+        order.order_id = 77;
+        book.add(order);
+        let mut backtest = Backtester::from_snapshot(&book.snapshot()).unwrap();
+        let mut strategy = JoinBid {
+            order: None,
+            ahead: vec![],
+        };
+        backtest.run(vec![event(1, Update::Cancel { id: 77 })], &mut strategy);
+        assert_eq!(backtest.book().len_offers(), 0);
+        assert_eq!(backtest.book().len_bids(), 1);
+    }
+}
@@ -0,0 +1,353 @@
+//! Offline processing of order command files, for regression scenarios and for
+//! replaying production incidents.
+//!
+//! Commands are read either as CSV, one command per line:
+//!
+//! ```text
+//! action,side,size,price,type,order_id
+//! add,buy,20,100,limit,7
+//! add,sell,31,,market
+//! cancel,1231
+//! session,halted
+//! ```
+//!
+//! or as newline-delimited JSON with the same field names:
+//!
+//! ```text
+//! {"action":"add","side":"buy","size":20,"price":100,"type":"limit","order_id":7}
+//! {"action":"cancel","order_number":1231}
+//! ```
+//!
+//! Blank lines and lines starting with `#` are ignored, as is a CSV header line.
+use crate::json::{self, Value};
+use crate::orderlib::{Fill, Order, OrderBook, OrderSide, OrderType, SessionState};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl Format {
+    /// Picks the format from a file name: `.ndjson`, `.jsonl` and `.json` are
+    /// NDJSON, anything else is CSV.
+    pub fn from_path(path: &str) -> Format {
+        let lower = path.to_lowercase();
+        if [".ndjson", ".jsonl", ".json"]
+            .iter()
+            .any(|ext| lower.ends_with(ext))
+        {
+            Format::Ndjson
+        } else {
+            Format::Csv
+        }
+    }
+}
+
+/// One line of a command file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BatchCommand {
+    Add(Order),
+    Cancel(i64),
+    Session(SessionState),
+}
+
+#[derive(Debug)]
+pub enum BatchError {
+    Io(io::Error),
+    /// A line that is not a valid command, numbered from 1.
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for BatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BatchError::Io(error) => write!(f, "{}", error),
+            BatchError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for BatchError {}
+
+impl From<io::Error> for BatchError {
+    fn from(error: io::Error) -> BatchError {
+        BatchError::Io(error)
+    }
+}
+
+/// What a batch run did.
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    pub commands: usize,
+    pub fills: Vec<Fill>,
+    /// Commands the book refused, with their line number and the reason.
+    pub rejected: Vec<(usize, String)>,
+}
+
+/// Parses one non-blank line.
+pub fn parse_line(line: &str, format: Format) -> Result<BatchCommand, String> {
+    match format {
+        Format::Csv => {
+            let fields: Vec<String> = line
+                .split(',')
+                .map(|field| field.trim().to_lowercase())
+                .collect();
+            let fields: Vec<&str> = fields.iter().map(String::as_str).collect();
+            match fields.as_slice() {
+                ["add", side, size, price, rest @ ..] => {
+                    let order_type = rest.first().copied().unwrap_or("limit");
+                    let order_id = rest.get(1).copied().unwrap_or("");
+                    build_order(
+                        side,
+                        size.parse().ok(),
+                        if price.is_empty() {
+                            None
+                        } else {
+                            Some(price.parse().map_err(|_| "invalid price".to_string())?)
+                        },
+                        order_type,
+                        if order_id.is_empty() {
+                            None
+                        } else {
+                            Some(
+                                order_id
+                                    .parse()
+                                    .map_err(|_| "invalid order_id".to_string())?,
+                            )
+                        },
+                    )
+                }
+                ["cancel", number, ..] => number
+                    .parse()
+                    .map(BatchCommand::Cancel)
+                    .map_err(|_| "invalid order number".to_string()),
+                ["session", state, ..] => session(state),
+                _ => Err("expected add, cancel or session".to_string()),
+            }
+        }
+        Format::Ndjson => {
+            let value = json::parse(line).map_err(|pos| format!("malformed JSON at {}", pos))?;
+            let text = |key: &str| {
+                value
+                    .get(key)
+                    .and_then(Value::as_str)
+                    .map(str::to_lowercase)
+            };
+            let number = |key: &str| value.get(key).and_then(Value::as_i64);
+            match text("action").as_deref() {
+                Some("add") => build_order(
+                    &text("side").unwrap_or_default(),
+                    number("size"),
+                    number("price"),
+                    &text("type").unwrap_or_else(|| "limit".to_string()),
+                    number("order_id"),
+                ),
+                Some("cancel") => number("order_number")
+                    .map(BatchCommand::Cancel)
+                    .ok_or_else(|| "cancel needs an order_number".to_string()),
+                Some("session") => session(&text("state").unwrap_or_default()),
+                _ => Err("expected action add, cancel or session".to_string()),
+            }
+        }
+    }
+}
+
+fn build_order(
+    side: &str,
+    size: Option<i64>,
+    price: Option<i64>,
+    order_type: &str,
+    order_id: Option<i64>,
+) -> Result<BatchCommand, String> {
+    let side = match side {
+        "buy" => OrderSide::Buy,
+        "sell" => OrderSide::Sell,
+        _ => return Err("side must be buy or sell".to_string()),
+    };
+    let size = size
+        .filter(|size| *size > 0)
+        .ok_or("size must be a positive integer")?;
+    let order_type = match order_type {
+        "limit" => OrderType::Limit,
+        "market" => OrderType::Market,
+        "ioc" => OrderType::Ioc,
+        "fok" => OrderType::Fok,
+        "aon" => OrderType::Aon,
+        _ => return Err(format!("unknown order type {}", order_type)),
+    };
+    let price = match (price, order_type) {
+        (Some(price), _) => price,
+        (None, OrderType::Market) => 0,
+        (None, _) => return Err("price is required unless the order is market".to_string()),
+    };
+    let mut order = Order::new(side, size, price, order_type);
+    order.order_id = order_id.unwrap_or(0);
+    Ok(BatchCommand::Add(order))
+}
+
+fn session(state: &str) -> Result<BatchCommand, String> {
+    match state {
+        "preopen" => Ok(BatchCommand::Session(SessionState::PreOpen)),
+        "auction" => Ok(BatchCommand::Session(SessionState::OpeningAuction)),
+        "continuous" => Ok(BatchCommand::Session(SessionState::Continuous)),
+        "halted" => Ok(BatchCommand::Session(SessionState::Halted)),
+        "closed" => Ok(BatchCommand::Session(SessionState::Closed)),
+        _ => Err(format!("unknown session state {}", state)),
+    }
+}
+
+/// Applies every command in `input` to `book`.  A malformed line stops the run;
+/// commands the book refuses are recorded in the summary and skipped.
+pub fn run<R: BufRead>(
+    input: R,
+    format: Format,
+    book: &mut OrderBook,
+) -> Result<BatchSummary, BatchError> {
+    let mut summary = BatchSummary::default();
+    for (i, line) in input.lines().enumerate() {
+        let line = line?;
+        let trimmed = line.trim();
+        if trimmed.is_empty()
+            || trimmed.starts_with('#')
+            || (format == Format::Csv && i == 0 && trimmed.starts_with("action"))
+        {
+            continue;
+        }
+        let command = parse_line(trimmed, format).map_err(|message| BatchError::Parse {
+            line: i + 1,
+            message,
+        })?;
+        summary.commands += 1;
+        match command {
+            BatchCommand::Add(order) => match book.try_add(order) {
+                Ok(report) => summary.fills.extend(report.fills),
+                Err(reason) => summary.rejected.push((i + 1, reason.to_string())),
+            },
+            BatchCommand::Cancel(number) => match book.cancel(number) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    let reason = format!("no resting order #{}", number);
+                    summary.rejected.push((i + 1, reason));
+                }
+                Err(reason) => summary.rejected.push((i + 1, reason.to_string())),
+            },
+            BatchCommand::Session(state) => match book.transition(state) {
+                Ok(fills) => summary.fills.extend(fills),
+                Err(error) => summary.rejected.push((i + 1, error.to_string())),
+            },
+        }
+    }
+    Ok(summary)
+}
+
+/// Writes fills as CSV with a header line.
+pub fn write_fills<W: Write>(out: &mut W, fills: &[Fill]) -> io::Result<()> {
+    writeln!(
+        out,
+        "timestamp,direction,size,price,aggressor_number,passive_number,aggressor_id,passive_id"
+    )?;
+    for fill in fills.iter() {
+        writeln!(
+            out,
+            "{},{:?},{},{},{},{},{},{}",
+            fill.timestamp,
+            fill.direction,
+            fill.size,
+            fill.price,
+            fill.aggressor_number,
+            fill.passive_number,
+            fill.aggressor_id,
+            fill.passive_id
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_line, run, write_fills, BatchCommand, BatchError, Format};
+    use crate::orderlib::{OrderBook, OrderSide::Sell, OrderType::Market};
+
+    #[test]
+    fn test_csv_and_ndjson_agree() {
+        let csv = "action,side,size,price,type,order_id\n\
+                   add,buy,20,100,limit,7\n\
+                   add,buy,20,101\n\
+                   \n\
+                   # comment\n\
+                   add,sell,31,,market,9\n\
+                   cancel,1230\n\
+                   cancel,1230\n";
+        let ndjson = r#"{"action":"add","side":"buy","size":20,"price":100,"type":"limit","order_id":7}
+{"action":"add","side":"buy","size":20,"price":101}
+{"action":"add","side":"sell","size":31,"type":"market","order_id":9}
+{"action":"cancel","order_number":1230}
+{"action":"cancel","order_number":1230}
+"#;
+        let mut csv_book = OrderBook::new();
+        let csv_summary = run(csv.as_bytes(), Format::Csv, &mut csv_book).unwrap();
+        let mut json_book = OrderBook::new();
+        let json_summary = run(ndjson.as_bytes(), Format::Ndjson, &mut json_book).unwrap();
+        assert_eq!(csv_summary.commands, 5);
+        assert_eq!(csv_summary.fills.len(), 2);
+        assert_eq!(csv_summary.fills[1].aggressor_id, 9);
+        assert_eq!(
+            csv_summary.rejected,
+            vec![(8, "no resting order #1230".into())]
+        );
+        assert_eq!(json_summary.rejected[0].0, 5);
+        assert_eq!(csv_book.len_bids(), 0);
+        assert_eq!(csv_book.snapshot(), json_book.snapshot());
+
+        let mut out: Vec<u8> = Vec::new();
+        write_fills(&mut out, &csv_summary.fills).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(",Sell,20,101,1232,1231,9,0"));
+    }
+
+    #[test]
+    fn test_parse_errors_stop_the_run() {
+        assert!(matches!(
+            parse_line("add,sell,5,,market", Format::Csv),
+            Ok(BatchCommand::Add(order)) if order.order_side == Sell && order.order_type == Market
+        ));
+        assert!(parse_line("add,buy,5,,limit", Format::Csv).is_err());
+        assert!(parse_line("{\"action\":\"add\"", Format::Ndjson).is_err());
+        let mut order_book = OrderBook::new();
+        match run(
+            "add,buy,1,1\nbogus\n".as_bytes(),
+            Format::Csv,
+            &mut order_book,
+        ) {
+            Err(BatchError::Parse { line, .. }) => assert_eq!(line, 2),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(Format::from_path("orders.JSONL"), Format::Ndjson);
+        assert_eq!(Format::from_path("orders.txt"), Format::Csv);
+    }
+
+    #[test]
+    fn test_market_remainder_is_cancelled() {
+        let mut order_book = OrderBook::new();
+        let summary = run(
+            "add,sell,5,100\nadd,buy,8,,market\n".as_bytes(),
+            Format::Csv,
+            &mut order_book,
+        )
+        .unwrap();
+        assert_eq!(summary.fills.len(), 1);
+        assert_eq!(order_book.len_bids(), 0);
+        assert_eq!(order_book.best_bid(), None);
+    }
+}
@@ -0,0 +1,26 @@
+//! FIX 4.4 order-entry gateway in front of a single `OrderBook`.
+//!
+//! Usage: fix_gateway [ADDRESS] [COMP_ID]   (defaults: 127.0.0.1:9878 ORDERLIB)
+use orderlib::fix;
+use orderlib::orderlib::OrderBook;
+use std::env;
+use std::net::TcpListener;
+use std::process;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let address = args.next().unwrap_or_else(|| "127.0.0.1:9878".to_string());
+    let comp_id = args.next().unwrap_or_else(|| "ORDERLIB".to_string());
+    let listener = match TcpListener::bind(&address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("cannot listen on {}: {}", address, error);
+            process::exit(1);
+        }
+    };
+    println!("{} accepting FIX sessions on {}", comp_id, address);
+    if let Err(error) = fix::serve(listener, &comp_id, OrderBook::new()) {
+        eprintln!("gateway stopped: {}", error);
+        process::exit(1);
+    }
+}
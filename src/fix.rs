@@ -0,0 +1,1213 @@
+//! A minimal FIX 4.4 order-entry gateway in front of an `OrderBook`.
+//!
+//! Clients log on, then send NewOrderSingle (D), OrderCancelRequest (F) and
+//! OrderCancelReplaceRequest (G).  Each is mapped onto `OrderBook::try_add`,
+//! `remove` and `add`, and answered with ExecutionReports (8), including reports
+//! to the resting side of every fill.  Prices travel as integer ticks, exactly as
+//! the book stores them.  A market order may give CashOrderQty (152) instead of
+//! OrderQty to spend a fixed amount.  A numeric Account (1) becomes
+//! `Order::account`, and fill reports carry the fill's fee as Commission (12).
+//!
+//! Session handling is deliberately thin: incoming MsgSeqNum (34) is not checked
+//! for gaps or duplicates, nothing is resent, and the gateway neither sends
+//! heartbeats on its own nor drops a silent counterparty.  It answers a
+//! TestRequest (1) with a Heartbeat (0) and ignores heartbeats it receives.
+use crate::orderlib::{
+    get_epoch_ms, ExecutionReport, Fill, Order, OrderBook, OrderSide, OrderType,
+};
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+pub const BEGIN_STRING: &str = "FIX.4.4";
+const SOH: u8 = 0x01;
+/// Largest BodyLength accepted.  Order entry messages are far smaller, so anything
+/// longer is treated as garbage rather than buffered.
+pub const MAX_BODY_LENGTH: usize = 8192;
+// BeginString and BodyLength must each end within this many bytes.
+const MAX_HEADER_FIELD: usize = 16;
+
+pub mod tag {
+    pub const ACCOUNT: u32 = 1;
+    pub const AVG_PX: u32 = 6;
+    pub const BEGIN_STRING: u32 = 8;
+    pub const BODY_LENGTH: u32 = 9;
+    pub const CHECK_SUM: u32 = 10;
+    pub const CL_ORD_ID: u32 = 11;
+    pub const COMMISSION: u32 = 12;
+    pub const COMM_TYPE: u32 = 13;
+    pub const CUM_QTY: u32 = 14;
+    pub const EXEC_ID: u32 = 17;
+    pub const EXEC_INST: u32 = 18;
+    pub const LAST_PX: u32 = 31;
+    pub const LAST_QTY: u32 = 32;
+    pub const MSG_SEQ_NUM: u32 = 34;
+    pub const MSG_TYPE: u32 = 35;
+    pub const ORDER_ID: u32 = 37;
+    pub const ORDER_QTY: u32 = 38;
+    pub const ORD_STATUS: u32 = 39;
+    pub const ORD_TYPE: u32 = 40;
+    pub const ORIG_CL_ORD_ID: u32 = 41;
+    pub const PRICE: u32 = 44;
+    pub const REF_SEQ_NUM: u32 = 45;
+    pub const SENDER_COMP_ID: u32 = 49;
+    pub const SENDING_TIME: u32 = 52;
+    pub const SIDE: u32 = 54;
+    pub const TARGET_COMP_ID: u32 = 56;
+    pub const TEXT: u32 = 58;
+    pub const TIME_IN_FORCE: u32 = 59;
+    pub const ENCRYPT_METHOD: u32 = 98;
+    pub const CXL_REJ_REASON: u32 = 102;
+    pub const HEART_BT_INT: u32 = 108;
+    pub const TEST_REQ_ID: u32 = 112;
+    pub const EXEC_TYPE: u32 = 150;
+    pub const LEAVES_QTY: u32 = 151;
+    pub const CASH_ORDER_QTY: u32 = 152;
+    pub const BUSINESS_REJECT_REASON: u32 = 380;
+    pub const REF_MSG_TYPE: u32 = 372;
+    pub const CXL_REJ_RESPONSE_TO: u32 = 434;
+}
+
+/// A FIX message body: every field except BeginString, BodyLength and CheckSum,
+/// which are produced by `encode` and checked by `decode`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub fields: Vec<(u32, String)>,
+}
+
+impl Message {
+    pub fn new(msg_type: &str) -> Message {
+        Message {
+            fields: vec![(tag::MSG_TYPE, msg_type.to_string())],
+        }
+    }
+
+    pub fn with<V: ToString>(mut self, tag: u32, value: V) -> Message {
+        self.fields.push((tag, value.to_string()));
+        self
+    }
+
+    pub fn get(&self, tag: u32) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_i64(&self, tag: u32) -> Option<i64> {
+        self.get(tag).and_then(|v| v.parse().ok())
+    }
+
+    pub fn msg_type(&self) -> &str {
+        self.get(tag::MSG_TYPE).unwrap_or("")
+    }
+
+    /// Serializes the message with a FIX 4.4 header and trailer.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body: Vec<u8> = Vec::new();
+        for (tag, value) in self.fields.iter() {
+            body.extend_from_slice(format!("{}={}", tag, value).as_bytes());
+            body.push(SOH);
+        }
+        let mut out: Vec<u8> = Vec::with_capacity(body.len() + 32);
+        out.extend_from_slice(format!("8={}\x019={}\x01", BEGIN_STRING, body.len()).as_bytes());
+        out.extend_from_slice(&body);
+        let sum = checksum(&out);
+        out.extend_from_slice(format!("10={:03}\x01", sum).as_bytes());
+        out
+    }
+
+    /// Reads one message from the front of `buf`.  Returns `None` if more bytes
+    /// are needed, otherwise the message and the number of bytes it took up.  A
+    /// BodyLength over `MAX_BODY_LENGTH` is `Garbled`.
+    pub fn decode(buf: &[u8]) -> Result<Option<(Message, usize)>, FixError> {
+        let Some(first) = buf.iter().position(|b| *b == SOH) else {
+            return if buf.len() > MAX_HEADER_FIELD {
+                Err(FixError::Garbled)
+            } else {
+                Ok(None)
+            };
+        };
+        if !buf.starts_with(b"8=") || first > MAX_HEADER_FIELD {
+            return Err(FixError::Garbled);
+        }
+        let rest = &buf[first + 1..];
+        let Some(second) = rest.iter().position(|b| *b == SOH) else {
+            return if rest.len() > MAX_HEADER_FIELD {
+                Err(FixError::Garbled)
+            } else {
+                Ok(None)
+            };
+        };
+        let length: usize = std::str::from_utf8(&rest[..second])
+            .ok()
+            .and_then(|field| field.strip_prefix("9="))
+            .and_then(|len| len.parse().ok())
+            .filter(|len| *len <= MAX_BODY_LENGTH)
+            .ok_or(FixError::Garbled)?;
+        let body_start = first + 1 + second + 1;
+        let body_end = body_start.checked_add(length).ok_or(FixError::Garbled)?;
+        let total = body_end.checked_add(7).ok_or(FixError::Garbled)?;
+        if buf.len() < total {
+            return Ok(None);
+        }
+        let trailer = &buf[body_end..total];
+        if !trailer.starts_with(b"10=") || trailer[6] != SOH {
+            return Err(FixError::Garbled);
+        }
+        let expected: u8 = std::str::from_utf8(&trailer[3..6])
+            .ok()
+            .and_then(|sum| sum.parse().ok())
+            .ok_or(FixError::Garbled)?;
+        if checksum(&buf[..body_end]) != expected {
+            return Err(FixError::Checksum);
+        }
+        let mut fields: Vec<(u32, String)> = Vec::new();
+        for field in buf[body_start..body_end].split(|b| *b == SOH) {
+            if field.is_empty() {
+                continue;
+            }
+            let text = std::str::from_utf8(field).map_err(|_| FixError::Garbled)?;
+            let (tag, value) = text.split_once('=').ok_or(FixError::Garbled)?;
+            fields.push((
+                tag.parse().map_err(|_| FixError::Garbled)?,
+                value.to_string(),
+            ));
+        }
+        if fields.first().map(|(tag, _)| *tag) != Some(tag::MSG_TYPE) {
+            return Err(FixError::Garbled);
+        }
+        Ok(Some((Message { fields }, total)))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FixError {
+    /// The bytes are not a well-formed FIX message.
+    Garbled,
+    /// The CheckSum field does not match the message.
+    Checksum,
+}
+
+impl fmt::Display for FixError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FixError::Garbled => write!(f, "malformed FIX message"),
+            FixError::Checksum => write!(f, "FIX checksum mismatch"),
+        }
+    }
+}
+
+impl std::error::Error for FixError {}
+
+/// Formats epoch milliseconds as a FIX UTCTimestamp, e.g. `20240301-14:05:09.123`.
+pub fn utc_timestamp(epoch_ms: i64) -> String {
+    let days = epoch_ms.div_euclid(86_400_000);
+    let ms = epoch_ms.rem_euclid(86_400_000);
+    // Civil-from-days, proleptic Gregorian calendar.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}{:02}{:02}-{:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        ms / 3_600_000,
+        ms / 60_000 % 60,
+        ms / 1000 % 60,
+        ms % 1000
+    )
+}
+
+// What the gateway remembers about an order it has sent to the book.
+#[derive(Clone, Debug)]
+struct OpenOrder {
+    owner: String,
+    cl_ord_id: String,
+    order: Order,
+    quantity: i64,
+    cum_qty: i64,
+    notional: i64,
+}
+
+impl OpenOrder {
+    fn leaves_qty(&self) -> i64 {
+        self.quantity - self.cum_qty
+    }
+
+    fn avg_px(&self) -> String {
+        if self.cum_qty == 0 {
+            "0".to_string()
+        } else {
+            format!("{}", self.notional as f64 / self.cum_qty as f64)
+        }
+    }
+}
+
+/// The application layer of the gateway: turns order-entry messages from a
+/// counterparty into book operations and the resulting messages into
+/// `(target comp id, message)` pairs.  Session-level concerns live in `serve`.
+#[derive(Debug)]
+pub struct Gateway {
+    book: OrderBook,
+    orders: HashMap<i64, OpenOrder>,
+    by_cl_ord_id: HashMap<(String, String), i64>,
+    // `Order::session_id` for each counterparty, kept across reconnects.
+    sessions: HashMap<String, i64>,
+    next_order_id: i64,
+    next_exec_id: i64,
+}
+
+impl Gateway {
+    pub fn new(book: OrderBook) -> Gateway {
+        Gateway {
+            book,
+            orders: HashMap::new(),
+            by_cl_ord_id: HashMap::new(),
+            sessions: HashMap::new(),
+            next_order_id: 1,
+            next_exec_id: 1,
+        }
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    /// Called when `sender` logs on, so that orders left from an earlier
+    /// connection are kept if their cancel-on-disconnect grace period is running.
+    /// A journal failure leaves the cancellation pending, as it does every other
+    /// change to the book.
+    pub fn logon(&mut self, sender: &str) -> bool {
+        let session_id = self.session_id(sender);
+        self.book.reconnect(session_id).unwrap_or(false)
+    }
+
+    /// Called when `sender`'s connection ends.  With cancel-on-disconnect set on
+    /// the book and no grace period, its orders are cancelled now and the reports
+    /// returned.  If the journal cannot be written nothing is cancelled.
+    pub fn disconnect(&mut self, sender: &str, now: i64) -> Vec<(String, Message)> {
+        let session_id = self.session_id(sender);
+        let cancelled = self.book.disconnect(session_id, now).unwrap_or_default();
+        self.report_cancelled(cancelled)
+    }
+
+    /// Cancels the orders of counterparties whose grace period has run out.
+    pub fn expire_disconnects(&mut self, now: i64) -> Vec<(String, Message)> {
+        let cancelled = self.book.expire_disconnects(now);
+        self.report_cancelled(cancelled)
+    }
+
+    fn session_id(&mut self, sender: &str) -> i64 {
+        let next = self.sessions.len() as i64 + 1;
+        *self.sessions.entry(sender.to_string()).or_insert(next)
+    }
+
+    // Reports orders the book cancelled on our behalf to their owners.
+    fn report_cancelled(&mut self, cancelled: Vec<Order>) -> Vec<(String, Message)> {
+        let mut out = Vec::new();
+        for order in cancelled {
+            if let Some(mut open) = self.forget(order.order_id) {
+                open.quantity = open.cum_qty;
+                let message = self.execution(&open, "4", None);
+                out.push((open.owner.clone(), message));
+            }
+        }
+        out
+    }
+
+    /// Handles one application message from `sender`.
+    pub fn handle(&mut self, sender: &str, message: &Message) -> Vec<(String, Message)> {
+        match message.msg_type() {
+            "D" => self.new_order(sender, message),
+            "F" => self.cancel(sender, message),
+            "G" => self.cancel_replace(sender, message),
+            other => vec![(
+                sender.to_string(),
+                Message::new("j")
+                    .with(tag::REF_MSG_TYPE, other)
+                    .with(tag::BUSINESS_REJECT_REASON, 3)
+                    .with(tag::TEXT, "unsupported message type"),
+            )],
+        }
+    }
+
+    fn new_order(&mut self, sender: &str, message: &Message) -> Vec<(String, Message)> {
+        let cl_ord_id = message.get(tag::CL_ORD_ID).unwrap_or("").to_string();
+        let order = match parse_order(message) {
+            Ok(order) => order,
+            Err(text) => return vec![(sender.to_string(), reject(message, &cl_ord_id, text))],
+        };
+        if self
+            .by_cl_ord_id
+            .contains_key(&(sender.to_string(), cl_ord_id.clone()))
+        {
+            return vec![(
+                sender.to_string(),
+                reject(message, &cl_ord_id, "duplicate ClOrdID"),
+            )];
+        }
+        self.submit(sender, cl_ord_id, order, None, "0")
+    }
+
+    // Sends an order to the book and reports on it, `exec_type` being New or Replaced.
+    fn submit(
+        &mut self,
+        sender: &str,
+        cl_ord_id: String,
+        mut order: Order,
+        orig: Option<(String, OpenOrder)>,
+        exec_type: &str,
+    ) -> Vec<(String, Message)> {
+        let order_id = match &orig {
+            Some((_, open)) => open.order.order_id,
+            None => {
+                self.next_order_id += 1;
+                self.next_order_id - 1
+            }
+        };
+        order.order_id = order_id;
+        order.session_id = self.session_id(sender);
+        let report: ExecutionReport = match self.book.try_add(order) {
+            Ok(report) => report,
+            Err(reason) => {
+                let mut message = Message::new("8");
+                if let Some((orig_cl_ord_id, _)) = &orig {
+                    message = message.with(tag::ORIG_CL_ORD_ID, orig_cl_ord_id);
+                }
+                let mut out = vec![(
+                    sender.to_string(),
+                    reject(&message, &cl_ord_id, &reason.to_string()),
+                )];
+                // The original is already out of the book, so say so.
+                if let Some((_, mut open)) = orig {
+                    open.quantity = open.cum_qty;
+                    let canceled = self.execution(&open, "4", None);
+                    out.push((sender.to_string(), canceled));
+                }
+                return out;
+            }
+        };
+        order.order_number = report.order_number;
+        let (quantity, cum_qty, notional) = match &orig {
+            Some((_, open)) => (open.quantity, open.cum_qty, open.notional),
+            // An order by cash amount is worth whatever it managed to fill.
+            None if order.notional > 0 => (report.fills.iter().map(|fill| fill.size).sum(), 0, 0),
+            None => (order.size, 0, 0),
+        };
+        let open = OpenOrder {
+            owner: sender.to_string(),
+            cl_ord_id: cl_ord_id.clone(),
+            order,
+            quantity,
+            cum_qty,
+            notional,
+        };
+        self.by_cl_ord_id
+            .insert((sender.to_string(), cl_ord_id), order_id);
+        self.orders.insert(order_id, open.clone());
+        let mut out = vec![(
+            sender.to_string(),
+            self.execution(&open, exec_type, orig.as_ref().map(|(id, _)| id.as_str())),
+        )];
+        for fill in report.fills.iter() {
+            out.extend(self.fill(order_id, fill));
+            out.extend(self.fill(fill.passive_id, fill));
+        }
+        if report.cancelled > 0 || order.notional > 0 {
+            if let Some(open) = self.forget(order_id) {
+                let canceled = self.execution(&open, "4", None);
+                out.push((sender.to_string(), canceled));
+            }
+        }
+        out
+    }
+
+    // Records a fill against one of our orders and reports it to the owner.
+    fn fill(&mut self, order_id: i64, fill: &Fill) -> Option<(String, Message)> {
+        let open = self.orders.get_mut(&order_id)?;
+        open.cum_qty += fill.size;
+        open.notional = open
+            .notional
+            .saturating_add(fill.size.saturating_mul(fill.price));
+        let open = open.clone();
+        if open.leaves_qty() <= 0 {
+            self.forget(order_id);
+        }
+        let mut message = self
+            .execution(&open, "F", None)
+            .with(tag::LAST_QTY, fill.size)
+            .with(tag::LAST_PX, fill.price);
+        let fee = if order_id == fill.aggressor_id {
+            fill.aggressor_fee
+        } else {
+            fill.passive_fee
+        };
+        if fee != 0 {
+            // CommType 3: an absolute amount, negative for a rebate.
+            message = message.with(tag::COMMISSION, fee).with(tag::COMM_TYPE, 3);
+        }
+        Some((open.owner.clone(), message))
+    }
+
+    fn forget(&mut self, order_id: i64) -> Option<OpenOrder> {
+        let open = self.orders.remove(&order_id)?;
+        self.by_cl_ord_id
+            .remove(&(open.owner.clone(), open.cl_ord_id.clone()));
+        Some(open)
+    }
+
+    // Removes the order named by OrigClOrdID from the book, or explains why not.
+    fn take(&mut self, sender: &str, message: &Message) -> Result<OpenOrder, Message> {
+        let cl_ord_id = message.get(tag::CL_ORD_ID).unwrap_or("");
+        let orig_cl_ord_id = message.get(tag::ORIG_CL_ORD_ID).unwrap_or("");
+        let cancel_reject = |reason: i64, text: &str| {
+            Message::new("9")
+                .with(tag::ORDER_ID, "NONE")
+                .with(tag::CL_ORD_ID, cl_ord_id)
+                .with(tag::ORIG_CL_ORD_ID, orig_cl_ord_id)
+                .with(tag::ORD_STATUS, 8)
+                .with(
+                    tag::CXL_REJ_RESPONSE_TO,
+                    if message.msg_type() == "F" { 1 } else { 2 },
+                )
+                .with(tag::CXL_REJ_REASON, reason)
+                .with(tag::TEXT, text)
+        };
+        let unknown = || cancel_reject(1, "unknown order");
+        let order_id = *self
+            .by_cl_ord_id
+            .get(&(sender.to_string(), orig_cl_ord_id.to_string()))
+            .ok_or_else(unknown)?;
+        let order = self.orders.get(&order_id).ok_or_else(unknown)?.order;
+        match self.book.remove(order) {
+            Ok(true) => Ok(self.forget(order_id).unwrap()),
+            Ok(false) => {
+                self.forget(order_id);
+                Err(unknown())
+            }
+            // The order is still resting, so the gateway keeps tracking it.
+            Err(reason) => Err(cancel_reject(99, &reason.to_string())),
+        }
+    }
+
+    fn cancel(&mut self, sender: &str, message: &Message) -> Vec<(String, Message)> {
+        let reply = match self.take(sender, message) {
+            Ok(mut open) => {
+                let orig_cl_ord_id = open.cl_ord_id.clone();
+                open.cl_ord_id = message.get(tag::CL_ORD_ID).unwrap_or("").to_string();
+                open.quantity = open.cum_qty;
+                self.execution(&open, "4", Some(&orig_cl_ord_id))
+            }
+            Err(reject) => reject,
+        };
+        vec![(sender.to_string(), reply)]
+    }
+
+    fn cancel_replace(&mut self, sender: &str, message: &Message) -> Vec<(String, Message)> {
+        let cl_ord_id = message.get(tag::CL_ORD_ID).unwrap_or("").to_string();
+        let mut order = match parse_order(message) {
+            Ok(order) => order,
+            Err(text) => return vec![(sender.to_string(), reject(message, &cl_ord_id, text))],
+        };
+        if order.notional > 0 {
+            let text = "a replace needs OrderQty, not CashOrderQty";
+            return vec![(sender.to_string(), reject(message, &cl_ord_id, text))];
+        }
+        // Refuse a replacement the book would reject before pulling the original,
+        // which then stays live.
+        let orig_cl_ord_id = message.get(tag::ORIG_CL_ORD_ID).unwrap_or("");
+        if let Some(orig) = self
+            .by_cl_ord_id
+            .get(&(sender.to_string(), orig_cl_ord_id.to_string()))
+            .and_then(|order_id| self.orders.get(order_id))
+        {
+            let mut check = order;
+            check.size -= orig.cum_qty;
+            check.order_side = orig.order.order_side;
+            if check.size > 0 {
+                if let Err(reason) = self.book.check(&check, Some(orig.order.order_number)) {
+                    return vec![(
+                        sender.to_string(),
+                        reject(message, &cl_ord_id, &reason.to_string()),
+                    )];
+                }
+            }
+        }
+        let mut open = match self.take(sender, message) {
+            Ok(open) => open,
+            Err(reject) => return vec![(sender.to_string(), reject)],
+        };
+        let orig_cl_ord_id = open.cl_ord_id.clone();
+        // The new quantity includes whatever has already been filled.
+        open.quantity = order.size;
+        order.size -= open.cum_qty;
+        order.order_side = open.order.order_side;
+        if order.size <= 0 {
+            let canceled = self.execution(&open, "4", Some(&orig_cl_ord_id));
+            return vec![(sender.to_string(), canceled)];
+        }
+        self.submit(sender, cl_ord_id, order, Some((orig_cl_ord_id, open)), "5")
+    }
+
+    fn execution(&mut self, open: &OpenOrder, exec_type: &str, orig: Option<&str>) -> Message {
+        self.next_exec_id += 1;
+        let status = match exec_type {
+            "4" => "4",
+            _ if open.leaves_qty() <= 0 => "2",
+            _ if open.cum_qty > 0 => "1",
+            _ => "0",
+        };
+        let leaves = if exec_type == "4" {
+            0
+        } else {
+            open.leaves_qty()
+        };
+        let mut message = Message::new("8")
+            .with(tag::ORDER_ID, open.order.order_number)
+            .with(tag::CL_ORD_ID, &open.cl_ord_id);
+        if let Some(orig) = orig {
+            message = message.with(tag::ORIG_CL_ORD_ID, orig);
+        }
+        message
+            .with(tag::EXEC_ID, self.next_exec_id - 1)
+            .with(tag::EXEC_TYPE, exec_type)
+            .with(tag::ORD_STATUS, status)
+            .with(tag::SIDE, side_code(open.order.order_side))
+            .with(tag::ORDER_QTY, open.quantity)
+            .with(tag::PRICE, open.order.price)
+            .with(tag::LEAVES_QTY, leaves)
+            .with(tag::CUM_QTY, open.cum_qty)
+            .with(tag::AVG_PX, open.avg_px())
+    }
+}
+
+fn side_code(side: OrderSide) -> &'static str {
+    match side {
+        OrderSide::Buy => "1",
+        OrderSide::Sell => "2",
+    }
+}
+
+fn parse_order(message: &Message) -> Result<Order, &'static str> {
+    let side = match message.get(tag::SIDE) {
+        Some("1") => OrderSide::Buy,
+        Some("2") => OrderSide::Sell,
+        _ => return Err("unsupported Side"),
+    };
+    let market = match message.get(tag::ORD_TYPE) {
+        Some("1") => true,
+        Some("2") | None => false,
+        _ => return Err("unsupported OrdType"),
+    };
+    let account = match message.get(tag::ACCOUNT) {
+        Some(account) => account.parse().map_err(|_| "Account must be an integer")?,
+        None => 0,
+    };
+    if market && message.get(tag::ORDER_QTY).is_none() {
+        if let Some(cash) = message.get(tag::CASH_ORDER_QTY) {
+            let cash = cash
+                .parse()
+                .ok()
+                .filter(|cash| *cash > 0)
+                .ok_or("CashOrderQty must be a positive integer")?;
+            return Ok(Order {
+                account,
+                ..Order::market_notional(side, cash)
+            });
+        }
+    }
+    let size = message
+        .get_i64(tag::ORDER_QTY)
+        .filter(|size| *size > 0)
+        .ok_or("OrderQty must be a positive integer")?;
+    let price = if market {
+        message.get_i64(tag::PRICE).unwrap_or(0)
+    } else {
+        message
+            .get_i64(tag::PRICE)
+            .ok_or("Price must be an integer number of ticks")?
+    };
+    let order_type = match (market, message.get(tag::TIME_IN_FORCE)) {
+        (true, _) => OrderType::Market,
+        (false, Some("3")) => OrderType::Ioc,
+        (false, Some("4")) => OrderType::Fok,
+        (false, None | Some("0") | Some("1")) => {
+            if message
+                .get(tag::EXEC_INST)
+                .is_some_and(|inst| inst.split(' ').any(|i| i == "G"))
+            {
+                OrderType::Aon
+            } else {
+                OrderType::Limit
+            }
+        }
+        _ => return Err("unsupported TimeInForce"),
+    };
+    Ok(Order {
+        account,
+        ..Order::new(side, size, price, order_type)
+    })
+}
+
+fn reject(message: &Message, cl_ord_id: &str, text: &str) -> Message {
+    let mut out = Message::new("8")
+        .with(tag::ORDER_ID, "NONE")
+        .with(tag::CL_ORD_ID, cl_ord_id);
+    if let Some(orig) = message.get(tag::ORIG_CL_ORD_ID) {
+        out = out.with(tag::ORIG_CL_ORD_ID, orig);
+    }
+    out.with(tag::EXEC_ID, 0)
+        .with(tag::EXEC_TYPE, 8)
+        .with(tag::ORD_STATUS, 8)
+        .with(tag::SIDE, message.get(tag::SIDE).unwrap_or("1"))
+        .with(tag::LEAVES_QTY, 0)
+        .with(tag::CUM_QTY, 0)
+        .with(tag::AVG_PX, 0)
+        .with(tag::TEXT, text)
+}
+
+/// One counterparty's session state: comp ids and the outgoing sequence number.
+#[derive(Debug)]
+pub struct Session {
+    pub sender_comp_id: String,
+    pub target_comp_id: String,
+    next_seq_num: i64,
+}
+
+impl Session {
+    pub fn new(sender_comp_id: &str, target_comp_id: &str) -> Session {
+        Session {
+            sender_comp_id: sender_comp_id.to_string(),
+            target_comp_id: target_comp_id.to_string(),
+            next_seq_num: 1,
+        }
+    }
+
+    /// Adds the standard header to `message` and encodes it for the wire.
+    pub fn stamp(&mut self, message: &Message) -> Vec<u8> {
+        let mut fields = message.fields.clone();
+        let header = [
+            (tag::SENDER_COMP_ID, self.sender_comp_id.clone()),
+            (tag::TARGET_COMP_ID, self.target_comp_id.clone()),
+            (tag::MSG_SEQ_NUM, self.next_seq_num.to_string()),
+            (tag::SENDING_TIME, utc_timestamp(get_epoch_ms())),
+        ];
+        self.next_seq_num += 1;
+        fields.splice(1..1, header);
+        Message { fields }.encode()
+    }
+}
+
+// Logged-on counterparties by comp id, with the id of the connection that owns
+// the session.  Each session has its own lock so a slow socket only holds up
+// messages to that counterparty.
+type Connections = HashMap<String, (u64, Arc<Mutex<(Session, TcpStream)>>)>;
+
+// How often `serve` looks for disconnect grace periods that have run out.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Runs a gateway on `listener`, one thread per connection, until accepting fails.
+/// A logon must name `comp_id` as its TargetCompID and a SenderCompID that is not
+/// already logged on, or it is answered with a Logout.  Execution reports for
+/// resting orders are routed to their owner's connection if it is still logged on.  If the book has cancel-on-disconnect set, a
+/// counterparty's orders are cancelled when its connection ends and it does not
+/// log on again within the grace period.
+pub fn serve(listener: TcpListener, comp_id: &str, book: OrderBook) -> io::Result<()> {
+    let sweep = book.cancel_on_disconnect().is_some_and(|grace| grace > 0);
+    let gateway = Arc::new(Mutex::new(Gateway::new(book)));
+    let connections: Arc<Mutex<Connections>> = Arc::new(Mutex::new(HashMap::new()));
+    if sweep {
+        let gateway = Arc::clone(&gateway);
+        let connections = Arc::clone(&connections);
+        thread::spawn(move || loop {
+            thread::sleep(SWEEP_INTERVAL);
+            let reports = gateway.lock().unwrap().expire_disconnects(get_epoch_ms());
+            for (target, report) in reports {
+                send(&connections, &target, &report);
+            }
+        });
+    }
+    for (id, stream) in (0..).zip(listener.incoming()) {
+        let stream = stream?;
+        let gateway = Arc::clone(&gateway);
+        let connections = Arc::clone(&connections);
+        let comp_id = comp_id.to_string();
+        thread::spawn(move || {
+            let _ = connection(id, stream, &comp_id, &gateway, &connections);
+        });
+    }
+    Ok(())
+}
+
+fn send(connections: &Mutex<Connections>, target: &str, message: &Message) {
+    let writer = match connections.lock().unwrap().get(target) {
+        Some((_, writer)) => Arc::clone(writer),
+        None => return,
+    };
+    // Stamping under the session lock keeps MsgSeqNum in the order written.
+    let mut writer = writer.lock().unwrap();
+    let (session, stream) = &mut *writer;
+    let bytes = session.stamp(message);
+    let _ = stream.write_all(&bytes);
+}
+
+fn connection(
+    id: u64,
+    mut stream: TcpStream,
+    comp_id: &str,
+    gateway: &Mutex<Gateway>,
+    connections: &Mutex<Connections>,
+) -> io::Result<()> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut counterparty: Option<String> = None;
+    loop {
+        // A reset connection ends the session just like a clean close.
+        let read = match stream.read(&mut chunk) {
+            Ok(0) | Err(_) => break,
+            Ok(read) => read,
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+        loop {
+            let (message, used) = match Message::decode(&buffer) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => break,
+                Err(_) => {
+                    // Skip to the next message start and carry on.
+                    let next = buffer[1..]
+                        .windows(2)
+                        .position(|w| w == b"8=")
+                        .map_or(buffer.len(), |p| p + 1);
+                    buffer.drain(..next);
+                    continue;
+                }
+            };
+            buffer.drain(..used);
+            let sender = message.get(tag::SENDER_COMP_ID).unwrap_or("").to_string();
+            match (message.msg_type(), &counterparty) {
+                ("A", None) => {
+                    let refusal = if sender.is_empty() {
+                        Some("SenderCompID is required")
+                    } else if message.get(tag::TARGET_COMP_ID) != Some(comp_id) {
+                        Some("wrong TargetCompID")
+                    } else {
+                        match connections.lock().unwrap().entry(sender.clone()) {
+                            Entry::Occupied(_) => Some("already logged on"),
+                            Entry::Vacant(entry) => {
+                                let session = Session::new(comp_id, &sender);
+                                let writer = (session, stream.try_clone()?);
+                                entry.insert((id, Arc::new(Mutex::new(writer))));
+                                None
+                            }
+                        }
+                    };
+                    if let Some(text) = refusal {
+                        // Answered on this socket only; any live session is untouched.
+                        let mut session = Session::new(comp_id, &sender);
+                        let logout = Message::new("5").with(tag::TEXT, text);
+                        stream.write_all(&session.stamp(&logout))?;
+                        return Ok(());
+                    }
+                    let logon = Message::new("A").with(tag::ENCRYPT_METHOD, 0).with(
+                        tag::HEART_BT_INT,
+                        message.get(tag::HEART_BT_INT).unwrap_or("30"),
+                    );
+                    send(connections, &sender, &logon);
+                    gateway.lock().unwrap().logon(&sender);
+                    counterparty = Some(sender);
+                }
+                (_, None) => return Ok(()),
+                ("0", Some(_)) => {}
+                ("1", Some(who)) => {
+                    let mut heartbeat = Message::new("0");
+                    if let Some(id) = message.get(tag::TEST_REQ_ID) {
+                        heartbeat = heartbeat.with(tag::TEST_REQ_ID, id);
+                    }
+                    send(connections, who, &heartbeat);
+                }
+                ("5", Some(who)) => {
+                    send(connections, who, &Message::new("5"));
+                    hang_up(id, who, gateway, connections);
+                    return Ok(());
+                }
+                (_, Some(who)) => {
+                    let mut gateway = gateway.lock().unwrap();
+                    for (target, reply) in gateway.handle(who, &message) {
+                        send(connections, &target, &reply);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(who) = counterparty {
+        hang_up(id, &who, gateway, connections);
+    }
+    Ok(())
+}
+
+// Forgets a counterparty's connection and applies cancel-on-disconnect, unless
+// the session now belongs to a different connection.
+fn hang_up(id: u64, who: &str, gateway: &Mutex<Gateway>, connections: &Mutex<Connections>) {
+    {
+        let mut connections = connections.lock().unwrap();
+        match connections.get(who) {
+            Some((owner, _)) if *owner == id => connections.remove(who),
+            _ => return,
+        };
+    }
+    let reports = gateway.lock().unwrap().disconnect(who, get_epoch_ms());
+    for (target, report) in reports {
+        send(connections, &target, &report);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{serve, tag, utc_timestamp, FixError, Gateway, Message, Session, MAX_BODY_LENGTH};
+    use crate::orderlib::{
+        FeeSchedule, Ledger, Order, OrderBook, OrderSide::Sell, OrderType::Limit,
+    };
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    fn order(msg_type: &str, cl_ord_id: &str, side: u8, qty: i64, price: i64) -> Message {
+        Message::new(msg_type)
+            .with(tag::CL_ORD_ID, cl_ord_id)
+            .with(tag::SIDE, side)
+            .with(tag::ORDER_QTY, qty)
+            .with(tag::ORD_TYPE, 2)
+            .with(tag::PRICE, price)
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let message = Message::new("D").with(tag::CL_ORD_ID, "abc");
+        let mut bytes = message.encode();
+        assert!(bytes.starts_with(b"8=FIX.4.4\x019=12\x0135=D\x0111=abc\x0110="));
+        let (decoded, used) = Message::decode(&bytes).unwrap().unwrap();
+        assert_eq!(decoded, message);
+        assert_eq!(used, bytes.len());
+        assert_eq!(Message::decode(&bytes[..bytes.len() - 1]), Ok(None));
+        bytes[16] = b'E';
+        assert_eq!(Message::decode(&bytes), Err(FixError::Checksum));
+        // Lengths that are too long or overflow are refused up front.
+        let huge = format!("8=FIX.4.4\x019={}\x0135=D\x01", usize::MAX);
+        assert_eq!(Message::decode(huge.as_bytes()), Err(FixError::Garbled));
+        let long = format!("8=FIX.4.4\x019={}\x0135=D\x01", MAX_BODY_LENGTH + 1);
+        assert_eq!(Message::decode(long.as_bytes()), Err(FixError::Garbled));
+        assert_eq!(Message::decode(&[b'8'; 64]), Err(FixError::Garbled));
+        assert_eq!(utc_timestamp(951_782_400_123), "20000229-00:00:00.123");
+    }
+
+    #[test]
+    fn test_gateway_cash_order_qty() {
+        let mut gateway = Gateway::new(OrderBook::new());
+        gateway.handle("A", &order("D", "1", 2, 10, 100));
+        gateway.handle("A", &order("D", "2", 2, 10, 110));
+        let by_cash = |cl_ord_id: &str, cash: i64| {
+            Message::new("D")
+                .with(tag::CL_ORD_ID, cl_ord_id)
+                .with(tag::SIDE, 1)
+                .with(tag::ORD_TYPE, 1)
+                .with(tag::CASH_ORDER_QTY, cash)
+        };
+        let replies = gateway.handle("B", &by_cash("1", 1500));
+        // New, then a fill to each side for both levels touched.
+        assert_eq!(replies.len(), 5);
+        assert_eq!(replies[0].1.get(tag::ORDER_QTY), Some("14"));
+        assert_eq!(replies[3].1.get(tag::ORD_STATUS), Some("2"));
+        assert_eq!(replies[3].1.get(tag::CUM_QTY), Some("14"));
+        assert_eq!(gateway.book().best_offer().unwrap().size, 6);
+        let replies = gateway.handle("B", &by_cash("2", 50));
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[1].1.get(tag::EXEC_TYPE), Some("4"));
+        let replies = gateway.handle("B", &by_cash("3", 0));
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("8"));
+    }
+
+    #[test]
+    fn test_gateway_account() {
+        let mut gateway = Gateway::new(OrderBook::new());
+        gateway.handle("A", &order("D", "1", 2, 10, 100).with(tag::ACCOUNT, 17));
+        assert_eq!(gateway.book().best_offer().unwrap().account, 17);
+        let replies = gateway.handle("A", &order("D", "2", 2, 10, 100).with(tag::ACCOUNT, "X"));
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("8"));
+        assert_eq!(
+            replies[0].1.get(tag::TEXT),
+            Some("Account must be an integer")
+        );
+    }
+
+    #[test]
+    fn test_gateway_cancel_on_disconnect() {
+        let mut book = OrderBook::new();
+        book.set_cancel_on_disconnect(Some(1000)).unwrap();
+        let mut gateway = Gateway::new(book);
+        gateway.handle("A", &order("D", "1", 2, 10, 101));
+        assert!(gateway.disconnect("A", 0).is_empty());
+        assert!(gateway.expire_disconnects(999).is_empty());
+        assert!(gateway.logon("A"));
+        assert!(gateway.expire_disconnects(5000).is_empty());
+        assert_eq!(gateway.book().len_offers(), 1);
+
+        gateway.disconnect("A", 10_000);
+        gateway.handle("B", &order("D", "1", 2, 10, 102));
+        let reports = gateway.expire_disconnects(11_000);
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].0, "A");
+        assert_eq!(reports[0].1.get(tag::EXEC_TYPE), Some("4"));
+        assert_eq!(reports[0].1.get(tag::CL_ORD_ID), Some("1"));
+        assert_eq!(gateway.book().best_offer().unwrap().price, 102);
+        // The ClOrdID is free again.
+        let replies = gateway.handle("A", &order("D", "1", 2, 10, 101));
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("0"));
+    }
+
+    #[test]
+    fn test_gateway_rejects_overflowing_orders() {
+        let mut book = OrderBook::new();
+        book.set_fee_schedule(Some(FeeSchedule::flat(1_000, -200)))
+            .unwrap();
+        let mut gateway = Gateway::new(book);
+        let replies = gateway.handle("A", &order("D", "1", 1, i64::MAX / 2, 3));
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("8"));
+        assert_eq!(gateway.book().len_bids(), 0);
+        // The largest order that fits still trades and is charged.
+        gateway.handle("A", &order("D", "2", 2, i64::MAX / 4, 2));
+        let replies = gateway.handle("B", &order("D", "1", 1, i64::MAX / 4, 2));
+        assert_eq!(replies[1].1.get(tag::ORD_STATUS), Some("2"));
+    }
+
+    #[test]
+    fn test_gateway_market_remainder_is_cancelled() {
+        let mut gateway = Gateway::new(OrderBook::new());
+        gateway.handle("A", &order("D", "1", 2, 5, 100));
+        let market = Message::new("D")
+            .with(tag::CL_ORD_ID, "1")
+            .with(tag::SIDE, 1)
+            .with(tag::ORDER_QTY, 8)
+            .with(tag::ORD_TYPE, 1);
+        let replies = gateway.handle("B", &market);
+        assert_eq!(replies.len(), 4);
+        assert_eq!(replies[3].0, "B");
+        assert_eq!(replies[3].1.get(tag::EXEC_TYPE), Some("4"));
+        assert_eq!(replies[3].1.get(tag::CUM_QTY), Some("5"));
+        assert_eq!(gateway.book().len_bids(), 0);
+    }
+
+    #[test]
+    fn test_gateway_commission() {
+        let mut book = OrderBook::new();
+        book.set_fee_schedule(Some(FeeSchedule::flat(1_000, -200)))
+            .unwrap();
+        let mut gateway = Gateway::new(book);
+        gateway.handle("A", &order("D", "1", 2, 100, 100));
+        let replies = gateway.handle("B", &order("D", "1", 1, 100, 100));
+        assert_eq!(replies[1].0, "B");
+        assert_eq!(replies[1].1.get(tag::COMMISSION), Some("10"));
+        assert_eq!(replies[1].1.get(tag::COMM_TYPE), Some("3"));
+        assert_eq!(replies[2].0, "A");
+        assert_eq!(replies[2].1.get(tag::COMMISSION), Some("-2"));
+        gateway.handle("A", &order("D", "2", 2, 1, 100));
+        let replies = gateway.handle("B", &order("D", "2", 1, 1, 100));
+        assert_eq!(replies[1].1.get(tag::COMMISSION), Some("1"));
+        assert_eq!(replies[2].1.get(tag::COMMISSION), None);
+    }
+
+    #[test]
+    fn test_gateway_cancel_and_replace() {
+        let mut gateway = Gateway::new(OrderBook::new());
+        let replies = gateway.handle("A", &order("D", "1", 2, 20, 101));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("0"));
+        let duplicate = gateway.handle("A", &order("D", "1", 2, 20, 101));
+        assert_eq!(duplicate[0].1.get(tag::EXEC_TYPE), Some("8"));
+
+        let replace = order("G", "2", 2, 30, 100).with(tag::ORIG_CL_ORD_ID, "1");
+        let replies = gateway.handle("A", &replace);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("5"));
+        assert_eq!(replies[0].1.get(tag::ORIG_CL_ORD_ID), Some("1"));
+        assert_eq!(gateway.book().best_offer().unwrap().price, 100);
+        assert_eq!(gateway.book().best_offer().unwrap().size, 30);
+
+        let fills = gateway.handle("B", &order("D", "1", 1, 10, 100));
+        assert_eq!(fills.len(), 3);
+        assert_eq!(fills[2].0, "A");
+        assert_eq!(fills[2].1.get(tag::ORD_STATUS), Some("1"));
+        assert_eq!(fills[2].1.get(tag::LEAVES_QTY), Some("20"));
+
+        let cancel = Message::new("F")
+            .with(tag::CL_ORD_ID, "3")
+            .with(tag::ORIG_CL_ORD_ID, "2");
+        let replies = gateway.handle("A", &cancel);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("4"));
+        assert_eq!(replies[0].1.get(tag::CUM_QTY), Some("10"));
+        assert_eq!(gateway.book().len_offers(), 0);
+        let replies = gateway.handle("A", &cancel);
+        assert_eq!(replies[0].1.msg_type(), "9");
+    }
+
+    #[test]
+    fn test_gateway_refused_replace_keeps_original() {
+        let mut ledger = Ledger::new();
+        ledger.deposit(0, 2_000);
+        ledger.set_enforce_buying_power(true);
+        let mut book = OrderBook::new();
+        book.attach_ledger(ledger).unwrap();
+        let mut gateway = Gateway::new(book);
+        gateway.handle("A", &order("D", "1", 1, 10, 100));
+        // Raising the bid to 1_500 fits once its own 1_000 is released...
+        let replace = order("G", "2", 1, 15, 100).with(tag::ORIG_CL_ORD_ID, "1");
+        let replies = gateway.handle("A", &replace);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("5"));
+        // ...but 2_500 does not, and the 1_500 bid stays live.
+        let replace = order("G", "3", 1, 25, 100).with(tag::ORIG_CL_ORD_ID, "2");
+        let replies = gateway.handle("A", &replace);
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("8"));
+        assert_eq!(replies[0].1.get(tag::ORIG_CL_ORD_ID), Some("2"));
+        assert_eq!(gateway.book().best_bid().unwrap().size, 15);
+        let cancel = Message::new("F")
+            .with(tag::CL_ORD_ID, "4")
+            .with(tag::ORIG_CL_ORD_ID, "2");
+        let replies = gateway.handle("A", &cancel);
+        assert_eq!(replies[0].1.get(tag::EXEC_TYPE), Some("4"));
+    }
+
+    fn read_message(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> Message {
+        loop {
+            if let Some((message, used)) = Message::decode(buffer).unwrap() {
+                buffer.drain(..used);
+                return message;
+            }
+            let mut chunk = [0u8; 1024];
+            let read = stream.read(&mut chunk).unwrap();
+            assert!(read > 0, "connection closed");
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+
+    fn logon(address: &str, comp_id: &str) -> (TcpStream, Session, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut session = Session::new(comp_id, "ORDERLIB");
+        let logon = Message::new("A").with(tag::ENCRYPT_METHOD, 0);
+        stream.write_all(&session.stamp(&logon)).unwrap();
+        let mut buffer = Vec::new();
+        let reply = read_message(&mut stream, &mut buffer);
+        assert_eq!(reply.msg_type(), "A");
+        assert_eq!(reply.get(tag::TARGET_COMP_ID), Some(comp_id));
+        (stream, session, buffer)
+    }
+
+    #[test]
+    fn test_logon_checks() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut book = OrderBook::new();
+        book.set_cancel_on_disconnect(Some(0)).unwrap();
+        thread::spawn(move || serve(listener, "ORDERLIB", book));
+        let refused = |session: &mut Session| {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let logon = Message::new("A").with(tag::ENCRYPT_METHOD, 0);
+            stream.write_all(&session.stamp(&logon)).unwrap();
+            let reply = read_message(&mut stream, &mut Vec::new());
+            assert_eq!(reply.msg_type(), "5");
+            reply.get(tag::TEXT).unwrap().to_string()
+        };
+        assert_eq!(
+            refused(&mut Session::new("", "ORDERLIB")),
+            "SenderCompID is required"
+        );
+        assert_eq!(
+            refused(&mut Session::new("FIRM", "OTHER")),
+            "wrong TargetCompID"
+        );
+
+        let (mut stream, mut session, mut buffer) = logon(&address, "FIRM");
+        stream
+            .write_all(&session.stamp(&order("D", "1", 2, 10, 100)))
+            .unwrap();
+        read_message(&mut stream, &mut buffer);
+        // A second logon as FIRM is turned away and its close leaves FIRM's order.
+        assert_eq!(
+            refused(&mut Session::new("FIRM", "ORDERLIB")),
+            "already logged on"
+        );
+        let request = Message::new("1").with(tag::TEST_REQ_ID, "still here");
+        stream.write_all(&session.stamp(&request)).unwrap();
+        let heartbeat = read_message(&mut stream, &mut buffer);
+        assert_eq!(heartbeat.get(tag::TEST_REQ_ID), Some("still here"));
+        let cancel = Message::new("F")
+            .with(tag::CL_ORD_ID, "2")
+            .with(tag::ORIG_CL_ORD_ID, "1");
+        stream.write_all(&session.stamp(&cancel)).unwrap();
+        let canceled = read_message(&mut stream, &mut buffer);
+        assert_eq!(canceled.get(tag::EXEC_TYPE), Some("4"));
+    }
+
+    #[test]
+    fn test_loopback_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let mut book = OrderBook::new();
+        book.add(Order::new(Sell, 5, 99, Limit));
+        thread::spawn(move || serve(listener, "ORDERLIB", book));
+
+        let (mut seller, mut seller_session, mut seller_buffer) = logon(&address, "SELLER");
+        let (mut buyer, mut buyer_session, mut buyer_buffer) = logon(&address, "BUYER");
+        seller
+            .write_all(&seller_session.stamp(&order("D", "s1", 2, 20, 100)))
+            .unwrap();
+        let new = read_message(&mut seller, &mut seller_buffer);
+        assert_eq!(new.get(tag::EXEC_TYPE), Some("0"));
+        assert_eq!(new.get(tag::MSG_SEQ_NUM), Some("2"));
+
+        buyer
+            .write_all(&buyer_session.stamp(&order("D", "b1", 1, 25, 100)))
+            .unwrap();
+        let new = read_message(&mut buyer, &mut buyer_buffer);
+        assert_eq!(new.get(tag::EXEC_TYPE), Some("0"));
+        let first = read_message(&mut buyer, &mut buyer_buffer);
+        assert_eq!(first.get(tag::LAST_PX), Some("99"));
+        let second = read_message(&mut buyer, &mut buyer_buffer);
+        assert_eq!(second.get(tag::LAST_QTY), Some("20"));
+        assert_eq!(second.get(tag::ORD_STATUS), Some("2"));
+        assert_eq!(second.get(tag::AVG_PX), Some("99.8"));
+        let passive = read_message(&mut seller, &mut seller_buffer);
+        assert_eq!(passive.get(tag::CL_ORD_ID), Some("s1"));
+        assert_eq!(passive.get(tag::ORD_STATUS), Some("2"));
+
+        let test_request = Message::new("1").with(tag::TEST_REQ_ID, "ping");
+        buyer
+            .write_all(&buyer_session.stamp(&test_request))
+            .unwrap();
+        let heartbeat = read_message(&mut buyer, &mut buyer_buffer);
+        assert_eq!(heartbeat.msg_type(), "0");
+        assert_eq!(heartbeat.get(tag::TEST_REQ_ID), Some("ping"));
+    }
+}
@@ -1,15 +1,40 @@
 #![crate_name = "orderlib"]
 
+#[cfg(feature = "async")]
+pub mod async_gateway;
+pub mod backtest;
+pub mod batch;
+pub mod fix;
+mod json;
+pub mod repl;
+pub mod risk;
+pub mod service;
+pub mod stats;
+pub mod wire;
+
 pub mod orderlib {
     /// orderlib is a package that provides trading logic and order primitives for
     /// use in a provided, high performance data structure.  A std::collections::BTreeSet
     /// is used to hold orders.  Orders are processed in Price/Time priority.
+    #[cfg(feature = "serde")]
+    use serde::{Deserialize, Serialize};
     use std::cmp;
     use std::cmp::Ordering;
-    use std::collections::BTreeSet;
+    use std::collections::{BTreeSet, HashMap};
+    use std::fmt;
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    pub mod fees;
+    pub mod journal;
+    pub mod ledger;
+    pub mod snapshot;
+    pub use fees::{FeeSchedule, FeeTier};
+    pub use journal::{Command, Journal, JournalError};
+    pub use ledger::{Account, Ledger};
+    pub use snapshot::{BookSnapshot, SnapshotError};
+
     #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub enum OrderType {
         /// A Type to represent the orders that traders want to make.
         /// Fill only up to the price limit indicated
@@ -26,6 +51,7 @@ pub mod orderlib {
     }
 
     #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub enum OrderSide {
         /// Side represents whether the order means to sell as asset or to buy it.
         /// This is useful for ensuring the order ends up in the right place.
@@ -34,6 +60,7 @@ pub mod orderlib {
     }
 
     #[derive(Clone, Copy, Debug)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub struct Order {
         pub order_id: i64,
         pub order_number: i64,
@@ -42,6 +69,18 @@ pub mod orderlib {
         pub price: i64,
         pub timestamp: i64,
         pub order_type: OrderType,
+        /// Cash budget for a `Market` order sized by value rather than quantity.
+        /// When positive, the order buys or sells as many whole units as this many
+        /// price points pay for, and `size` is ignored.  Zero for all other orders.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub notional: i64,
+        /// Account that placed the order, for the `Ledger`.  Zero if unattributed.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub account: i64,
+        /// Gateway session that placed the order, for cancel-on-disconnect.  Zero
+        /// if none.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub session_id: i64,
         // user: &'user User<'user>, // this is a reference to the user who placed the order - not used
     }
 
@@ -55,6 +94,18 @@ pub mod orderlib {
                 price,
                 timestamp: 0,
                 order_type,
+                notional: 0,
+                account: 0,
+                session_id: 0,
+            }
+        }
+
+        /// A market order that spends up to `notional` (size times price) rather
+        /// than filling a fixed size, e.g. "buy 10,000 worth".
+        pub fn market_notional(order_side: OrderSide, notional: i64) -> Order {
+            Order {
+                notional,
+                ..Order::new(order_side, 0, 0, OrderType::Market)
             }
         }
     }
@@ -80,10 +131,11 @@ pub mod orderlib {
             } else if self.price < other.price {
                 Ordering::Less
             } else {
+                // earlier orders come first within a price level
                 if self.order_number < other.order_number {
-                    Ordering::Greater
-                } else if self.order_number > other.order_number {
                     Ordering::Less
+                } else if self.order_number > other.order_number {
+                    Ordering::Greater
                 } else {
                     Ordering::Equal
                 }
@@ -91,7 +143,8 @@ pub mod orderlib {
         }
     }
 
-    #[derive(Debug)]
+    #[derive(Clone, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub struct Fill {
         pub size: i64,
         pub price: i64,
@@ -99,15 +152,294 @@ pub mod orderlib {
         pub aggressor_id: i64,
         pub passive_id: i64,
         pub timestamp: i64,
+        /// Book-assigned match number, counting up from 1 over the book's life.
         pub fill_id: i64,
+        /// Book-assigned order numbers of the two sides, for feeds keyed by order.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub aggressor_number: i64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub passive_number: i64,
+        /// `Order::account` of the two sides.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub aggressor_account: i64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub passive_account: i64,
+        /// Fees charged to each side under the book's `FeeSchedule`; negative for
+        /// a rebate.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub aggressor_fee: i64,
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub passive_fee: i64,
     }
 
     #[derive(Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
     pub struct LimitReport {
         pub price: f64,
         pub size: i64,
     }
 
+    /// The resting orders at one price.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Level {
+        pub price: i64,
+        pub size: i64,
+        pub orders: usize,
+    }
+
+    /// Best bid and offer with the total size resting at each price.  `seq` goes up
+    /// by one each time any of the four values changes.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct Bbo {
+        pub bid: Option<i64>,
+        pub bid_size: i64,
+        pub ask: Option<i64>,
+        pub ask_size: i64,
+        pub seq: u64,
+    }
+
+    /// A size and what it costs at the prices resting on the book.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct NotionalReport {
+        pub size: i64,
+        /// Sum of size times price over the orders taken.
+        pub notional: i64,
+    }
+
+    /// What it would cost to take `requested` from one side of the book right now.
+    /// Slippage is in price points and positive when the fill is worse than the
+    /// reference price.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct ImpactReport {
+        pub requested: i64,
+        /// Size available, at most `requested`.
+        pub filled: i64,
+        /// False when the book holds less than `requested`; the other fields then
+        /// describe sweeping the whole side.
+        pub sufficient: bool,
+        pub vwap: f64,
+        /// Price of the last level reached.
+        pub worst_price: i64,
+        pub levels: usize,
+        pub slippage_vs_best: f64,
+        /// `None` when the book is one-sided.
+        pub slippage_vs_mid: Option<f64>,
+    }
+
+    /// A resting order's place in line.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct QueuePosition {
+        /// Resting size at the same price that will match first.
+        pub size_ahead: i64,
+        pub orders_ahead: usize,
+        /// Number of better-priced levels on the same side; 0 at the top of book.
+        pub levels_ahead: usize,
+    }
+
+    /// The outcome of an accepted order.
+    #[derive(Debug)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct ExecutionReport {
+        pub order_number: i64,
+        /// When the book accepted the order, in milliseconds since the epoch.
+        #[cfg_attr(feature = "serde", serde(default))]
+        pub timestamp: i64,
+        pub fills: Vec<Fill>,
+        /// Size left resting on the book.
+        pub remaining: i64,
+        /// Size neither filled nor rested, e.g. the unfilled part of an `Ioc` or a
+        /// `Market` order.  Always zero for orders sized by `notional`, whose unspent
+        /// budget is simply dropped.
+        pub cancelled: i64,
+    }
+
+    /// Selects resting orders for `OrderBook::cancel_all`.  Every criterion that is
+    /// set must match; the default matches everything.
+    #[derive(Clone, Copy, Debug, Default, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct CancelFilter {
+        pub account: Option<i64>,
+        pub session_id: Option<i64>,
+        pub side: Option<OrderSide>,
+        /// Inclusive price bounds.
+        pub min_price: Option<i64>,
+        pub max_price: Option<i64>,
+        pub order_type: Option<OrderType>,
+    }
+
+    impl CancelFilter {
+        /// Whether `order`, with its real (positive) price, is selected.
+        pub fn matches(&self, order: &Order) -> bool {
+            self.account.is_none_or(|account| order.account == account)
+                && self
+                    .session_id
+                    .is_none_or(|session_id| order.session_id == session_id)
+                && self.side.is_none_or(|side| order.order_side == side)
+                && self.min_price.is_none_or(|min| order.price >= min)
+                && self.max_price.is_none_or(|max| order.price <= max)
+                && self
+                    .order_type
+                    .is_none_or(|order_type| order.order_type == order_type)
+        }
+    }
+
+    /// Why an order, or a change to the book's settings, was refused.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum RejectReason {
+        /// The session state does not accept this order type.
+        Session(SessionState, OrderType),
+        /// The attached journal could not be written.
+        Journal,
+        /// The account's `Ledger` balance, less what its resting orders hold, does
+        /// not cover the order: cash for a buy, position for a sell.
+        BuyingPower { required: i64, available: i64 },
+        /// The order's size times its price does not fit in an `i64`.
+        Overflow,
+        /// A `replace` changed more than a reduction in the order's size.
+        Amend,
+    }
+
+    impl fmt::Display for RejectReason {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                RejectReason::Session(state, order_type) => {
+                    write!(
+                        f,
+                        "{:?} orders are not accepted during {:?}",
+                        order_type, state
+                    )
+                }
+                RejectReason::Journal => write!(f, "the journal could not be written"),
+                RejectReason::BuyingPower {
+                    required,
+                    available,
+                } => write!(
+                    f,
+                    "insufficient buying power: {} required, {} available",
+                    required, available
+                ),
+                RejectReason::Overflow => write!(f, "order value is out of range"),
+                RejectReason::Amend => write!(f, "a replace may only reduce an order's size"),
+            }
+        }
+    }
+
+    /// The market phase the book is in.  Orders are collected without matching
+    /// outside of `Continuous`.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum SessionState {
+        PreOpen,
+        OpeningAuction,
+        Continuous,
+        Halted,
+        Closed,
+    }
+
+    impl SessionState {
+        /// Whether an order of this type is accepted in this state.  Only plain
+        /// `Limit` orders can rest while matching is off, and nothing is accepted
+        /// once the market is closed.
+        pub fn accepts(&self, order_type: OrderType) -> bool {
+            match self {
+                SessionState::Continuous => true,
+                SessionState::PreOpen | SessionState::OpeningAuction | SessionState::Halted => {
+                    order_type == OrderType::Limit
+                }
+                SessionState::Closed => false,
+            }
+        }
+
+        /// Whether incoming orders are matched in this state.
+        pub fn matches(&self) -> bool {
+            *self == SessionState::Continuous
+        }
+
+        /// Whether the book may move from this state to `to`.  Any open state may
+        /// close, and a closed book may only go back to `PreOpen`.
+        pub fn can_transition(&self, to: SessionState) -> bool {
+            use SessionState::*;
+            match (*self, to) {
+                (PreOpen, OpeningAuction) => true,
+                (OpeningAuction, Continuous) => true,
+                (Continuous, Halted) => true,
+                (Halted, Continuous) | (Halted, OpeningAuction) => true,
+                (Closed, PreOpen) => true,
+                (Closed, Closed) => false,
+                (_, Closed) => true,
+                _ => false,
+            }
+        }
+    }
+
+    /// The price a `PriceBand` is centred on.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum BandReference {
+        /// The last traded price.  No band applies until the first trade.
+        LastTrade,
+        /// A fixed price, e.g. the previous close.
+        Static(i64),
+    }
+
+    /// What happens to the unfilled part of an order stopped by a `PriceBand`.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum BandPolicy {
+        Cancel,
+        /// Rest the remainder at its limit price.  Market orders are always cancelled.
+        Rest,
+    }
+
+    /// A volatility circuit breaker: trades are only allowed within `width` of the
+    /// reference price.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct PriceBand {
+        pub reference: BandReference,
+        pub width: i64,
+        pub policy: BandPolicy,
+    }
+
+    /// A recorded change of session state.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub struct SessionEvent {
+        pub from: SessionState,
+        pub to: SessionState,
+        pub timestamp: i64,
+    }
+
+    /// Returned when the book cannot change session state.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    #[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+    pub enum TransitionError {
+        /// The session state machine does not allow this move.
+        Invalid {
+            from: SessionState,
+            to: SessionState,
+        },
+        /// The attached journal could not be written.
+        Journal,
+    }
+
+    impl fmt::Display for TransitionError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                TransitionError::Invalid { from, to } => {
+                    write!(f, "cannot move from {:?} to {:?}", from, to)
+                }
+                TransitionError::Journal => write!(f, "the journal could not be written"),
+            }
+        }
+    }
+
     pub fn get_epoch_ms() -> i64 {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
@@ -115,12 +447,141 @@ pub mod orderlib {
             .as_millis() as i64
     }
 
+    // One side of the book: its orders in matching order, indexed by order number,
+    // with what each account's orders hold back kept as a running total.  Bids
+    // are stored with negated prices.
+    #[derive(Debug)]
+    struct Stack {
+        side: OrderSide,
+        orders: BTreeSet<Order>,
+        by_number: HashMap<i64, Order>,
+        // Cash for bids, units for offers.  Each order's share fits an i64, as
+        // `check` refuses any whose value does not, but their sum may not.
+        reserved: HashMap<i64, i128>,
+    }
+
+    impl Stack {
+        fn new(side: OrderSide) -> Stack {
+            Stack {
+                side,
+                orders: BTreeSet::new(),
+                by_number: HashMap::new(),
+                reserved: HashMap::new(),
+            }
+        }
+
+        fn hold(&self, order: &Order) -> i128 {
+            match self.side {
+                OrderSide::Buy => order.size as i128 * -order.price as i128,
+                OrderSide::Sell => order.size as i128,
+            }
+        }
+
+        fn release(&mut self, order: &Order) {
+            let hold = self.hold(order);
+            if let Some(total) = self.reserved.get_mut(&order.account) {
+                *total -= hold;
+                if *total == 0 {
+                    self.reserved.remove(&order.account);
+                }
+            }
+            self.by_number.remove(&order.order_number);
+        }
+
+        fn first(&self) -> Option<&Order> {
+            self.orders.first()
+        }
+
+        fn iter(&self) -> std::collections::btree_set::Iter<'_, Order> {
+            self.orders.iter()
+        }
+
+        fn len(&self) -> usize {
+            self.orders.len()
+        }
+
+        fn is_empty(&self) -> bool {
+            self.orders.is_empty()
+        }
+
+        fn contains(&self, order: &Order) -> bool {
+            self.orders.contains(order)
+        }
+
+        fn get(&self, order_number: i64) -> Option<&Order> {
+            self.by_number.get(&order_number)
+        }
+
+        fn reserved(&self, account: i64) -> i64 {
+            let total = self.reserved.get(&account).copied().unwrap_or(0);
+            total.clamp(i64::MIN as i128, i64::MAX as i128) as i64
+        }
+
+        // Adds `order`, or swaps it for the stored order with the same price and
+        // order number, which is returned.
+        fn replace(&mut self, order: Order) -> Option<Order> {
+            let old = self.orders.replace(order);
+            if let Some(old) = old.as_ref() {
+                self.release(old);
+            }
+            *self.reserved.entry(order.account).or_insert(0) += self.hold(&order);
+            self.by_number.insert(order.order_number, order);
+            old
+        }
+
+        fn insert(&mut self, order: Order) {
+            self.replace(order);
+        }
+
+        fn remove(&mut self, order: &Order) -> bool {
+            match self.orders.take(order) {
+                Some(old) => {
+                    self.release(&old);
+                    true
+                }
+                None => false,
+            }
+        }
+    }
+
+    impl Extend<Order> for Stack {
+        fn extend<I: IntoIterator<Item = Order>>(&mut self, orders: I) {
+            for order in orders {
+                self.insert(order);
+            }
+        }
+    }
+
     #[derive(Debug)]
     pub struct OrderBook {
         // will be in increasing order of price, best is last
-        buy_orders: BTreeSet<Order>,
-        sell_orders: BTreeSet<Order>,
+        buy_orders: Stack,
+        sell_orders: Stack,
         counter: i64,
+        session: SessionState,
+        session_events: Vec<SessionEvent>,
+        price_band: Option<PriceBand>,
+        last_trade: Option<i64>,
+        market_protection: Option<i64>,
+        journal: Option<Journal>,
+        bbo: Bbo,
+        // Queued top-of-book changes; `None` unless the feed is switched on.
+        bbo_updates: Option<Vec<Bbo>>,
+        ledger: Option<Ledger>,
+        fees: Option<FeeSchedule>,
+        // Notional traded per account, for picking fee tiers.
+        traded: HashMap<i64, i64>,
+        // Fills made so far; the last `Fill::fill_id` handed out.
+        fill_count: i64,
+        cancel_on_disconnect: Option<i64>,
+        // When each disconnected session's orders are due to be cancelled.
+        disconnected: HashMap<i64, i64>,
+    }
+
+    impl Default for OrderBook {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
     impl OrderBook {
@@ -129,76 +590,665 @@ pub mod orderlib {
         /// # Examples
         ///
         /// ```
-        /// use orderlib::new;
+        /// use orderlib::orderlib::OrderBook;
         ///
-        /// let ob = orderlib::new();
+        /// let ob = OrderBook::new();
         /// ```
         pub fn new() -> OrderBook {
             OrderBook {
-                buy_orders: BTreeSet::new(),
-                sell_orders: BTreeSet::new(),
+                buy_orders: Stack::new(OrderSide::Buy),
+                sell_orders: Stack::new(OrderSide::Sell),
                 counter: 1230,
+                session: SessionState::Continuous,
+                session_events: Vec::new(),
+                price_band: None,
+                last_trade: None,
+                market_protection: None,
+                journal: None,
+                bbo: Bbo::default(),
+                bbo_updates: None,
+                ledger: None,
+                fees: None,
+                traded: HashMap::new(),
+                fill_count: 0,
+                cancel_on_disconnect: None,
+                disconnected: HashMap::new(),
             }
         }
 
         pub fn next(&mut self, side: OrderSide) -> Option<&Order> {
             match side {
-                OrderSide::Buy => {
-                    return self.sell_orders.first();
-                }
-                OrderSide::Sell => {
-                    return self.buy_orders.first();
+                OrderSide::Buy => self.sell_orders.first(),
+                OrderSide::Sell => self.buy_orders.first(),
+            }
+        }
+
+        /// Adds an order to the book, matching it against the opposite side if the
+        /// current session state allows it.  Returns the assigned order number and
+        /// any fills.  An order rejected by the session state is dropped and gets
+        /// order number 0; use `try_add` to find out why.
+        pub fn add(&mut self, order: Order) -> (i64, Vec<Fill>) {
+            match self.try_add(order) {
+                Ok(report) => (report.order_number, report.fills),
+                Err(_) => (0, Vec::new()),
+            }
+        }
+
+        /// Like `add`, but reports rejections and how much of the order was left
+        /// resting or cancelled.
+        pub fn try_add(&mut self, order: Order) -> Result<ExecutionReport, RejectReason> {
+            self.try_add_at(order, get_epoch_ms())
+        }
+
+        /// Makes the checks `try_add` would, short of journaling, without changing
+        /// the book.  With `replacing`, the order number of a resting order, the
+        /// checks assume that order has been removed first, so a cancel/replace can
+        /// be refused while the original is still in place.
+        pub fn check(&self, order: &Order, replacing: Option<i64>) -> Result<(), RejectReason> {
+            if !self.session.accepts(order.order_type) {
+                return Err(RejectReason::Session(self.session, order.order_type));
+            }
+            // Every notional the book works out later is bounded by this one.
+            if order.size.checked_mul(order.price).is_none() {
+                return Err(RejectReason::Overflow);
+            }
+            if let Some(ledger) = self.ledger.as_ref() {
+                if ledger.enforces_buying_power() {
+                    let replacing = replacing.and_then(|number| self.order(number));
+                    self.check_buying_power(order, ledger.account(order.account), replacing)?;
                 }
             }
+            Ok(())
         }
 
-        pub fn add(&mut self, mut order: Order) -> (i64, Vec<Fill>) {
-            order.timestamp = get_epoch_ms();
+        // `try_add` with the clock supplied by the caller, for simulations.
+        pub(crate) fn try_add_at(
+            &mut self,
+            mut order: Order,
+            timestamp: i64,
+        ) -> Result<ExecutionReport, RejectReason> {
+            self.check(&order, None)?;
+            order.timestamp = timestamp;
             order.order_number = self.counter;
-            self.counter += 1;
-            match order.order_type {
-                OrderType::Fok => {}
-                OrderType::Aon => {}
-                _ => {}
+            if !self.record(&Command::Add(order)) {
+                return Err(RejectReason::Journal);
             }
-            match order.order_side {
+            Ok(self.execute(order))
+        }
+
+        // Runs an order that has already been numbered and timestamped through the
+        // book.  Fills carry the order's timestamp so that replays are identical.
+        fn execute(&mut self, order: Order) -> ExecutionReport {
+            self.counter = order.order_number + 1;
+            let size = order.size;
+            let (mut fills, remaining) = if self.session.matches() {
+                match order.order_side {
+                    OrderSide::Buy => self.trade(order, 1),
+                    OrderSide::Sell => self.trade(order, -1),
+                }
+            } else {
+                self.rest(order);
+                (Vec::new(), order.size)
+            };
+            let filled: i64 = fills.iter().map(|fill| fill.size).sum();
+            self.charge(&mut fills);
+            self.settle(&fills);
+            self.update_bbo();
+            ExecutionReport {
+                order_number: order.order_number,
+                timestamp: order.timestamp,
+                fills,
+                remaining,
+                // An order sized by notional has no size to leave over.
+                cancelled: if order.notional > 0 {
+                    0
+                } else {
+                    size - filled - remaining
+                },
+            }
+        }
+
+        /// Current trading session state.
+        pub fn session(&self) -> SessionState {
+            self.session
+        }
+
+        /// Moves the book to a new session state.  Entering `Continuous` runs an
+        /// auction for any orders that were collected while matching was off: all
+        /// that can trade do so at the single price that matches the most size,
+        /// kept within the price band if one is set.  The resulting fills are
+        /// returned.  The transition is recorded as a `SessionEvent`.
+        pub fn transition(&mut self, to: SessionState) -> Result<Vec<Fill>, TransitionError> {
+            let from = self.session;
+            if !from.can_transition(to) {
+                return Err(TransitionError::Invalid { from, to });
+            }
+            let timestamp = get_epoch_ms();
+            if !self.record(&Command::Transition(to, timestamp)) {
+                return Err(TransitionError::Journal);
+            }
+            Ok(self.enter(to, timestamp))
+        }
+
+        fn enter(&mut self, to: SessionState, timestamp: i64) -> Vec<Fill> {
+            self.session_events.push(SessionEvent {
+                from: self.session,
+                to,
+                timestamp,
+            });
+            self.session = to;
+            if to.matches() {
+                let mut fills = self.uncross(timestamp);
+                self.charge(&mut fills);
+                self.settle(&fills);
+                self.update_bbo();
+                fills
+            } else {
+                Vec::new()
+            }
+        }
+
+        /// Installs or clears the volatility circuit breaker.  While a band is set,
+        /// an order that would trade outside it stops matching at the band, its
+        /// remainder is handled per the band's policy and the book is `Halted`.
+        /// Fails, leaving the band as it was, if the journal cannot be written.
+        pub fn set_price_band(&mut self, band: Option<PriceBand>) -> Result<(), RejectReason> {
+            if !self.record(&Command::SetPriceBand(band)) {
+                return Err(RejectReason::Journal);
+            }
+            self.price_band = band;
+            Ok(())
+        }
+
+        pub fn price_band(&self) -> Option<PriceBand> {
+            self.price_band
+        }
+
+        /// Caps how far a `Market` order may walk from the best opposite price at
+        /// entry, in price points.  Whatever cannot fill inside the collar is
+        /// cancelled and shows up in `ExecutionReport::cancelled`.  Fails, leaving
+        /// the collar as it was, if the journal cannot be written.
+        pub fn set_market_protection(&mut self, points: Option<i64>) -> Result<(), RejectReason> {
+            if !self.record(&Command::SetMarketProtection(points)) {
+                return Err(RejectReason::Journal);
+            }
+            self.market_protection = points;
+            Ok(())
+        }
+
+        pub fn market_protection(&self) -> Option<i64> {
+            self.market_protection
+        }
+
+        /// Price of the most recent fill, if any.
+        pub fn last_trade(&self) -> Option<i64> {
+            self.last_trade
+        }
+
+        /// Starts writing every command that changes the book to `journal`, so that
+        /// `OrderBook::replay` can rebuild it.  Returns the previous journal, if any.
+        pub fn attach_journal(&mut self, journal: Journal) -> Option<Journal> {
+            self.journal.replace(journal)
+        }
+
+        pub fn detach_journal(&mut self) -> Option<Journal> {
+            self.journal.take()
+        }
+
+        // Writes a command ahead of applying it.  Returns false if the journal
+        // could not be written, in which case the command must not be applied.
+        fn record(&mut self, command: &Command) -> bool {
+            match self.journal.as_mut() {
+                Some(journal) => journal.append(command).is_ok(),
+                None => true,
+            }
+        }
+
+        /// Starts settling fills into `ledger`.  While it enforces buying power,
+        /// orders the account cannot cover are rejected.  Returns the previous
+        /// ledger, if any.  The whole ledger is journaled, so a replay starts from
+        /// the same balances.  Fails, leaving the ledger as it was, if the journal
+        /// cannot be written.
+        pub fn attach_ledger(&mut self, ledger: Ledger) -> Result<Option<Ledger>, RejectReason> {
+            if !self.record(&Command::SetLedger(Some(ledger.clone()))) {
+                return Err(RejectReason::Journal);
+            }
+            Ok(self.ledger.replace(ledger))
+        }
+
+        pub fn detach_ledger(&mut self) -> Result<Option<Ledger>, RejectReason> {
+            if !self.record(&Command::SetLedger(None)) {
+                return Err(RejectReason::Journal);
+            }
+            Ok(self.ledger.take())
+        }
+
+        pub fn ledger(&self) -> Option<&Ledger> {
+            self.ledger.as_ref()
+        }
+
+        /// Adds `amount` to an account's cash in the attached ledger; negative to
+        /// withdraw.  Does nothing without a ledger.  Fails, leaving the balance as
+        /// it was, if the journal cannot be written.
+        pub fn deposit(&mut self, account: i64, amount: i64) -> Result<(), RejectReason> {
+            if self.ledger.is_none() {
+                return Ok(());
+            }
+            if !self.record(&Command::Deposit(account, amount)) {
+                return Err(RejectReason::Journal);
+            }
+            if let Some(ledger) = self.ledger.as_mut() {
+                ledger.deposit(account, amount);
+            }
+            Ok(())
+        }
+
+        /// Turns buying power checks on the attached ledger on or off.  Does
+        /// nothing without a ledger.  Fails, leaving the setting as it was, if the
+        /// journal cannot be written.
+        pub fn set_enforce_buying_power(&mut self, enabled: bool) -> Result<(), RejectReason> {
+            if self.ledger.is_none() {
+                return Ok(());
+            }
+            if !self.record(&Command::SetEnforceBuyingPower(enabled)) {
+                return Err(RejectReason::Journal);
+            }
+            if let Some(ledger) = self.ledger.as_mut() {
+                ledger.set_enforce_buying_power(enabled);
+            }
+            Ok(())
+        }
+
+        /// Cash an account can still commit to new buy orders: its ledger cash less
+        /// the value of its resting bids.  `None` without a ledger.
+        pub fn buying_power(&self, account: i64) -> Option<i64> {
+            let ledger = self.ledger.as_ref()?;
+            Some(
+                ledger
+                    .account(account)
+                    .cash
+                    .saturating_sub(self.reserved(account, OrderSide::Buy)),
+            )
+        }
+
+        // What an account's resting orders on one side hold back: cash for bids,
+        // units for offers.
+        fn reserved(&self, account: i64, side: OrderSide) -> i64 {
+            match side {
+                OrderSide::Buy => self.buy_orders.reserved(account),
+                OrderSide::Sell => self.sell_orders.reserved(account),
+            }
+        }
+
+        // `replacing` is a resting order whose reservation is released first.
+        fn check_buying_power(
+            &self,
+            order: &Order,
+            account: Account,
+            replacing: Option<Order>,
+        ) -> Result<(), RejectReason> {
+            let released = match replacing {
+                Some(old) if old.account == order.account && old.order_side == order.order_side => {
+                    match old.order_side {
+                        OrderSide::Buy => old.size.saturating_mul(old.price),
+                        OrderSide::Sell => old.size,
+                    }
+                }
+                _ => 0,
+            };
+            let (required, available) = match order.order_side {
                 OrderSide::Buy => {
-                    return (order.order_number, self.trade(order, 1));
+                    let required = match order.order_type {
+                        OrderType::Market if order.notional > 0 => order.notional,
+                        OrderType::Market => self
+                            .notional_for_size(OrderSide::Buy, order.size)
+                            .map_or(0, |report| report.notional),
+                        _ => order.size * order.price,
+                    };
+                    // Enough for the fee too, should it all trade as taker.
+                    let fee = self.fees.as_ref().map_or(0, |fees| {
+                        fees.fee(self.traded_notional(order.account), required, false)
+                    });
+                    let required = required.saturating_add(fee.max(0));
+                    (
+                        required,
+                        account.cash.saturating_sub(
+                            self.reserved(order.account, OrderSide::Buy) - released,
+                        ),
+                    )
                 }
                 OrderSide::Sell => {
-                    return (order.order_number, self.trade(order, -1));
+                    let required = if order.notional > 0 {
+                        self.size_for_notional(OrderSide::Sell, order.notional)
+                            .map_or(0, |report| report.size)
+                    } else {
+                        order.size
+                    };
+                    (
+                        required,
+                        account.position.saturating_sub(
+                            self.reserved(order.account, OrderSide::Sell) - released,
+                        ),
+                    )
                 }
+            };
+            if required > available {
+                return Err(RejectReason::BuyingPower {
+                    required,
+                    available,
+                });
             }
-            // fills
+            Ok(())
         }
 
-        pub fn remove(&mut self, mut order: Order) -> bool {
-            match order.order_side {
-                OrderSide::Buy => {
-                    order.price = -order.price;
-                    return self.buy_orders.remove(&order);
+        /// Installs or clears the fee schedule used to price new fills.  Fails,
+        /// leaving the schedule as it was, if the journal cannot be written.
+        pub fn set_fee_schedule(&mut self, fees: Option<FeeSchedule>) -> Result<(), RejectReason> {
+            if !self.record(&Command::SetFeeSchedule(fees.clone())) {
+                return Err(RejectReason::Journal);
+            }
+            self.fees = fees;
+            Ok(())
+        }
+
+        pub fn fee_schedule(&self) -> Option<&FeeSchedule> {
+            self.fees.as_ref()
+        }
+
+        /// Notional an account has traded on either side since the last reset.
+        pub fn traded_notional(&self, account: i64) -> i64 {
+            self.traded.get(&account).copied().unwrap_or(0)
+        }
+
+        /// Starts a new fee period, putting every account back in the first tier.
+        /// Fails, keeping the traded notionals, if the journal cannot be written.
+        pub fn reset_traded_notional(&mut self) -> Result<(), RejectReason> {
+            if !self.record(&Command::ResetTradedNotional) {
+                return Err(RejectReason::Journal);
+            }
+            self.traded.clear();
+            Ok(())
+        }
+
+        // Numbers each fill and prices it at the tier its accounts were in before it.
+        fn charge(&mut self, fills: &mut [Fill]) {
+            for fill in fills.iter_mut() {
+                self.fill_count += 1;
+                fill.fill_id = self.fill_count;
+                let notional = fill.size.saturating_mul(fill.price);
+                if let Some(fees) = self.fees.as_ref() {
+                    fill.aggressor_fee = fees.fee(
+                        self.traded_notional(fill.aggressor_account),
+                        notional,
+                        false,
+                    );
+                    fill.passive_fee =
+                        fees.fee(self.traded_notional(fill.passive_account), notional, true);
                 }
-                OrderSide::Sell => {
-                    return self.sell_orders.remove(&order);
+                for account in [fill.aggressor_account, fill.passive_account] {
+                    let traded = self.traded.entry(account).or_default();
+                    *traded = traded.saturating_add(notional);
+                }
+            }
+        }
+
+        // Books fills into the attached ledger, if any.
+        fn settle(&mut self, fills: &[Fill]) {
+            if let Some(ledger) = self.ledger.as_mut() {
+                for fill in fills.iter() {
+                    ledger.apply(fill);
                 }
             }
         }
 
-        pub fn replace(&mut self, order: Order) -> Option<Order> {
+        /// Returns and clears the session transitions recorded since the last call.
+        pub fn drain_session_events(&mut self) -> Vec<SessionEvent> {
+            std::mem::take(&mut self.session_events)
+        }
+
+        /// Takes `order`, given with its real price, off the book.  Returns whether
+        /// it was resting, or `Err(Journal)` with the book unchanged if the removal
+        /// could not be journaled.
+        pub fn remove(&mut self, order: Order) -> Result<bool, RejectReason> {
+            if !self.record(&Command::Remove(order)) {
+                return Err(RejectReason::Journal);
+            }
+            let removed = self.unlink(order);
+            self.update_bbo();
+            Ok(removed)
+        }
+
+        /// Cancels every resting order selected by `filter`, e.g. all of one
+        /// account's orders for a kill switch, and returns them with their real
+        /// prices.  Each is journaled as a separate removal.  If the journal fails
+        /// part way, the orders cancelled so far are returned and the rest stay.
+        pub fn cancel_all(&mut self, filter: CancelFilter) -> Vec<Order> {
+            let selected: Vec<Order> = self
+                .buy_orders
+                .iter()
+                .map(|order| Order {
+                    price: -order.price,
+                    ..*order
+                })
+                .chain(self.sell_orders.iter().copied())
+                .filter(|order| filter.matches(order))
+                .collect();
+            let mut cancelled = Vec::with_capacity(selected.len());
+            for order in selected {
+                if !self.record(&Command::Remove(order)) {
+                    break;
+                }
+                self.unlink(order);
+                cancelled.push(order);
+            }
+            self.update_bbo();
+            cancelled
+        }
+
+        /// Turns cancel-on-disconnect on with a grace period in milliseconds, or off
+        /// with `None`.  Turning it off forgets any pending disconnects.  The
+        /// setting and pending disconnects are journaled and snapshotted, so they
+        /// survive a restart.  Fails, leaving the setting as it was, if the journal
+        /// cannot be written.
+        pub fn set_cancel_on_disconnect(&mut self, grace: Option<i64>) -> Result<(), RejectReason> {
+            if !self.record(&Command::SetCancelOnDisconnect(grace)) {
+                return Err(RejectReason::Journal);
+            }
+            self.cancel_on_disconnect = grace;
+            if grace.is_none() {
+                self.disconnected.clear();
+            }
+            Ok(())
+        }
+
+        pub fn cancel_on_disconnect(&self) -> Option<i64> {
+            self.cancel_on_disconnect
+        }
+
+        /// Notes that a session dropped at `now`.  With cancel-on-disconnect on, its
+        /// resting orders are cancelled once the grace period has passed without a
+        /// `reconnect`: straight away, and returned, for a grace of zero, otherwise
+        /// by a later `expire_disconnects`.  Fails, with nothing pending, if the
+        /// journal cannot be written.
+        pub fn disconnect(
+            &mut self,
+            session_id: i64,
+            now: i64,
+        ) -> Result<Vec<Order>, RejectReason> {
+            let Some(grace) = self.cancel_on_disconnect else {
+                return Ok(Vec::new());
+            };
+            let deadline = now.saturating_add(grace);
+            if !self.record(&Command::Disconnect(session_id, deadline)) {
+                return Err(RejectReason::Journal);
+            }
+            self.disconnected.insert(session_id, deadline);
+            if grace <= 0 {
+                return Ok(self.expire_disconnects(now));
+            }
+            Ok(Vec::new())
+        }
+
+        /// Keeps a returning session's orders.  Returns whether any cancellation
+        /// was pending.  Fails, leaving it pending, if the journal cannot be
+        /// written.
+        pub fn reconnect(&mut self, session_id: i64) -> Result<bool, RejectReason> {
+            if !self.disconnected.contains_key(&session_id) {
+                return Ok(false);
+            }
+            if !self.record(&Command::Reconnect(session_id)) {
+                return Err(RejectReason::Journal);
+            }
+            self.disconnected.remove(&session_id);
+            Ok(true)
+        }
+
+        /// Cancels the orders of every session whose grace period ended by `now`.
+        /// Call it periodically while cancel-on-disconnect is on.  If the journal
+        /// fails part way, the orders cancelled so far are returned and the rest
+        /// stay pending.
+        pub fn expire_disconnects(&mut self, now: i64) -> Vec<Order> {
+            let mut expired: Vec<i64> = self
+                .disconnected
+                .iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(session_id, _)| *session_id)
+                .collect();
+            expired.sort_unstable();
+            let mut cancelled = Vec::new();
+            for session_id in expired {
+                // Journaled like a reconnect, ahead of the cancels themselves.
+                if !self.record(&Command::Reconnect(session_id)) {
+                    break;
+                }
+                self.disconnected.remove(&session_id);
+                cancelled.extend(self.cancel_all(CancelFilter {
+                    session_id: Some(session_id),
+                    ..CancelFilter::default()
+                }));
+            }
+            cancelled
+        }
+
+        // Takes an order, given with its real price, out of its side.
+        fn unlink(&mut self, mut order: Order) -> bool {
             match order.order_side {
                 OrderSide::Buy => {
-                    return self.buy_orders.replace(order);
+                    order.price = -order.price;
+                    self.buy_orders.remove(&order)
                 }
-                OrderSide::Sell => {
-                    return self.sell_orders.replace(order);
+                OrderSide::Sell => self.sell_orders.remove(&order),
+            }
+        }
+
+        /// Reduces the size of a resting order, keeping its place in the queue.
+        /// `order` must have the same side, price and order number as the resting
+        /// one.  Returns the order that was replaced, or `None` if no such order is
+        /// resting.  Any other change, or a size that is not positive or is larger,
+        /// is refused with `Err(Amend)`, as it would skip `check`.  Errors leave the
+        /// book unchanged.
+        pub fn replace(&mut self, order: Order) -> Result<Option<Order>, RejectReason> {
+            // Only journal replaces that will take effect.
+            let Some(resting) = self.order(order.order_number) else {
+                return Ok(None);
+            };
+            if (resting.order_side, resting.price) != (order.order_side, order.price) {
+                return Ok(None);
+            }
+            let fixed = |order: &Order| {
+                (
+                    order.order_id,
+                    order.timestamp,
+                    order.order_type,
+                    order.notional,
+                    order.account,
+                    order.session_id,
+                )
+            };
+            if order.size <= 0 || order.size > resting.size || fixed(&order) != fixed(&resting) {
+                return Err(RejectReason::Amend);
+            }
+            if !self.record(&Command::Replace(order)) {
+                return Err(RejectReason::Journal);
+            }
+            Ok(self.swap(order))
+        }
+
+        // `replace` without journaling, for replay.
+        fn swap(&mut self, mut order: Order) -> Option<Order> {
+            let stack = match order.order_side {
+                OrderSide::Buy => {
+                    order.price = -order.price;
+                    &mut self.buy_orders
                 }
+                OrderSide::Sell => &mut self.sell_orders,
+            };
+            if !stack.contains(&order) {
+                return None;
+            }
+            let mut old = stack.replace(order)?;
+            if old.order_side == OrderSide::Buy {
+                old.price = -old.price;
+            }
+            self.update_bbo();
+            Some(old)
+        }
+
+        /// Current best bid and offer.
+        pub fn bbo(&self) -> Bbo {
+            self.bbo
+        }
+
+        /// Starts or stops queueing a `Bbo` for every top-of-book change, to be
+        /// collected with `drain_bbo_updates`.  Stopping discards anything queued.
+        pub fn set_bbo_feed(&mut self, enabled: bool) {
+            self.bbo_updates = enabled.then(Vec::new);
+        }
+
+        /// Returns and clears the top-of-book changes queued since the last call.
+        pub fn drain_bbo_updates(&mut self) -> Vec<Bbo> {
+            self.bbo_updates
+                .as_mut()
+                .map(std::mem::take)
+                .unwrap_or_default()
+        }
+
+        // Recomputes the top of book after a change and queues it if it moved.
+        fn update_bbo(&mut self) {
+            let top = |side| {
+                self.depth(side, 1)
+                    .first()
+                    .map_or((None, 0), |level| (Some(level.price), level.size))
+            };
+            let (bid, bid_size) = top(OrderSide::Buy);
+            let (ask, ask_size) = top(OrderSide::Sell);
+            let current = (
+                self.bbo.bid,
+                self.bbo.bid_size,
+                self.bbo.ask,
+                self.bbo.ask_size,
+            );
+            if current == (bid, bid_size, ask, ask_size) {
+                return;
+            }
+            self.bbo = Bbo {
+                bid,
+                bid_size,
+                ask,
+                ask_size,
+                seq: self.bbo.seq + 1,
+            };
+            if let Some(updates) = self.bbo_updates.as_mut() {
+                updates.push(self.bbo);
             }
         }
 
+        /// The first order in line on the bid side, with its real (positive) price.
         pub fn best_bid(&self) -> Option<Order> {
-            let mut bid: Order = self.buy_orders.first().cloned().unwrap();
-            bid.price = -1 * bid.price;
-            Some(bid)
+            self.buy_orders.first().map(|bid| Order {
+                price: -bid.price,
+                ..*bid
+            })
         }
 
         pub fn best_offer(&self) -> Option<Order> {
@@ -213,8 +1263,82 @@ pub mod orderlib {
             self.sell_orders.len()
         }
 
+        /// Looks up a resting order by order number.  Bids are returned with their
+        /// real (positive) price.
+        pub fn order(&self, order_number: i64) -> Option<Order> {
+            if let Some(order) = self.buy_orders.get(order_number) {
+                return Some(Order {
+                    price: -order.price,
+                    ..*order
+                });
+            }
+            self.sell_orders.get(order_number).copied()
+        }
+
+        /// Removes a resting order by order number, returning it if it was found.
+        /// `Err(Journal)` leaves it resting.
+        pub fn cancel(&mut self, order_number: i64) -> Result<Option<Order>, RejectReason> {
+            let Some(order) = self.order(order_number) else {
+                return Ok(None);
+            };
+            Ok(self.remove(order)?.then_some(order))
+        }
+
+        /// Where a resting order stands in the matching queue, or `None` if it is not
+        /// resting.
+        pub fn queue_position(&self, order_number: i64) -> Option<QueuePosition> {
+            let order = self.order(order_number)?;
+            let (stack, price) = match order.order_side {
+                OrderSide::Buy => (&self.buy_orders, -order.price),
+                OrderSide::Sell => (&self.sell_orders, order.price),
+            };
+            let mut position = QueuePosition {
+                size_ahead: 0,
+                orders_ahead: 0,
+                levels_ahead: 0,
+            };
+            let mut level = None;
+            for o in stack.iter().take_while(|o| o.order_number != order_number) {
+                if o.price == price {
+                    position.size_ahead += o.size;
+                    position.orders_ahead += 1;
+                } else if level != Some(o.price) {
+                    position.levels_ahead += 1;
+                    level = Some(o.price);
+                }
+            }
+            Some(position)
+        }
+
+        /// Aggregated resting size for up to `levels` price levels of `side`, best
+        /// price first.
+        pub fn depth(&self, side: OrderSide, levels: usize) -> Vec<Level> {
+            let (stack, sign) = match side {
+                OrderSide::Buy => (&self.buy_orders, -1),
+                OrderSide::Sell => (&self.sell_orders, 1),
+            };
+            let mut depth: Vec<Level> = Vec::new();
+            for order in stack.iter() {
+                let price = sign * order.price;
+                if let Some(level) = depth.last_mut().filter(|level| level.price == price) {
+                    level.size += order.size;
+                    level.orders += 1;
+                    continue;
+                }
+                if depth.len() == levels {
+                    break;
+                }
+                depth.push(Level {
+                    price,
+                    size: order.size,
+                    orders: 1,
+                });
+            }
+            depth
+        }
+
         pub fn size_at_limit(&self, direction: OrderSide, mut price: f64) -> Option<LimitReport> {
-            let opposite_stack: &BTreeSet<Order>;
+            let opposite_stack: &Stack;
             let mut found_size: i64 = 0;
             let mut size_weighted_price: i64 = 0;
             match direction {
@@ -226,7 +1350,7 @@ pub mod orderlib {
                     opposite_stack = &self.sell_orders;
                 }
             }
-            if opposite_stack.len() == 0 {
+            if opposite_stack.is_empty() {
                 return None;
             }
 
@@ -249,18 +1373,15 @@ pub mod orderlib {
             Some(limit_report)
         }
 
+        /// Average price to take `size` from the opposite side.  If the book is too
+        /// thin the report covers only what is there; see `estimate_impact`.
         pub fn limit_at_size(&self, direction: OrderSide, size: i64) -> Option<LimitReport> {
             let mut unfound_size: i64 = size;
             let mut size_weighted_price: i64 = 0;
-            let opposite_stack: &BTreeSet<Order>;
-            match direction {
-                OrderSide::Sell => {
-                    opposite_stack = &self.buy_orders;
-                }
-                OrderSide::Buy => {
-                    opposite_stack = &self.sell_orders;
-                }
-            }
+            let opposite_stack: &Stack = match direction {
+                OrderSide::Sell => &self.buy_orders,
+                OrderSide::Buy => &self.sell_orders,
+            };
             for order in opposite_stack.iter() {
                 if unfound_size <= order.size {
                     size_weighted_price += unfound_size * order.price;
@@ -272,7 +1393,7 @@ pub mod orderlib {
                 }
             }
             if size == 0 {
-                return None;
+                None
             } else {
                 let found_size: i64 = size - unfound_size;
                 let limit_report: LimitReport = LimitReport {
@@ -283,12 +1404,261 @@ pub mod orderlib {
             }
         }
 
-        fn trade(&mut self, mut order: Order, bs: i64) -> Vec<Fill> {
+        /// Halfway between the best bid and offer.  `None` unless both sides are quoted,
+        /// as for the other top-of-book metrics.
+        pub fn mid(&self) -> Option<f64> {
+            Some((self.bbo.bid? + self.bbo.ask?) as f64 / 2.0)
+        }
+
+        /// Best offer minus best bid, in ticks.  Negative while the book is crossed,
+        /// which can happen outside continuous trading.
+        pub fn spread(&self) -> Option<i64> {
+            Some(self.bbo.ask? - self.bbo.bid?)
+        }
+
+        /// Mid weighted by the size on the opposite side, so it leans towards the
+        /// side with less size resting: the price more likely to trade next.
+        pub fn microprice(&self) -> Option<f64> {
+            let (bid, ask) = (self.bbo.bid? as f64, self.bbo.ask? as f64);
+            let (bid_size, ask_size) = (self.bbo.bid_size as f64, self.bbo.ask_size as f64);
+            Some((bid * ask_size + ask * bid_size) / (bid_size + ask_size))
+        }
+
+        /// `(bid size - offer size) / (bid size + offer size)` over the best `levels`
+        /// on each side, from -1 (all offers) to 1 (all bids).  `None` if both are empty.
+        pub fn imbalance(&self, levels: usize) -> Option<f64> {
+            let total = |side| -> i64 { self.depth(side, levels).iter().map(|l| l.size).sum() };
+            let (bids, offers) = (total(OrderSide::Buy), total(OrderSide::Sell));
+            if bids + offers == 0 {
+                return None;
+            }
+            Some((bids - offers) as f64 / (bids + offers) as f64)
+        }
+
+        /// Pre-trade estimate of sweeping `size` from the opposite side with a market
+        /// order.  Returns `None` if `size` is not positive or there is nothing to
+        /// trade against.
+        pub fn estimate_impact(&self, direction: OrderSide, size: i64) -> Option<ImpactReport> {
+            let (opposite_stack, sign) = match direction {
+                OrderSide::Sell => (&self.buy_orders, -1),
+                OrderSide::Buy => (&self.sell_orders, 1),
+            };
+            let best = sign * opposite_stack.first()?.price;
+            if size <= 0 {
+                return None;
+            }
+            // Each order's notional fits an i64, but the sum over a deep book may not.
+            let mut notional: i128 = 0;
+            let (mut filled, mut worst_price, mut levels) = (0, best, 0);
+            for order in opposite_stack.iter() {
+                if filled == size {
+                    break;
+                }
+                let price = sign * order.price;
+                if levels == 0 || price != worst_price {
+                    levels += 1;
+                    worst_price = price;
+                }
+                let take = cmp::min(order.size, size - filled);
+                filled += take;
+                notional += take as i128 * price as i128;
+            }
+            let vwap = notional as f64 / filled as f64;
+            // Positive means paying up when buying or giving away when selling.
+            let slippage = |reference: f64| match direction {
+                OrderSide::Buy => vwap - reference,
+                OrderSide::Sell => reference - vwap,
+            };
+            let mid = self.mid();
+            Some(ImpactReport {
+                requested: size,
+                filled,
+                sufficient: filled == size,
+                vwap,
+                worst_price,
+                levels,
+                slippage_vs_best: slippage(best as f64),
+                slippage_vs_mid: mid.map(slippage),
+            })
+        }
+
+        /// How much can be taken from the opposite side by spending at most `cash`,
+        /// e.g. to size a "buy 10,000 worth" order.  Returns `None` if `cash` does
+        /// not cover one unit at the best price.
+        pub fn size_for_notional(&self, direction: OrderSide, cash: i64) -> Option<NotionalReport> {
+            let (opposite_stack, sign) = match direction {
+                OrderSide::Sell => (&self.buy_orders, -1),
+                OrderSide::Buy => (&self.sell_orders, 1),
+            };
+            let mut report = NotionalReport {
+                size: 0,
+                notional: 0,
+            };
+            for order in opposite_stack.iter() {
+                let price = sign * order.price;
+                if price <= 0 {
+                    break;
+                }
+                let take = cmp::min(order.size, (cash - report.notional) / price);
+                report.size += take;
+                report.notional += take * price;
+                if take < order.size {
+                    break;
+                }
+            }
+            (report.size > 0).then_some(report)
+        }
+
+        /// Cost of taking `size` from the opposite side.  If the book is too thin the
+        /// report covers only what is there.
+        pub fn notional_for_size(&self, direction: OrderSide, size: i64) -> Option<NotionalReport> {
+            let (opposite_stack, sign) = match direction {
+                OrderSide::Sell => (&self.buy_orders, -1),
+                OrderSide::Buy => (&self.sell_orders, 1),
+            };
+            let mut report = NotionalReport {
+                size: 0,
+                notional: 0,
+            };
+            for order in opposite_stack.iter() {
+                if report.size >= size {
+                    break;
+                }
+                let take = cmp::min(order.size, size - report.size);
+                report.size += take;
+                report.notional = report
+                    .notional
+                    .saturating_add(take.saturating_mul(sign * order.price));
+            }
+            (report.size > 0).then_some(report)
+        }
+
+        fn rest(&mut self, mut order: Order) {
+            match order.order_side {
+                OrderSide::Buy => {
+                    order.price = -order.price;
+                    self.buy_orders.replace(order);
+                }
+                OrderSide::Sell => {
+                    self.sell_orders.replace(order);
+                }
+            }
+        }
+
+        // Runs the auction that opens continuous trading: every order that can
+        // trade does so at one clearing price, taking bids and offers in price
+        // and time priority.  The older order of each pair is the passive one.
+        fn uncross(&mut self, timestamp: i64) -> Vec<Fill> {
+            let mut fills: Vec<Fill> = Vec::new();
+            let Some(price) = self.clearing_price() else {
+                return fills;
+            };
+            while let (Some(bid), Some(offer)) = (self.buy_orders.first(), self.sell_orders.first())
+            {
+                let (mut bid, mut offer) = (*bid, *offer);
+                if -bid.price < price || offer.price > price {
+                    break;
+                }
+                let size = cmp::min(bid.size, offer.size);
+                let (aggressor, passive, direction) = if bid.order_number < offer.order_number {
+                    (offer, bid, OrderSide::Sell)
+                } else {
+                    (bid, offer, OrderSide::Buy)
+                };
+                let fill = Fill {
+                    size,
+                    price,
+                    direction,
+                    aggressor_id: aggressor.order_id,
+                    passive_id: passive.order_id,
+                    timestamp,
+                    fill_id: 0,
+                    aggressor_number: aggressor.order_number,
+                    passive_number: passive.order_number,
+                    aggressor_account: aggressor.account,
+                    passive_account: passive.account,
+                    aggressor_fee: 0,
+                    passive_fee: 0,
+                };
+                self.buy_orders.remove(&bid);
+                self.sell_orders.remove(&offer);
+                bid.size -= size;
+                offer.size -= size;
+                if bid.size > 0 {
+                    self.buy_orders.insert(bid);
+                }
+                if offer.size > 0 {
+                    self.sell_orders.insert(offer);
+                }
+                fills.push(fill);
+            }
+            if !fills.is_empty() {
+                self.last_trade = Some(price);
+            }
+            fills
+        }
+
+        // The auction price: the one that trades the most size, then leaves the
+        // least unmatched at that price, then lies toward the side with surplus
+        // size, then is nearest the last trade.  Any remaining tie goes to the
+        // lower price.  Prices are limited to the price band, if one applies.
+        // `None` if the book is not crossed.
+        fn clearing_price(&self) -> Option<i64> {
+            let mut bids = self.depth(OrderSide::Buy, usize::MAX);
+            let offers = self.depth(OrderSide::Sell, usize::MAX);
+            bids.reverse();
+            let band = self.band_limits();
+            let mut prices: Vec<i64> = bids
+                .iter()
+                .chain(offers.iter())
+                .map(|level| band.map_or(level.price, |(low, high)| level.price.clamp(low, high)))
+                .collect();
+            prices.sort_unstable();
+            prices.dedup();
+            // Size bid at or above, and offered at or below, each price in turn.
+            let mut demand: i64 = bids.iter().map(|level| level.size).sum();
+            let mut supply = 0;
+            let (mut below, mut at_or_below) = (bids.iter().peekable(), offers.iter().peekable());
+            let mut candidates: Vec<(i64, i64, i64)> = Vec::with_capacity(prices.len());
+            for price in prices {
+                while let Some(level) = below.next_if(|level| level.price < price) {
+                    demand -= level.size;
+                }
+                while let Some(level) = at_or_below.next_if(|level| level.price <= price) {
+                    supply += level.size;
+                }
+                candidates.push((price, cmp::min(demand, supply), demand - supply));
+            }
+            let volume = candidates.iter().map(|c| c.1).max()?;
+            if volume == 0 {
+                return None;
+            }
+            candidates.retain(|c| c.1 == volume);
+            let imbalance = candidates.iter().map(|c| c.2.abs()).min()?;
+            candidates.retain(|c| c.2.abs() == imbalance);
+            if candidates.iter().all(|c| c.2 > 0) {
+                return candidates.last().map(|c| c.0);
+            }
+            if candidates.iter().all(|c| c.2 < 0) {
+                return candidates.first().map(|c| c.0);
+            }
+            let reference = self.last_trade;
+            candidates
+                .iter()
+                .min_by_key(|c| reference.map_or(0, |reference| (c.0 - reference).abs()))
+                .map(|c| c.0)
+        }
+
+        fn trade(&mut self, mut order: Order, bs: i64) -> (Vec<Fill>, i64) {
             let mut fills: Vec<Fill> = Vec::new();
-            order.price = bs * order.price;
+            order.price *= bs;
+            let band = self.band_limits();
+            let mut breached = false;
+            let mut collared = false;
+            let by_notional = order.order_type == OrderType::Market && order.notional > 0;
 
-            let opp: &mut BTreeSet<Order>;
-            let these: &mut BTreeSet<Order>;
+            let opp: &mut Stack;
+            let these: &mut Stack;
 
             match order.order_side {
                 OrderSide::Buy => {
@@ -301,24 +1671,60 @@ pub mod orderlib {
                 }
             }
 
-            while opp.len() > 0 && order.size > 0 {
+            // Internal prices on the opposite side get worse as they increase.
+            let collar = match (order.order_type, self.market_protection, opp.first()) {
+                (OrderType::Market, Some(points), Some(best)) => Some(best.price + points),
+                _ => None,
+            };
+
+            while !opp.is_empty() && (order.size > 0 || by_notional) {
                 let next_order: &Order = opp.first().unwrap();
 
+                if by_notional {
+                    // Whole units the remaining budget buys at this price; once that
+                    // is no more than this order holds, the budget is used up.
+                    let price = bs * next_order.price;
+                    if price <= 0 {
+                        break;
+                    }
+                    order.size = order.notional / price;
+                    if order.size == 0 {
+                        break;
+                    }
+                }
+
                 if next_order.price > order.price && order.order_type != OrderType::Market {
                     break;
                 }
+                if collar.is_some_and(|collar| next_order.price > collar) {
+                    collared = true;
+                    break;
+                }
+                if let Some((low, high)) = band {
+                    let price = bs * next_order.price;
+                    if price < low || price > high {
+                        breached = true;
+                        break;
+                    }
+                }
                 let mut fill: Fill = Fill {
                     size: 0,
                     price: bs * next_order.price,
                     direction: order.order_side,
                     aggressor_id: order.order_id,
                     passive_id: next_order.order_id,
-                    timestamp: get_epoch_ms(),
+                    timestamp: order.timestamp,
                     fill_id: 0,
+                    aggressor_number: order.order_number,
+                    passive_number: next_order.order_number,
+                    aggressor_account: order.account,
+                    passive_account: next_order.account,
+                    aggressor_fee: 0,
+                    passive_fee: 0,
                 };
                 if order.size < next_order.size {
                     fill.size = order.size;
-                    let mut next_order_clone: Order = next_order.clone();
+                    let mut next_order_clone: Order = *next_order;
                     next_order_clone.size -= order.size;
                     // This copy and replace should be unnecessary
                     let replacement: Order = next_order_clone;
@@ -328,25 +1734,64 @@ pub mod orderlib {
                     break;
                 } else if order.size > next_order.size {
                     fill.size = next_order.size;
-                    let next_order_clone: Order = next_order.clone();
+                    let next_order_clone: Order = *next_order;
                     order.size -= next_order.size;
+                    if by_notional {
+                        order.notional -= fill.size * fill.price;
+                    }
                     opp.remove(&next_order_clone);
                     fills.push(fill);
                 } else if order.size == next_order.size {
                     fill.size = next_order.size;
-                    let next_order_clone: Order = next_order.clone();
+                    let next_order_clone: Order = *next_order;
                     order.size = 0;
+                    if by_notional {
+                        order.notional -= fill.size * fill.price;
+                    }
                     opp.remove(&next_order_clone);
                     fills.push(fill);
-                    break;
+                    // Budget left after clearing a level may still buy or sell
+                    // something at the next one.
+                    if !by_notional {
+                        break;
+                    }
                 }
             }
 
-            if order.size > 0 && order.order_type != OrderType::Ioc {
-                order.price = -1 * order.price;
+            // A Market order has no meaningful price to rest at, so like an Ioc
+            // whatever it could not fill is cancelled.
+            let mut rest = order.size > 0
+                && !matches!(order.order_type, OrderType::Ioc | OrderType::Market)
+                && !collared
+                && !by_notional;
+            if breached {
+                rest &= self.price_band.map(|band| band.policy) == Some(BandPolicy::Rest);
+            }
+            let remaining = if rest {
+                order.price = -order.price;
                 these.replace(order);
+                order.size
+            } else {
+                0
+            };
+            if let Some(fill) = fills.last() {
+                self.last_trade = Some(fill.price);
             }
-            fills
+            if breached {
+                self.enter(SessionState::Halted, order.timestamp);
+            }
+            (fills, remaining)
+        }
+
+        // The inclusive range of prices allowed to trade, if a band is configured and
+        // has a reference price to work from.
+        fn band_limits(&self) -> Option<(i64, i64)> {
+            let band = self.price_band?;
+            let reference = match band.reference {
+                BandReference::LastTrade => self.last_trade?,
+                BandReference::Static(price) => price,
+            };
+            Some((reference - band.width, reference + band.width))
         }
     }
 
@@ -371,8 +1816,10 @@ pub mod orderlib {
 #[cfg(test)]
 mod tests {
     use super::orderlib::{
-        Fill, LimitReport, Order, OrderBook, OrderSide, OrderSide::Buy, OrderSide::Sell,
-        OrderType::Ioc, OrderType::Limit, OrderType::Market,
+        BandPolicy, BandReference, Bbo, CancelFilter, Fill, ImpactReport, LimitReport,
+        NotionalReport, Order, OrderBook, OrderSide, OrderSide::Buy, OrderSide::Sell,
+        OrderType::Aon, OrderType::Ioc, OrderType::Limit, OrderType::Market, PriceBand,
+        QueuePosition, RejectReason, SessionState,
     };
 
     #[test]
@@ -381,13 +1828,13 @@ mod tests {
         let mut order1 = Order::new(Buy, 20, 100, Limit);
         let order1num = order_book.add(order1).0; // len == 1
         assert_eq!(order_book.len_bids(), 1);
-        order_book.remove(order1);
+        order_book.remove(order1).unwrap();
         assert_eq!(order_book.len_bids(), 1); // doesn't work, len still == 1
         let original: Order = order_book.best_bid().unwrap();
         order1.order_number = order1num;
-        order_book.remove(order1);
+        order_book.remove(order1).unwrap();
         assert_eq!(order_book.len_bids(), 0); // should now work
-        order_book.remove(original);
+        order_book.remove(original).unwrap();
         assert_eq!(order_book.len_bids(), 0); // doesn't work, already removed above
 
         order_book.add(Order::new(Buy, 20, 100, Limit));
@@ -395,7 +1842,7 @@ mod tests {
         assert_eq!(order_book.len_bids(), 2);
         let first: Order = order_book.best_bid().unwrap();
         assert_eq!(first.price, 101);
-        assert!(order_book.remove(first));
+        assert_eq!(order_book.remove(first), Ok(true));
         assert_eq!(order_book.len_bids(), 1);
         let last: Order = order_book.best_bid().unwrap();
         assert_eq!(last.price, 100);
@@ -466,12 +1913,12 @@ mod tests {
         assert_eq!(order_book.len_offers(), 2);
         let mut to_delete_order_1: Order = Order::new(Buy, 20, 100, Limit);
         to_delete_order_1.order_number = order_1_number;
-        order_book.remove(to_delete_order_1);
+        order_book.remove(to_delete_order_1).unwrap();
         assert_eq!(order_book.len_bids(), 1);
         assert_eq!(order_book.len_offers(), 2);
         let mut order3_copy = Order::new(Sell, 20, 102, Limit);
         order3_copy.order_number = order_3_number;
-        order_book.remove(order3_copy);
+        order_book.remove(order3_copy).unwrap();
         assert_eq!(order_book.len_bids(), 1);
         assert_eq!(order_book.len_offers(), 1);
     }
@@ -560,4 +2007,558 @@ mod tests {
         assert_eq!(order_book.best_offer().unwrap().size, 31);
         assert_eq!(fills.len(), 0);
     }
+
+    #[test]
+    fn test_session_order_acceptance() {
+        let mut order_book: OrderBook = OrderBook::new();
+        assert_eq!(order_book.session(), SessionState::Continuous);
+        order_book.transition(SessionState::Closed).unwrap();
+        assert!(order_book.transition(SessionState::Continuous).is_err());
+        order_book.transition(SessionState::PreOpen).unwrap();
+        assert_eq!(
+            order_book
+                .try_add(Order::new(Buy, 20, 100, Market))
+                .unwrap_err(),
+            RejectReason::Session(SessionState::PreOpen, Market)
+        );
+        assert_eq!(order_book.add(Order::new(Buy, 20, 100, Market)).0, 0);
+        assert_eq!(order_book.len_bids(), 0);
+        let events = order_book.drain_session_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[1].from, SessionState::Closed);
+        assert_eq!(events[1].to, SessionState::PreOpen);
+        assert!(order_book.drain_session_events().is_empty());
+    }
+
+    #[test]
+    fn test_session_uncross_on_open() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.transition(SessionState::Halted).unwrap();
+        let report = order_book.try_add(Order::new(Buy, 20, 101, Limit)).unwrap();
+        assert_eq!(report.remaining, 20);
+        assert!(report.fills.is_empty());
+        order_book.add(Order::new(Sell, 30, 100, Limit));
+        assert_eq!(order_book.len_bids(), 1);
+        assert_eq!(order_book.len_offers(), 1);
+        let fills: Vec<Fill> = order_book.transition(SessionState::Continuous).unwrap();
+        assert_eq!(fills.len(), 1);
+        assert_eq!(fills[0].size, 20);
+        // 20 trades at either price; the surplus is on offer, so the lower wins.
+        assert_eq!(fills[0].price, 100);
+        assert_eq!(fills[0].direction, Sell);
+        assert_eq!(order_book.len_bids(), 0);
+        assert_eq!(order_book.best_offer().unwrap().size, 10);
+        assert_eq!(order_book.last_trade(), Some(100));
+    }
+
+    #[test]
+    fn test_auction_single_clearing_price() {
+        let auction = |band: Option<PriceBand>| {
+            let mut order_book: OrderBook = OrderBook::new();
+            order_book.transition(SessionState::Halted).unwrap();
+            order_book.set_price_band(band).unwrap();
+            order_book.add(Order::new(Buy, 10, 103, Limit));
+            order_book.add(Order::new(Buy, 5, 101, Limit));
+            order_book.add(Order::new(Sell, 8, 100, Limit));
+            order_book.add(Order::new(Sell, 10, 102, Limit));
+            let fills = order_book.transition(SessionState::Continuous).unwrap();
+            let trades: Vec<(i64, i64)> = fills.iter().map(|f| (f.size, f.price)).collect();
+            (trades, order_book.bbo())
+        };
+        // 10 can trade at 102 or 103 with offers left over; the lower wins.
+        let (trades, bbo) = auction(None);
+        assert_eq!(trades, vec![(8, 102), (2, 102)]);
+        assert_eq!((bbo.bid, bbo.ask, bbo.ask_size), (Some(101), Some(102), 8));
+        // The band keeps the price at 101, where only 8 can trade; what is left
+        // crosses outside the band and stays.
+        let (trades, bbo) = auction(Some(PriceBand {
+            reference: BandReference::Static(100),
+            width: 1,
+            policy: BandPolicy::Cancel,
+        }));
+        assert_eq!(trades, vec![(8, 101)]);
+        assert_eq!((bbo.bid, bbo.bid_size, bbo.ask), (Some(103), 2, Some(102)));
+    }
+
+    #[test]
+    fn test_ioc_cancelled_size() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 20, 101, Limit));
+        let report = order_book.try_add(Order::new(Sell, 31, 101, Ioc)).unwrap();
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.remaining, 0);
+        assert_eq!(report.cancelled, 11);
+    }
+
+    #[test]
+    fn test_price_band_halts_market_sweep() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book
+            .set_price_band(Some(PriceBand {
+                reference: BandReference::Static(100),
+                width: 2,
+                policy: BandPolicy::Cancel,
+            }))
+            .unwrap();
+        order_book.add(Order::new(Buy, 20, 101, Limit));
+        order_book.add(Order::new(Buy, 20, 99, Limit));
+        order_book.add(Order::new(Buy, 20, 90, Limit));
+        let report = order_book.try_add(Order::new(Sell, 60, 0, Market)).unwrap();
+        assert_eq!(report.fills.len(), 2);
+        assert_eq!(report.fills[1].price, 99);
+        assert_eq!(report.cancelled, 20);
+        assert_eq!(order_book.session(), SessionState::Halted);
+        assert_eq!(order_book.best_bid().unwrap().price, 90);
+        assert_eq!(order_book.last_trade(), Some(99));
+        assert_eq!(
+            order_book.drain_session_events()[0].to,
+            SessionState::Halted
+        );
+    }
+
+    #[test]
+    fn test_price_band_rests_limit_remainder() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Sell, 10, 100, Limit));
+        order_book.add(Order::new(Sell, 10, 110, Limit));
+        order_book
+            .set_price_band(Some(PriceBand {
+                reference: BandReference::LastTrade,
+                width: 5,
+                policy: BandPolicy::Rest,
+            }))
+            .unwrap();
+        // no trade yet, so no reference to band around
+        order_book.add(Order::new(Buy, 5, 100, Limit));
+        assert_eq!(order_book.session(), SessionState::Continuous);
+        let report = order_book.try_add(Order::new(Buy, 20, 110, Limit)).unwrap();
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.fills[0].size, 5);
+        assert_eq!(report.remaining, 15);
+        assert_eq!(order_book.session(), SessionState::Halted);
+        assert_eq!(order_book.best_bid().unwrap().price, 110);
+    }
+
+    #[test]
+    fn test_market_protection_collar() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.set_market_protection(Some(2)).unwrap();
+        order_book.add(Order::new(Sell, 10, 100, Limit));
+        order_book.add(Order::new(Sell, 10, 102, Limit));
+        order_book.add(Order::new(Sell, 10, 103, Limit));
+        let report = order_book.try_add(Order::new(Buy, 30, 0, Market)).unwrap();
+        assert_eq!(report.fills.len(), 2);
+        assert_eq!(report.fills[1].price, 102);
+        assert_eq!(report.remaining, 0);
+        assert_eq!(report.cancelled, 10);
+        assert_eq!(order_book.len_bids(), 0);
+        assert_eq!(order_book.best_offer().unwrap().price, 103);
+
+        order_book.add(Order::new(Buy, 10, 99, Limit));
+        order_book.add(Order::new(Buy, 10, 96, Limit));
+        let report = order_book.try_add(Order::new(Sell, 20, 0, Market)).unwrap();
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(report.cancelled, 10);
+        assert_eq!(order_book.best_bid().unwrap().price, 96);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_wire_format() {
+        let mut order = Order::new(Buy, 20, 100, Limit);
+        order.order_number = 1231;
+        let text = serde_json::to_string(&order).unwrap();
+        assert_eq!(
+            text,
+            "{\"order_id\":0,\"order_number\":1231,\"order_side\":\"Buy\",\"size\":20,\
+             \"price\":100,\"timestamp\":0,\"order_type\":\"Limit\",\"notional\":0,\
+             \"account\":0,\"session_id\":0}"
+        );
+        // Orders written before the trailing fields existed still read.
+        let old = text.replace(",\"notional\":0,\"account\":0,\"session_id\":0", "");
+        assert_eq!(serde_json::from_str::<Order>(&old).unwrap().notional, 0);
+        let back: Order = serde_json::from_str(&text).unwrap();
+        assert_eq!(
+            (back.order_number, back.price, back.order_side),
+            (1231, 100, Buy)
+        );
+
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 20, 100, Limit));
+        let fills: Vec<Fill> = order_book.add(Order::new(Sell, 5, 100, Ioc)).1;
+        let text = serde_json::to_string(&fills).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Fill>>(&text).unwrap(), fills);
+        let report = order_book.limit_at_size(Sell, 10).unwrap();
+        let text = serde_json::to_string(&report).unwrap();
+        assert_eq!(text, "{\"price\":100.0,\"size\":10}");
+        assert_eq!(serde_json::from_str::<LimitReport>(&text).unwrap(), report);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_order_book_round_trip() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 20, 100, Limit));
+        order_book.add(Order::new(Buy, 20, 101, Limit));
+        order_book.add(Order::new(Sell, 10, 103, Limit));
+        let value = serde_json::to_value(&order_book).unwrap();
+        assert_eq!(value["bids"][0]["price"], 101);
+        assert_eq!(value["session"], "Continuous");
+        let restored: OrderBook = serde_json::from_value(value).unwrap();
+        assert_eq!(restored.snapshot(), order_book.snapshot());
+    }
+
+    #[test]
+    fn test_depth_and_cancel_by_number() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 20, 100, Limit));
+        let number = order_book.add(Order::new(Buy, 10, 101, Limit)).0;
+        order_book.add(Order::new(Buy, 5, 101, Limit));
+        order_book.add(Order::new(Sell, 7, 103, Limit));
+        let bids = order_book.depth(Buy, 5);
+        assert_eq!(bids.len(), 2);
+        assert_eq!((bids[0].price, bids[0].size, bids[0].orders), (101, 15, 2));
+        assert_eq!((bids[1].price, bids[1].size, bids[1].orders), (100, 20, 1));
+        assert_eq!(order_book.depth(Buy, 1).len(), 1);
+        assert_eq!(order_book.depth(Sell, 5)[0].size, 7);
+        assert_eq!(order_book.order(number).unwrap().price, 101);
+        assert_eq!(order_book.cancel(number).unwrap().unwrap().size, 10);
+        assert_eq!(order_book.cancel(number), Ok(None));
+        assert_eq!(order_book.depth(Buy, 5)[0].size, 5);
+    }
+
+    #[test]
+    fn test_cancel_all() {
+        let mut order_book: OrderBook = OrderBook::new();
+        for (account, side, price, order_type) in [
+            (1, Buy, 99, Limit),
+            (1, Buy, 100, Aon),
+            (2, Buy, 100, Limit),
+            (1, Sell, 102, Limit),
+            (1, Sell, 105, Limit),
+            (2, Sell, 103, Limit),
+        ] {
+            order_book.add(Order {
+                account,
+                ..Order::new(side, 10, price, order_type)
+            });
+        }
+        let cancelled = order_book.cancel_all(CancelFilter {
+            account: Some(1),
+            min_price: Some(100),
+            max_price: Some(104),
+            ..CancelFilter::default()
+        });
+        assert_eq!(
+            cancelled
+                .iter()
+                .map(|order| order.price)
+                .collect::<Vec<_>>(),
+            [100, 102]
+        );
+        assert_eq!(order_book.bbo().ask, Some(103));
+        let cancelled = order_book.cancel_all(CancelFilter {
+            side: Some(Buy),
+            order_type: Some(Limit),
+            ..CancelFilter::default()
+        });
+        assert_eq!(cancelled.len(), 2);
+        assert_eq!(order_book.len_bids(), 0);
+        assert_eq!(order_book.cancel_all(CancelFilter::default()).len(), 2);
+        assert!(order_book.cancel_all(CancelFilter::default()).is_empty());
+    }
+
+    #[test]
+    fn test_cancel_on_disconnect() {
+        let mut order_book: OrderBook = OrderBook::new();
+        let mut add = |session_id, side, price| {
+            order_book
+                .add(Order {
+                    session_id,
+                    ..Order::new(side, 10, price, Limit)
+                })
+                .0
+        };
+        add(1, Buy, 99);
+        add(1, Sell, 102);
+        let kept = add(2, Buy, 98);
+        // Off by default.
+        assert!(order_book.disconnect(1, 0).unwrap().is_empty());
+        assert!(order_book.expire_disconnects(i64::MAX).is_empty());
+
+        order_book.set_cancel_on_disconnect(Some(500)).unwrap();
+        assert!(order_book.disconnect(1, 1000).unwrap().is_empty());
+        assert!(order_book.disconnect(2, 1000).unwrap().is_empty());
+        assert_eq!(order_book.reconnect(2), Ok(true));
+        assert_eq!(order_book.reconnect(3), Ok(false));
+        // A grace period reaching past the end of time never expires.
+        order_book.set_cancel_on_disconnect(Some(i64::MAX)).unwrap();
+        assert!(order_book.disconnect(4, 1000).unwrap().is_empty());
+        assert!(order_book.reconnect(4).unwrap());
+        order_book.set_cancel_on_disconnect(Some(500)).unwrap();
+        assert!(order_book.expire_disconnects(1499).is_empty());
+        let cancelled = order_book.expire_disconnects(1500);
+        assert_eq!(cancelled.len(), 2);
+        assert!(cancelled.iter().all(|order| order.session_id == 1));
+        assert_eq!(order_book.len_bids(), 1);
+        assert_eq!(order_book.len_offers(), 0);
+
+        order_book.set_cancel_on_disconnect(Some(0)).unwrap();
+        let cancelled = order_book.disconnect(2, 2000).unwrap();
+        assert_eq!(cancelled[0].order_number, kept);
+        assert_eq!(order_book.len_bids(), 0);
+    }
+
+    #[test]
+    fn test_time_priority_within_level() {
+        let mut order_book: OrderBook = OrderBook::new();
+        let first = order_book.add(Order::new(Sell, 10, 100, Limit)).0;
+        let second = order_book.add(Order::new(Sell, 10, 100, Limit)).0;
+        let fills: Vec<Fill> = order_book.add(Order::new(Buy, 15, 100, Limit)).1;
+        assert_eq!(fills[0].passive_number, first);
+        assert_eq!(fills[1].passive_number, second);
+        assert_eq!(fills[1].size, 5);
+        assert_eq!((fills[0].fill_id, fills[1].fill_id), (1, 2));
+        let fills: Vec<Fill> = order_book.add(Order::new(Buy, 5, 100, Limit)).1;
+        assert_eq!(fills[0].fill_id, 3);
+    }
+
+    #[test]
+    fn test_replace_keeps_priority() {
+        let mut order_book: OrderBook = OrderBook::new();
+        let first = order_book.add(Order::new(Buy, 10, 100, Limit)).0;
+        order_book.add(Order::new(Buy, 10, 100, Limit));
+        let mut smaller = order_book.order(first).unwrap();
+        smaller.size = 4;
+        assert_eq!(order_book.replace(smaller).unwrap().unwrap().size, 10);
+        assert_eq!(order_book.len_bids(), 2);
+        assert_eq!(order_book.best_bid().unwrap().order_number, first);
+        assert_eq!(order_book.best_bid().unwrap().size, 4);
+        smaller.order_number = 99;
+        assert_eq!(order_book.replace(smaller), Ok(None));
+        assert_eq!(order_book.len_bids(), 2);
+    }
+
+    #[test]
+    fn test_queue_position() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 10, 101, Limit));
+        order_book.add(Order::new(Buy, 5, 101, Limit));
+        let first = order_book.add(Order::new(Buy, 7, 100, Limit)).0;
+        order_book.add(Order::new(Buy, 3, 99, Limit));
+        let second = order_book.add(Order::new(Buy, 8, 100, Limit)).0;
+        let ask = order_book.add(Order::new(Sell, 4, 102, Limit)).0;
+        let position = |size_ahead, orders_ahead, levels_ahead| {
+            Some(QueuePosition {
+                size_ahead,
+                orders_ahead,
+                levels_ahead,
+            })
+        };
+        assert_eq!(order_book.queue_position(first), position(0, 0, 1));
+        assert_eq!(order_book.queue_position(second), position(7, 1, 1));
+        assert_eq!(order_book.queue_position(ask), position(0, 0, 0));
+        order_book.add(Order::new(Sell, 15, 101, Ioc));
+        assert_eq!(order_book.queue_position(second), position(7, 1, 0));
+        order_book.cancel(first).unwrap();
+        assert_eq!(order_book.queue_position(second), position(0, 0, 0));
+        assert_eq!(order_book.queue_position(first), None);
+    }
+
+    #[test]
+    fn test_bbo_updates() {
+        let mut order_book: OrderBook = OrderBook::new();
+        assert_eq!(order_book.best_bid(), None);
+        assert_eq!(order_book.best_offer(), None);
+        order_book.set_bbo_feed(true);
+        let bid = order_book.add(Order::new(Buy, 10, 100, Limit)).0;
+        order_book.add(Order::new(Buy, 5, 100, Limit));
+        order_book.add(Order::new(Buy, 5, 99, Limit));
+        order_book.add(Order::new(Sell, 8, 102, Limit));
+        order_book.add(Order::new(Sell, 3, 0, Market));
+        order_book.cancel(bid).unwrap();
+        let bbo = |bid, bid_size, ask, ask_size, seq| Bbo {
+            bid,
+            bid_size,
+            ask,
+            ask_size,
+            seq,
+        };
+        assert_eq!(
+            order_book.drain_bbo_updates(),
+            vec![
+                bbo(Some(100), 10, None, 0, 1),
+                bbo(Some(100), 15, None, 0, 2),
+                bbo(Some(100), 15, Some(102), 8, 3),
+                bbo(Some(100), 12, Some(102), 8, 4),
+                bbo(Some(100), 5, Some(102), 8, 5),
+            ]
+        );
+        order_book.add(Order::new(Buy, 8, 102, Limit));
+        order_book.add(Order::new(Sell, 1, 105, Limit));
+        assert_eq!(
+            order_book.drain_bbo_updates(),
+            vec![
+                bbo(Some(100), 5, None, 0, 6),
+                bbo(Some(100), 5, Some(105), 1, 7)
+            ]
+        );
+        assert!(order_book.drain_bbo_updates().is_empty());
+        order_book.set_bbo_feed(false);
+        order_book.add(Order::new(Sell, 1, 104, Limit));
+        assert!(order_book.drain_bbo_updates().is_empty());
+        assert_eq!(order_book.bbo(), bbo(Some(100), 5, Some(104), 1, 8));
+    }
+
+    #[test]
+    fn test_estimate_impact() {
+        let mut order_book: OrderBook = OrderBook::new();
+        assert_eq!(order_book.estimate_impact(Buy, 10), None);
+        order_book.add(Order::new(Buy, 10, 98, Limit));
+        order_book.add(Order::new(Sell, 10, 102, Limit));
+        order_book.add(Order::new(Sell, 5, 102, Limit));
+        order_book.add(Order::new(Sell, 10, 104, Limit));
+        let before = order_book.snapshot();
+        assert_eq!(
+            order_book.estimate_impact(Buy, 20),
+            Some(ImpactReport {
+                requested: 20,
+                filled: 20,
+                sufficient: true,
+                vwap: 102.5,
+                worst_price: 104,
+                levels: 2,
+                slippage_vs_best: 0.5,
+                slippage_vs_mid: Some(2.5),
+            })
+        );
+        let thin = order_book.estimate_impact(Buy, 40).unwrap();
+        assert!(!thin.sufficient);
+        assert_eq!(thin.filled, 25);
+        let sell = order_book.estimate_impact(Sell, 4).unwrap();
+        assert_eq!(sell.vwap, 98.0);
+        assert_eq!(sell.slippage_vs_best, 0.0);
+        assert_eq!(sell.slippage_vs_mid, Some(2.0));
+        assert_eq!(order_book.estimate_impact(Sell, 0), None);
+        assert_eq!(order_book.snapshot(), before);
+        order_book
+            .cancel(order_book.best_bid().unwrap().order_number)
+            .unwrap();
+        assert_eq!(
+            order_book.estimate_impact(Buy, 1).unwrap().slippage_vs_mid,
+            None
+        );
+        let mut deep = OrderBook::new();
+        deep.add(Order::new(Sell, 2, i64::MAX / 2, Limit));
+        deep.add(Order::new(Sell, 2, i64::MAX / 2, Limit));
+        let impact = deep.estimate_impact(Buy, 4).unwrap();
+        assert_eq!(impact.vwap, (i64::MAX / 2) as f64);
+    }
+
+    #[test]
+    fn test_notional_queries() {
+        let mut order_book: OrderBook = OrderBook::new();
+        assert_eq!(order_book.size_for_notional(Buy, 10_000), None);
+        order_book.add(Order::new(Sell, 10, 100, Limit));
+        order_book.add(Order::new(Sell, 10, 110, Limit));
+        order_book.add(Order::new(Buy, 10, 90, Limit));
+        order_book.add(Order::new(Buy, 10, 80, Limit));
+        let report = |size, notional| Some(NotionalReport { size, notional });
+        assert_eq!(order_book.size_for_notional(Buy, 1_500), report(14, 1_440));
+        assert_eq!(order_book.size_for_notional(Buy, 99), None);
+        assert_eq!(
+            order_book.size_for_notional(Buy, 100_000),
+            report(20, 2_100)
+        );
+        assert_eq!(order_book.size_for_notional(Sell, 1_000), report(11, 980));
+        assert_eq!(order_book.notional_for_size(Buy, 14), report(14, 1_440));
+        assert_eq!(order_book.notional_for_size(Sell, 25), report(20, 1_700));
+        assert_eq!(order_book.notional_for_size(Sell, 0), None);
+    }
+
+    #[test]
+    fn test_market_notional() {
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Sell, 10, 100, Limit));
+        order_book.add(Order::new(Sell, 10, 110, Limit));
+        order_book.add(Order::new(Sell, 10, 120, Limit));
+        let report = order_book
+            .try_add(Order::market_notional(Buy, 2_000))
+            .unwrap();
+        // 10 @ 100 leaves 1,000, which buys 9 @ 110 with 10 left over.
+        let fills: Vec<(i64, i64)> = report.fills.iter().map(|f| (f.size, f.price)).collect();
+        assert_eq!(fills, vec![(10, 100), (9, 110)]);
+        assert_eq!((report.remaining, report.cancelled), (0, 0));
+        assert_eq!(order_book.best_offer().unwrap().size, 1);
+        assert_eq!(order_book.len_bids(), 0);
+        // Exactly exhausts a level, then the budget is too small for the next.
+        let report = order_book
+            .try_add(Order::market_notional(Buy, 110))
+            .unwrap();
+        assert_eq!(report.fills.len(), 1);
+        assert_eq!(order_book.best_offer().unwrap().price, 120);
+        assert!(order_book
+            .try_add(Order::market_notional(Buy, 119))
+            .unwrap()
+            .fills
+            .is_empty());
+        // Sweeps the book and does not rest with budget to spare.
+        let report = order_book
+            .try_add(Order::market_notional(Buy, 1_000_000))
+            .unwrap();
+        assert_eq!(report.fills[0].size, 10);
+        assert_eq!(order_book.len_offers(), 0);
+        assert_eq!(order_book.len_bids(), 0);
+
+        order_book.add(Order::new(Buy, 5, 50, Limit));
+        let report = order_book
+            .try_add(Order::market_notional(Sell, 120))
+            .unwrap();
+        assert_eq!(report.fills[0].size, 2);
+        assert_eq!(order_book.best_bid().unwrap().size, 3);
+
+        // Budget left after clearing a level carries on to the next, as
+        // `size_for_notional` estimates.
+        let mut order_book: OrderBook = OrderBook::new();
+        order_book.add(Order::new(Buy, 10, 100, Limit));
+        order_book.add(Order::new(Buy, 1, 50, Limit));
+        let estimate = order_book.size_for_notional(Sell, 1_050).unwrap();
+        assert_eq!((estimate.size, estimate.notional), (11, 1_050));
+        let report = order_book
+            .try_add(Order::market_notional(Sell, 1_050))
+            .unwrap();
+        let fills: Vec<(i64, i64)> = report.fills.iter().map(|f| (f.size, f.price)).collect();
+        assert_eq!(fills, vec![(10, 100), (1, 50)]);
+        let filled: i64 = report.fills.iter().map(|f| f.size).sum();
+        let notional: i64 = report.fills.iter().map(|f| f.size * f.price).sum();
+        assert_eq!((filled, notional), (estimate.size, estimate.notional));
+        assert_eq!(order_book.len_bids(), 0);
+        // An ordinary order resting after a partial fill keeps a zero notional.
+        order_book.add(Order::new(Sell, 5, 100, Limit));
+        let number = order_book.add(Order::new(Buy, 10, 100, Limit)).0;
+        assert_eq!(order_book.order(number).unwrap().notional, 0);
+    }
+
+    #[test]
+    fn test_top_of_book_metrics() {
+        let mut order_book: OrderBook = OrderBook::new();
+        assert_eq!(order_book.mid(), None);
+        assert_eq!(order_book.imbalance(5), None);
+        order_book.add(Order::new(Buy, 30, 100, Limit));
+        assert_eq!(order_book.spread(), None);
+        assert_eq!(order_book.microprice(), None);
+        assert_eq!(order_book.imbalance(5), Some(1.0));
+        order_book.add(Order::new(Buy, 20, 99, Limit));
+        order_book.add(Order::new(Sell, 10, 104, Limit));
+        order_book.add(Order::new(Sell, 40, 105, Limit));
+        assert_eq!(order_book.mid(), Some(102.0));
+        assert_eq!(order_book.spread(), Some(4));
+        // Thin offer: the microprice leans up towards it.
+        assert_eq!(
+            order_book.microprice(),
+            Some((100.0 * 10.0 + 104.0 * 30.0) / 40.0)
+        );
+        assert_eq!(order_book.imbalance(1), Some(0.5));
+        assert_eq!(order_book.imbalance(2), Some(0.0));
+    }
 }
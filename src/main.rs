@@ -1,3 +1,99 @@
+//! Order book console.
+//!
+//! With no arguments this is an interactive console; type `help` for commands.
+//!
+//! `orderlib batch INPUT [--fills FILE] [--snapshot FILE]` instead runs a CSV or
+//! NDJSON command file through a fresh book, writing the fills as CSV and the
+//! final book as a JSON snapshot.
+use orderlib::batch::{self, Format};
+use orderlib::orderlib::OrderBook;
+use orderlib::repl;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Write};
+use std::process;
+
 fn main() {
-    println!("Hello, world!");
+    let args: Vec<String> = env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => console(),
+        Some("batch") => {
+            if let Err(error) = batch_mode(&args[1..]) {
+                eprintln!("error: {}", error);
+                process::exit(1);
+            }
+        }
+        Some(_) => {
+            eprintln!("usage: orderlib [batch INPUT [--fills FILE] [--snapshot FILE]]");
+            process::exit(2);
+        }
+    }
+}
+
+fn console() {
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    let mut book = OrderBook::new();
+    if interactive {
+        println!("orderlib console, `help` lists commands");
+    }
+    loop {
+        if interactive {
+            print!("> ");
+            let _ = io::stdout().flush();
+        }
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+        if matches!(line.trim(), "quit" | "exit") {
+            break;
+        }
+        match repl::execute(&mut book, &line) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{}", out),
+            Err(error) => println!("error: {}", error),
+        }
+    }
+}
+
+fn batch_mode(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut input: Option<&str> = None;
+    let mut fills_path: Option<&str> = None;
+    let mut snapshot_path: Option<&str> = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fills" => fills_path = args.next().map(String::as_str),
+            "--snapshot" => snapshot_path = args.next().map(String::as_str),
+            path if input.is_none() => input = Some(path),
+            other => return Err(format!("unexpected argument {}", other).into()),
+        }
+    }
+    let input = input.ok_or("batch needs an input file")?;
+    let mut book = OrderBook::new();
+    let reader = BufReader::new(File::open(input)?);
+    let summary = batch::run(reader, Format::from_path(input), &mut book)?;
+    for (line, reason) in summary.rejected.iter() {
+        eprintln!("line {}: {}", line, reason);
+    }
+    println!(
+        "{} commands, {} fills, {} rejected, {} bids and {} offers resting",
+        summary.commands,
+        summary.fills.len(),
+        summary.rejected.len(),
+        book.len_bids(),
+        book.len_offers()
+    );
+    if let Some(path) = fills_path {
+        let mut out = BufWriter::new(File::create(path)?);
+        batch::write_fills(&mut out, &summary.fills)?;
+        out.flush()?;
+    }
+    if let Some(path) = snapshot_path {
+        let mut out = File::create(path)?;
+        writeln!(out, "{}", book.snapshot().to_json())?;
+    }
+    Ok(())
 }
@@ -0,0 +1,184 @@
+//! Maker/taker fees charged on each fill.
+//!
+//! A `FeeSchedule` set on an `OrderBook` prices both sides of every fill as it is
+//! made, so `Fill::aggressor_fee` and `Fill::passive_fee` are what the account
+//! will be billed.  Rates are in hundredths of a basis point of the fill's
+//! notional (size times price), so 1.1 bps is 110, and step down in tiers as an
+//! account's traded notional grows.  Fees are worked out in integers and rounded
+//! up exactly.
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// Rates, in hundredths of a basis point, that apply once an account has traded
+/// `volume` notional.  A negative rate is a rebate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeeTier {
+    pub volume: i64,
+    pub taker_rate: i64,
+    pub maker_rate: i64,
+}
+
+// Hundredths of a basis point in one.
+const RATE_SCALE: i128 = 1_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct FeeSchedule {
+    /// In increasing order of `volume`.  Volume below the first tier pays nothing.
+    pub tiers: Vec<FeeTier>,
+    /// Smallest fee charged on a fill that is charged at all; rebates are not
+    /// affected.
+    pub minimum: i64,
+}
+
+impl FeeSchedule {
+    /// One tier for everyone and no minimum.
+    pub fn flat(taker_rate: i64, maker_rate: i64) -> FeeSchedule {
+        FeeSchedule {
+            tiers: vec![FeeTier {
+                volume: 0,
+                taker_rate,
+                maker_rate,
+            }],
+            minimum: 0,
+        }
+    }
+
+    /// The tier for an account that has traded `volume` so far.
+    pub fn tier(&self, volume: i64) -> Option<&FeeTier> {
+        self.tiers.iter().rev().find(|tier| tier.volume <= volume)
+    }
+
+    /// Fee on `notional` for the taker or maker side, rounded up; a rebate
+    /// therefore rounds toward zero.
+    pub fn fee(&self, volume: i64, notional: i64, maker: bool) -> i64 {
+        let Some(tier) = self.tier(volume) else {
+            return 0;
+        };
+        let rate = if maker {
+            tier.maker_rate
+        } else {
+            tier.taker_rate
+        };
+        // Ceiling division, which for a negative product rounds toward zero.
+        let product = i128::from(notional) * i128::from(rate);
+        let fee = -(-product).div_euclid(RATE_SCALE);
+        let fee = i64::try_from(fee).unwrap_or(if fee < 0 { i64::MIN } else { i64::MAX });
+        if rate > 0 {
+            fee.max(self.minimum)
+        } else {
+            fee
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FeeSchedule, FeeTier};
+    use crate::orderlib::{
+        Ledger, Order, OrderBook, OrderSide, OrderSide::Buy, OrderSide::Sell, OrderType::Limit,
+        RejectReason,
+    };
+
+    fn order(account: i64, side: OrderSide, size: i64, price: i64) -> Order {
+        Order {
+            account,
+            ..Order::new(side, size, price, Limit)
+        }
+    }
+
+    #[test]
+    fn test_tiers_rounding_and_minimum() {
+        let schedule = FeeSchedule {
+            tiers: vec![
+                FeeTier {
+                    volume: 0,
+                    taker_rate: 3_000,
+                    maker_rate: -1_000,
+                },
+                FeeTier {
+                    volume: 1_000_000,
+                    taker_rate: 2_000,
+                    maker_rate: -1_500,
+                },
+            ],
+            minimum: 5,
+        };
+        assert_eq!(schedule.fee(0, 100_000, false), 300);
+        assert_eq!(schedule.fee(0, 100_000, true), -100);
+        assert_eq!(schedule.fee(2_000_000, 100_000, false), 200);
+        assert_eq!(schedule.fee(2_000_000, 100_000, true), -150);
+        // 0.3 rounds up to the minimum; a -0.1 rebate rounds to nothing.
+        assert_eq!(schedule.fee(0, 100, false), 5);
+        assert_eq!(schedule.fee(0, 100, true), 0);
+        assert_eq!(schedule.fee(-1, 100_000, false), 0);
+        // Exact: 1.1 bps of 100,000 is 11, where floating point made it 12.
+        let schedule = FeeSchedule::flat(110, -110);
+        assert_eq!(schedule.fee(0, 100_000, false), 11);
+        assert_eq!(schedule.fee(0, 100_001, false), 12);
+        assert_eq!(schedule.fee(0, 100_000, true), -11);
+        assert_eq!(schedule.fee(0, 100_001, true), -11);
+        assert_eq!(schedule.fee(0, i64::MAX, false), 1_014_570_924_054_026);
+    }
+
+    #[test]
+    fn test_buying_power_covers_taker_fee() {
+        let mut ledger = Ledger::new();
+        ledger.deposit(1, 10_009);
+        ledger.set_enforce_buying_power(true);
+        let mut book = OrderBook::new();
+        book.attach_ledger(ledger).unwrap();
+        book.set_fee_schedule(Some(FeeSchedule::flat(1_000, -200)))
+            .unwrap();
+        assert_eq!(
+            book.try_add(order(1, Buy, 100, 100)).unwrap_err(),
+            RejectReason::BuyingPower {
+                required: 10_010,
+                available: 10_009
+            }
+        );
+        book.deposit(1, 1).unwrap();
+        assert!(book.try_add(order(1, Buy, 100, 100)).is_ok());
+    }
+
+    #[test]
+    fn test_book_charges_fills() {
+        let mut book = OrderBook::new();
+        book.attach_ledger(Ledger::new()).unwrap();
+        book.set_fee_schedule(Some(FeeSchedule {
+            tiers: vec![
+                FeeTier {
+                    volume: 0,
+                    taker_rate: 1_000,
+                    maker_rate: -200,
+                },
+                FeeTier {
+                    volume: 10_000,
+                    taker_rate: 500,
+                    maker_rate: -200,
+                },
+            ],
+            minimum: 0,
+        }))
+        .unwrap();
+        book.add(order(1, Sell, 200, 100));
+        let fills = book.add(order(2, Buy, 100, 100)).1;
+        assert_eq!((fills[0].aggressor_fee, fills[0].passive_fee), (10, -2));
+        // Account 2 has now traded 10,000 and moves to the cheaper tier.
+        assert_eq!(book.traded_notional(2), 10_000);
+        let fills = book.add(order(2, Buy, 100, 100)).1;
+        assert_eq!((fills[0].aggressor_fee, fills[0].passive_fee), (5, -2));
+        let ledger = book.ledger().unwrap();
+        assert_eq!(ledger.account(2).fees, 15);
+        assert_eq!(ledger.account(2).cash, -20_015);
+        assert_eq!(ledger.account(1).fees, -4);
+        assert_eq!(ledger.account(1).cash, 20_004);
+        book.reset_traded_notional().unwrap();
+        assert_eq!(book.traded_notional(2), 0);
+        book.set_fee_schedule(None).unwrap();
+        book.add(order(1, Sell, 1, 100));
+        let fills = book.add(order(2, Buy, 1, 100)).1;
+        assert_eq!((fills[0].aggressor_fee, fills[0].passive_fee), (0, 0));
+    }
+}
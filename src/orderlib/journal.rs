@@ -0,0 +1,677 @@
+//! An append-only, write-ahead record of every command applied to an `OrderBook`.
+//! Each command is one line of text, written before the book acts on it, so a
+//! journal can be replayed after a crash or read during an audit.  A journal
+//! opened with `Journal::open` syncs every line to disk; one wrapping another
+//! writer is only flushed.
+//!
+//! ```text
+//! A <order_number> <timestamp> <order_id> <side> <size> <price> <type> [<notional> [<account> [<session>]]]   add
+//! R <order_number> <timestamp> <order_id> <side> <size> <price> <type> [<notional> [<account> [<session>]]]   remove
+//! P <order_number> <timestamp> <order_id> <side> <size> <price> <type> [<notional> [<account> [<session>]]]   replace
+//! T <timestamp> <state>                                                                                       transition
+//! B - | B <LastTrade|Static:price> <width> <policy>                                                           price band
+//! M - | M <points>                                                                                            market protection
+//! F - | F <minimum> <volume>:<taker_rate>:<maker_rate>...                                                     fee schedule
+//! N                                                                                                           reset traded notional
+//! L - | L <enforce> <account>:<cash>:<position>:<average_price>:<realized_pnl>:<fees>...                      ledger
+//! D <account> <amount>                                                                                        deposit
+//! E <enforce>                                                                                                 buying power checks
+//! C - | C <grace>                                                                                             cancel-on-disconnect
+//! X <session> <deadline>                                                                                      session disconnected
+//! Y <session>                                                                                                 disconnect no longer pending
+//! ```
+//!
+//! The trailing fields are written only as far as the last nonzero one: the
+//! notional on orders sized by value, the account on attributed orders and the
+//! gateway session id, with zeros filling any gaps before it.  `<enforce>` is
+//! `true` or `false`.
+use super::snapshot::{order_type_from_name, session_from_name};
+use super::{
+    Account, BandPolicy, BandReference, FeeSchedule, FeeTier, Fill, Ledger, Order, OrderBook,
+    OrderSide, PriceBand, SessionState,
+};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+
+/// A single journaled change to the book.  Adds carry the order number and
+/// timestamp the book assigned.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Add(Order),
+    Remove(Order),
+    Replace(Order),
+    Transition(SessionState, i64),
+    SetPriceBand(Option<PriceBand>),
+    SetMarketProtection(Option<i64>),
+    SetFeeSchedule(Option<FeeSchedule>),
+    ResetTradedNotional,
+    SetLedger(Option<Ledger>),
+    Deposit(i64, i64),
+    SetEnforceBuyingPower(bool),
+    SetCancelOnDisconnect(Option<i64>),
+    /// A session dropped; its orders are due to be cancelled at the deadline.
+    Disconnect(i64, i64),
+    /// A pending disconnect ended, by the session returning or its orders being
+    /// cancelled.
+    Reconnect(i64),
+}
+
+impl fmt::Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Command::Add(order) => write_order(f, 'A', order),
+            Command::Remove(order) => write_order(f, 'R', order),
+            Command::Replace(order) => write_order(f, 'P', order),
+            Command::Transition(state, timestamp) => write!(f, "T {} {:?}", timestamp, state),
+            Command::SetPriceBand(None) => write!(f, "B -"),
+            Command::SetPriceBand(Some(band)) => {
+                match band.reference {
+                    BandReference::LastTrade => write!(f, "B LastTrade")?,
+                    BandReference::Static(price) => write!(f, "B Static:{}", price)?,
+                }
+                write!(f, " {} {:?}", band.width, band.policy)
+            }
+            Command::SetMarketProtection(None) => write!(f, "M -"),
+            Command::SetMarketProtection(Some(points)) => write!(f, "M {}", points),
+            Command::SetFeeSchedule(None) => write!(f, "F -"),
+            Command::SetFeeSchedule(Some(fees)) => {
+                write!(f, "F {}", fees.minimum)?;
+                for tier in fees.tiers.iter() {
+                    write!(
+                        f,
+                        " {}:{}:{}",
+                        tier.volume, tier.taker_rate, tier.maker_rate
+                    )?;
+                }
+                Ok(())
+            }
+            Command::ResetTradedNotional => write!(f, "N"),
+            Command::SetLedger(None) => write!(f, "L -"),
+            Command::SetLedger(Some(ledger)) => {
+                write!(f, "L {}", ledger.enforces_buying_power())?;
+                for (id, account) in ledger.sorted_accounts() {
+                    write!(
+                        f,
+                        " {}:{}:{}:{}:{}:{}",
+                        id,
+                        account.cash,
+                        account.position,
+                        account.average_price,
+                        account.realized_pnl,
+                        account.fees
+                    )?;
+                }
+                Ok(())
+            }
+            Command::Deposit(account, amount) => write!(f, "D {} {}", account, amount),
+            Command::SetEnforceBuyingPower(enabled) => write!(f, "E {}", enabled),
+            Command::SetCancelOnDisconnect(None) => write!(f, "C -"),
+            Command::SetCancelOnDisconnect(Some(grace)) => write!(f, "C {}", grace),
+            Command::Disconnect(session_id, deadline) => {
+                write!(f, "X {} {}", session_id, deadline)
+            }
+            Command::Reconnect(session_id) => write!(f, "Y {}", session_id),
+        }
+    }
+}
+
+fn write_order(f: &mut fmt::Formatter, tag: char, order: &Order) -> fmt::Result {
+    write!(
+        f,
+        "{} {} {} {} {:?} {} {} {:?}",
+        tag,
+        order.order_number,
+        order.timestamp,
+        order.order_id,
+        order.order_side,
+        order.size,
+        order.price,
+        order.order_type
+    )?;
+    let trailing = [order.notional, order.account, order.session_id];
+    let len = trailing
+        .iter()
+        .rposition(|field| *field != 0)
+        .map_or(0, |last| last + 1);
+    for field in trailing[..len].iter() {
+        write!(f, " {}", field)?;
+    }
+    Ok(())
+}
+
+impl Command {
+    /// Parses one journal line.
+    pub fn parse(line: &str) -> Option<Command> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            ["A", rest @ ..] => parse_order(rest).map(Command::Add),
+            ["R", rest @ ..] => parse_order(rest).map(Command::Remove),
+            ["P", rest @ ..] => parse_order(rest).map(Command::Replace),
+            ["T", timestamp, state] => Some(Command::Transition(
+                session_from_name(state)?,
+                timestamp.parse().ok()?,
+            )),
+            ["B", "-"] => Some(Command::SetPriceBand(None)),
+            ["B", reference, width, policy] => {
+                let reference = match reference.strip_prefix("Static:") {
+                    Some(price) => BandReference::Static(price.parse().ok()?),
+                    None if *reference == "LastTrade" => BandReference::LastTrade,
+                    None => return None,
+                };
+                let policy = match *policy {
+                    "Cancel" => BandPolicy::Cancel,
+                    "Rest" => BandPolicy::Rest,
+                    _ => return None,
+                };
+                Some(Command::SetPriceBand(Some(PriceBand {
+                    reference,
+                    width: width.parse().ok()?,
+                    policy,
+                })))
+            }
+            ["M", "-"] => Some(Command::SetMarketProtection(None)),
+            ["M", points] => Some(Command::SetMarketProtection(Some(points.parse().ok()?))),
+            ["F", "-"] => Some(Command::SetFeeSchedule(None)),
+            ["F", minimum, tiers @ ..] => {
+                let tiers = tiers
+                    .iter()
+                    .map(|tier| {
+                        let mut rates = tier.split(':').map(str::parse::<i64>);
+                        let tier = FeeTier {
+                            volume: rates.next()?.ok()?,
+                            taker_rate: rates.next()?.ok()?,
+                            maker_rate: rates.next()?.ok()?,
+                        };
+                        rates.next().is_none().then_some(tier)
+                    })
+                    .collect::<Option<Vec<FeeTier>>>()?;
+                Some(Command::SetFeeSchedule(Some(FeeSchedule {
+                    tiers,
+                    minimum: minimum.parse().ok()?,
+                })))
+            }
+            ["N"] => Some(Command::ResetTradedNotional),
+            ["L", "-"] => Some(Command::SetLedger(None)),
+            ["L", enforce, accounts @ ..] => {
+                let mut ledger = Ledger::new();
+                ledger.set_enforce_buying_power(enforce.parse().ok()?);
+                for account in accounts {
+                    let fields: Vec<&str> = account.split(':').collect();
+                    let [id, cash, position, average_price, realized_pnl, fees] = fields[..] else {
+                        return None;
+                    };
+                    ledger.set_account(
+                        id.parse().ok()?,
+                        Account {
+                            cash: cash.parse().ok()?,
+                            position: position.parse().ok()?,
+                            average_price: average_price.parse().ok()?,
+                            realized_pnl: realized_pnl.parse().ok()?,
+                            fees: fees.parse().ok()?,
+                        },
+                    );
+                }
+                Some(Command::SetLedger(Some(ledger)))
+            }
+            ["D", account, amount] => Some(Command::Deposit(
+                account.parse().ok()?,
+                amount.parse().ok()?,
+            )),
+            ["E", enforce] => Some(Command::SetEnforceBuyingPower(enforce.parse().ok()?)),
+            ["C", "-"] => Some(Command::SetCancelOnDisconnect(None)),
+            ["C", grace] => Some(Command::SetCancelOnDisconnect(Some(grace.parse().ok()?))),
+            ["X", session_id, deadline] => Some(Command::Disconnect(
+                session_id.parse().ok()?,
+                deadline.parse().ok()?,
+            )),
+            ["Y", session_id] => Some(Command::Reconnect(session_id.parse().ok()?)),
+            _ => None,
+        }
+    }
+}
+
+fn parse_order(fields: &[&str]) -> Option<Order> {
+    if !(7..=10).contains(&fields.len()) {
+        return None;
+    }
+    let (fields, trailing) = fields.split_at(7);
+    let mut extra = [0i64; 3];
+    for (value, field) in extra.iter_mut().zip(trailing) {
+        *value = field.parse().ok()?;
+    }
+    let [notional, account, session_id] = extra;
+    match fields {
+        [order_number, timestamp, order_id, side, size, price, order_type] => Some(Order {
+            order_id: order_id.parse().ok()?,
+            order_number: order_number.parse().ok()?,
+            order_side: match *side {
+                "Buy" => OrderSide::Buy,
+                "Sell" => OrderSide::Sell,
+                _ => return None,
+            },
+            size: size.parse().ok()?,
+            price: price.parse().ok()?,
+            timestamp: timestamp.parse().ok()?,
+            order_type: order_type_from_name(order_type)?,
+            notional,
+            account,
+            session_id,
+        }),
+        _ => None,
+    }
+}
+
+/// The writing end of a journal.  Once a write fails the journal stays failed and
+/// the book refuses further commands, so the journal never misses one.
+pub struct Journal {
+    out: Box<dyn Write + Send>,
+    // The file behind `out` when opened by path, synced after every write.
+    file: Option<File>,
+    error: Option<io::Error>,
+}
+
+impl fmt::Debug for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Journal")
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+impl Journal {
+    /// Journals to any writer.  Each command is flushed, so it is only as
+    /// durable as `out` makes a flush.
+    pub fn new<W: Write + Send + 'static>(out: W) -> Journal {
+        Journal {
+            out: Box::new(out),
+            file: None,
+            error: None,
+        }
+    }
+
+    /// Opens `path` for appending, creating it if needed.  Each command reaches
+    /// the disk before the book applies it, so it survives a power failure.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Journal> {
+        let file: File = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Journal {
+            file: Some(file.try_clone()?),
+            ..Journal::new(file)
+        })
+    }
+
+    /// Writes and flushes one command, and syncs it if the journal is a file.
+    pub fn append(&mut self, command: &Command) -> io::Result<()> {
+        if let Some(error) = &self.error {
+            return Err(io::Error::new(error.kind(), error.to_string()));
+        }
+        let result = writeln!(self.out, "{}", command)
+            .and_then(|_| self.out.flush())
+            .and_then(|_| self.file.as_ref().map_or(Ok(()), File::sync_data));
+        if let Err(error) = &result {
+            self.error = Some(io::Error::new(error.kind(), error.to_string()));
+        }
+        result
+    }
+
+    /// The write error that stopped this journal, if any.
+    pub fn error(&self) -> Option<&io::Error> {
+        self.error.as_ref()
+    }
+}
+
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    /// The line could not be parsed.  Lines are numbered from 1.
+    Parse(usize),
+    /// The command on this line could not be applied to the book as rebuilt so far.
+    Diverged(usize),
+}
+
+impl fmt::Display for JournalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JournalError::Io(error) => write!(f, "reading journal: {}", error),
+            JournalError::Parse(line) => write!(f, "malformed journal line {}", line),
+            JournalError::Diverged(line) => {
+                write!(f, "journal line {} does not apply to the book", line)
+            }
+        }
+    }
+}
+
+impl std::error::Error for JournalError {}
+
+impl From<io::Error> for JournalError {
+    fn from(error: io::Error) -> JournalError {
+        JournalError::Io(error)
+    }
+}
+
+impl OrderBook {
+    /// Rebuilds a book from a journal, returning it together with every fill the
+    /// journaled commands produced, in order.
+    pub fn replay<R: Read>(journal: R) -> Result<(OrderBook, Vec<Fill>), JournalError> {
+        let mut book = OrderBook::new();
+        let fills = book.apply_journal(journal)?;
+        Ok((book, fills))
+    }
+
+    /// Applies journaled commands on top of the book's current state, e.g. one
+    /// restored from a snapshot taken when the journal was started.  Nothing is
+    /// written to a journal attached to the book.
+    pub fn apply_journal<R: Read>(&mut self, journal: R) -> Result<Vec<Fill>, JournalError> {
+        let mut fills: Vec<Fill> = Vec::new();
+        for (i, line) in BufReader::new(journal).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match Command::parse(&line).ok_or(JournalError::Parse(i + 1))? {
+                Command::Add(order) => {
+                    if order.order_number < self.counter || !self.session.accepts(order.order_type)
+                    {
+                        return Err(JournalError::Diverged(i + 1));
+                    }
+                    fills.extend(self.execute(order).fills);
+                }
+                Command::Remove(order) => {
+                    self.unlink(order);
+                    self.update_bbo();
+                }
+                Command::Replace(order) => {
+                    self.swap(order);
+                }
+                Command::Transition(to, timestamp) => {
+                    if !self.session.can_transition(to) {
+                        return Err(JournalError::Diverged(i + 1));
+                    }
+                    fills.extend(self.enter(to, timestamp));
+                }
+                Command::SetPriceBand(band) => self.price_band = band,
+                Command::SetMarketProtection(points) => self.market_protection = points,
+                Command::SetFeeSchedule(fees) => self.fees = fees,
+                Command::ResetTradedNotional => self.traded.clear(),
+                Command::SetLedger(ledger) => self.ledger = ledger,
+                Command::Deposit(account, amount) => match self.ledger.as_mut() {
+                    Some(ledger) => ledger.deposit(account, amount),
+                    None => return Err(JournalError::Diverged(i + 1)),
+                },
+                Command::SetEnforceBuyingPower(enabled) => match self.ledger.as_mut() {
+                    Some(ledger) => ledger.set_enforce_buying_power(enabled),
+                    None => return Err(JournalError::Diverged(i + 1)),
+                },
+                Command::SetCancelOnDisconnect(grace) => {
+                    self.cancel_on_disconnect = grace;
+                    if grace.is_none() {
+                        self.disconnected.clear();
+                    }
+                }
+                Command::Disconnect(session_id, deadline) => {
+                    if self.cancel_on_disconnect.is_none() {
+                        return Err(JournalError::Diverged(i + 1));
+                    }
+                    self.disconnected.insert(session_id, deadline);
+                }
+                Command::Reconnect(session_id) => {
+                    if self.disconnected.remove(&session_id).is_none() {
+                        return Err(JournalError::Diverged(i + 1));
+                    }
+                }
+            }
+        }
+        Ok(fills)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Command, Journal, JournalError};
+    use crate::orderlib::{
+        BandPolicy, BandReference, FeeSchedule, FeeTier, Fill, Ledger, Order, OrderBook,
+        OrderSide::Buy, OrderSide::Sell, OrderType::Ioc, OrderType::Limit, OrderType::Market,
+        PriceBand, RejectReason, SessionState, TransitionError,
+    };
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk full"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_command_lines_round_trip() {
+        let mut order = Order::new(Sell, 20, 101, Ioc);
+        order.order_number = 1231;
+        order.timestamp = 1700000000000;
+        order.order_id = 7;
+        let mut ledger = Ledger::new();
+        ledger.deposit(1, 500);
+        ledger.set_enforce_buying_power(true);
+        let commands = [
+            Command::Add(order),
+            Command::Remove(order),
+            Command::Replace(order),
+            Command::Transition(SessionState::OpeningAuction, 1700000000001),
+            Command::SetPriceBand(None),
+            Command::SetPriceBand(Some(PriceBand {
+                reference: BandReference::Static(-5),
+                width: 3,
+                policy: BandPolicy::Rest,
+            })),
+            Command::SetMarketProtection(Some(4)),
+            Command::SetMarketProtection(None),
+            Command::SetFeeSchedule(None),
+            Command::SetFeeSchedule(Some(FeeSchedule {
+                tiers: vec![
+                    FeeTier {
+                        volume: 0,
+                        taker_rate: 110,
+                        maker_rate: -20,
+                    },
+                    FeeTier {
+                        volume: 1_000_000,
+                        taker_rate: 90,
+                        maker_rate: -25,
+                    },
+                ],
+                minimum: 1,
+            })),
+            Command::ResetTradedNotional,
+            Command::SetLedger(None),
+            Command::SetLedger(Some(ledger.clone())),
+            Command::Deposit(1, -250),
+            Command::SetEnforceBuyingPower(false),
+            Command::SetCancelOnDisconnect(Some(500)),
+            Command::SetCancelOnDisconnect(None),
+            Command::Disconnect(3, 1_500),
+            Command::Reconnect(3),
+        ];
+        for command in commands.iter() {
+            assert_eq!(Command::parse(&command.to_string()).as_ref(), Some(command));
+        }
+        assert_eq!(Command::parse("A 1 2 3 Buy 4 5"), None);
+        assert_eq!(commands[9].to_string(), "F 1 0:110:-20 1000000:90:-25");
+        assert_eq!(Command::parse("F 1 0:110"), None);
+        assert_eq!(commands[12].to_string(), "L true 1:500:0:0:0:0");
+        assert_eq!(Command::parse("L true 1:500"), None);
+        let mut by_value = Order::market_notional(Buy, 5000);
+        by_value.order_number = 1232;
+        let line = Command::Add(by_value).to_string();
+        assert_eq!(line, "A 1232 0 0 Buy 0 0 Market 5000");
+        match Command::parse(&line) {
+            Some(Command::Add(order)) => assert_eq!(order.notional, 5000),
+            other => panic!("unexpected {:?}", other),
+        }
+        let attributed = Order {
+            account: 42,
+            ..order
+        };
+        let line = Command::Remove(attributed).to_string();
+        assert_eq!(line, "R 1231 1700000000000 7 Sell 20 101 Ioc 0 42");
+        match Command::parse(&line) {
+            Some(Command::Remove(order)) => assert_eq!((order.notional, order.account), (0, 42)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let line = Command::Add(Order {
+            session_id: 3,
+            ..order
+        })
+        .to_string();
+        assert_eq!(line, "A 1231 1700000000000 7 Sell 20 101 Ioc 0 0 3");
+        match Command::parse(&line) {
+            Some(Command::Add(order)) => assert_eq!((order.account, order.session_id), (0, 3)),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(Command::parse(&format!("{} 0", line)), None);
+    }
+
+    #[test]
+    fn test_replay_rebuilds_state_and_fills() {
+        let buffer = Shared::default();
+        let mut order_book = OrderBook::new();
+        order_book.attach_journal(Journal::new(buffer.clone()));
+        let mut fills: Vec<Fill> = Vec::new();
+        order_book.set_market_protection(Some(5)).unwrap();
+        order_book
+            .set_fee_schedule(Some(FeeSchedule::flat(110, -20)))
+            .unwrap();
+        order_book.attach_ledger(Ledger::new()).unwrap();
+        order_book.deposit(0, 10_000).unwrap();
+        order_book.add(Order::new(Buy, 20, 100, Limit));
+        let number = order_book.add(Order::new(Buy, 20, 101, Limit)).0;
+        order_book.add(Order::new(Buy, 10, 99, Limit));
+        fills.extend(order_book.add(Order::new(Sell, 25, 100, Limit)).1);
+        let mut cancel = Order::new(Buy, 10, 99, Limit);
+        cancel.order_number = number + 1;
+        assert_eq!(order_book.remove(cancel), Ok(true));
+        order_book.reset_traded_notional().unwrap();
+        order_book.transition(SessionState::Halted).unwrap();
+        order_book.add(Order::new(Sell, 30, 99, Limit));
+        assert_eq!(order_book.add(Order::new(Sell, 5, 0, Market)).0, 0);
+        fills.extend(order_book.transition(SessionState::Continuous).unwrap());
+        fills.extend(order_book.add(Order::new(Buy, 40, 0, Market)).1);
+        order_book.set_enforce_buying_power(true).unwrap();
+        order_book.set_cancel_on_disconnect(Some(100)).unwrap();
+        order_book.disconnect(8, 0).unwrap();
+        order_book.disconnect(9, 0).unwrap();
+        assert!(order_book.reconnect(8).unwrap());
+
+        let journal = buffer.0.lock().unwrap().clone();
+        let (replayed, replayed_fills) = OrderBook::replay(journal.as_slice()).unwrap();
+        assert_eq!(replayed_fills, fills);
+        assert!(fills.iter().all(|fill| fill.aggressor_fee > 0));
+        assert_eq!(replayed.fee_schedule(), order_book.fee_schedule());
+        assert_eq!(replayed.traded_notional(0), order_book.traded_notional(0));
+        assert_eq!(replayed.ledger(), order_book.ledger());
+        assert_eq!(replayed.buying_power(0), order_book.buying_power(0));
+        assert_eq!(replayed.snapshot(), order_book.snapshot());
+    }
+
+    #[test]
+    fn test_replay_does_not_journal() {
+        let lines = "M 5\nA 1230 0 0 Buy 10 100 Limit\nP 1230 0 0 Buy 4 100 Limit\n\
+                     B LastTrade 3 Cancel\nR 1230 0 0 Buy 4 100 Limit\nT 0 Halted\n";
+        let buffer = Shared::default();
+        let mut order_book = OrderBook::new();
+        order_book.attach_journal(Journal::new(buffer.clone()));
+        order_book.apply_journal(lines.as_bytes()).unwrap();
+        assert!(buffer.0.lock().unwrap().is_empty());
+        // Nor is a replace of an order that is not resting.
+        assert_eq!(order_book.replace(Order::new(Buy, 4, 100, Limit)), Ok(None));
+        assert!(buffer.0.lock().unwrap().is_empty());
+        assert_eq!(order_book.market_protection(), Some(5));
+        assert_eq!(order_book.price_band().unwrap().width, 3);
+        assert_eq!(order_book.session(), SessionState::Halted);
+        assert_eq!(order_book.len_bids(), 0);
+    }
+
+    #[test]
+    fn test_journal_file_is_synced() {
+        let path = std::env::temp_dir().join(format!("orderlib-{}.journal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut order_book = OrderBook::new();
+        order_book.attach_journal(Journal::open(&path).unwrap());
+        order_book.add(Order::new(Buy, 10, 100, Limit));
+        order_book.set_market_protection(Some(2)).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        let (replayed, _) = OrderBook::replay(text.as_bytes()).unwrap();
+        assert_eq!(replayed.snapshot(), order_book.snapshot());
+    }
+
+    #[test]
+    fn test_failed_journal_stops_the_book() {
+        let mut order_book = OrderBook::new();
+        order_book.attach_journal(Journal::new(Broken));
+        assert_eq!(
+            order_book
+                .try_add(Order::new(Buy, 20, 100, Limit))
+                .unwrap_err(),
+            RejectReason::Journal
+        );
+        assert_eq!(order_book.len_bids(), 0);
+        assert_eq!(
+            order_book.set_market_protection(Some(1)),
+            Err(RejectReason::Journal)
+        );
+        assert_eq!(order_book.market_protection(), None);
+        assert_eq!(
+            order_book.transition(SessionState::Halted),
+            Err(TransitionError::Journal)
+        );
+        assert_eq!(order_book.session(), SessionState::Continuous);
+        assert!(order_book.detach_journal().unwrap().error().is_some());
+        let number = order_book
+            .try_add(Order::new(Buy, 20, 100, Limit))
+            .unwrap()
+            .order_number;
+        // Failed cancels and replaces are told apart from missing orders.
+        order_book.attach_journal(Journal::new(Broken));
+        let resting = order_book.order(number).unwrap();
+        assert_eq!(order_book.cancel(number), Err(RejectReason::Journal));
+        assert_eq!(
+            order_book.replace(Order { size: 5, ..resting }),
+            Err(RejectReason::Journal)
+        );
+        assert_eq!(order_book.order(number).unwrap().size, 20);
+        assert_eq!(order_book.cancel(number + 1), Ok(None));
+    }
+
+    #[test]
+    fn test_replay_rejects_divergent_journal() {
+        let journal = "A 1231 0 0 Buy 10 100 Limit\nA 1231 0 0 Buy 10 100 Limit\n";
+        assert!(matches!(
+            OrderBook::replay(journal.as_bytes()),
+            Err(JournalError::Diverged(2))
+        ));
+        assert!(matches!(
+            OrderBook::replay("T 0 Sideways\n".as_bytes()),
+            Err(JournalError::Parse(1))
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+//! Per-account positions, cash and profit and loss, settled from fills.
+//!
+//! Attach a `Ledger` to an `OrderBook` with `attach_ledger` and every fill the
+//! book produces is booked against `Fill::aggressor_account` and
+//! `Fill::passive_account`.  Positions carry an average entry price, so closing
+//! trades realize PnL against it.  With buying power enforced, the book also
+//! refuses orders that an account's cash or position cannot cover once its
+//! resting orders are set aside.
+//!
+//! The attached ledger is part of the book's journal and snapshots, so a replay
+//! or restore makes the same buying power decisions as the live book.  Change it
+//! through `OrderBook::deposit` and `OrderBook::set_enforce_buying_power` once
+//! attached, so that the journal sees every change.
+use super::{Fill, OrderSide};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// One account's balances.  Cash moves by size times price, and by the fee, on
+/// every fill.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Account {
+    pub cash: i64,
+    /// Net filled size: positive long, negative short.
+    pub position: i64,
+    /// Average price of the open position; zero when flat.
+    pub average_price: f64,
+    pub realized_pnl: f64,
+    /// Fees paid, net of rebates.  Already taken out of `cash`.
+    pub fees: i64,
+}
+
+impl Account {
+    /// Profit or loss on the open position if it were closed at `mark`.
+    pub fn unrealized_pnl(&self, mark: f64) -> f64 {
+        self.position as f64 * (mark - self.average_price)
+    }
+
+    // Balances saturate rather than overflow.
+    fn trade(&mut self, side: OrderSide, size: i64, price: i64, fee: i64) {
+        self.cash = self.cash.saturating_sub(fee);
+        self.fees = self.fees.saturating_add(fee);
+        let signed = match side {
+            OrderSide::Buy => size,
+            OrderSide::Sell => -size,
+        };
+        self.cash = self.cash.saturating_sub(signed.saturating_mul(price));
+        if self.position == 0 || self.position.signum() == signed.signum() {
+            let held = self.position.abs() as f64;
+            self.average_price =
+                (self.average_price * held + size as f64 * price as f64) / (held + size as f64);
+        } else {
+            let closed = size.min(self.position.abs());
+            self.realized_pnl +=
+                (closed * self.position.signum()) as f64 * (price as f64 - self.average_price);
+            if size > closed {
+                // Flipped through flat: what is left opened at this price.
+                self.average_price = price as f64;
+            } else if size == self.position.abs() {
+                self.average_price = 0.0;
+            }
+        }
+        self.position = self.position.saturating_add(signed);
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Ledger {
+    accounts: HashMap<i64, Account>,
+    enforce_buying_power: bool,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    /// Adds `amount` to an account's cash; negative to withdraw.  Like every
+    /// balance, cash saturates rather than overflows.
+    pub fn deposit(&mut self, account: i64, amount: i64) {
+        let cash = &mut self.accounts.entry(account).or_default().cash;
+        *cash = cash.saturating_add(amount);
+    }
+
+    /// An account's balances, all zero if it has never been used.
+    pub fn account(&self, account: i64) -> Account {
+        self.accounts.get(&account).copied().unwrap_or_default()
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = (i64, &Account)> + '_ {
+        self.accounts.iter().map(|(id, account)| (*id, account))
+    }
+
+    // Every account in id order, for journal lines and snapshots.
+    pub(super) fn sorted_accounts(&self) -> Vec<(i64, Account)> {
+        let mut accounts: Vec<(i64, Account)> = self
+            .accounts
+            .iter()
+            .map(|(id, account)| (*id, *account))
+            .collect();
+        accounts.sort_unstable_by_key(|(id, _)| *id);
+        accounts
+    }
+
+    // Puts back an account read from a journal or snapshot.
+    pub(super) fn set_account(&mut self, id: i64, account: Account) {
+        self.accounts.insert(id, account);
+    }
+
+    /// When on, the book rejects a buy costing more than the account's cash less
+    /// its resting bids, and a sell larger than its position less its resting
+    /// offers.  Off by default, in which case the ledger only keeps score.
+    pub fn set_enforce_buying_power(&mut self, enabled: bool) {
+        self.enforce_buying_power = enabled;
+    }
+
+    pub fn enforces_buying_power(&self) -> bool {
+        self.enforce_buying_power
+    }
+
+    /// Books both sides of a fill.
+    pub fn apply(&mut self, fill: &Fill) {
+        let passive_side = match fill.direction {
+            OrderSide::Buy => OrderSide::Sell,
+            OrderSide::Sell => OrderSide::Buy,
+        };
+        self.accounts
+            .entry(fill.aggressor_account)
+            .or_default()
+            .trade(fill.direction, fill.size, fill.price, fill.aggressor_fee);
+        self.accounts
+            .entry(fill.passive_account)
+            .or_default()
+            .trade(passive_side, fill.size, fill.price, fill.passive_fee);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Ledger;
+    use crate::orderlib::{
+        Order, OrderBook, OrderSide, OrderSide::Buy, OrderSide::Sell, OrderType::Limit,
+        OrderType::Market, RejectReason,
+    };
+
+    fn order(account: i64, side: OrderSide, size: i64, price: i64) -> Order {
+        Order {
+            account,
+            ..Order::new(side, size, price, Limit)
+        }
+    }
+
+    #[test]
+    fn test_positions_and_pnl() {
+        let mut book = OrderBook::new();
+        book.attach_ledger(Ledger::new()).unwrap();
+        book.add(order(1, Sell, 10, 100));
+        book.add(order(2, Buy, 10, 100));
+        book.add(order(1, Sell, 10, 110));
+        book.add(order(2, Buy, 5, 110));
+        let ledger = book.ledger().unwrap();
+        let buyer = ledger.account(2);
+        assert_eq!(buyer.position, 15);
+        assert_eq!(buyer.cash, -1550);
+        assert!((buyer.average_price - 1550.0 / 15.0).abs() < 1e-9);
+        assert!((buyer.unrealized_pnl(110.0) - 100.0).abs() < 1e-9);
+        assert_eq!(ledger.account(1).position, -15);
+        // Selling out at 120, part against a fresh bid, flips to short.
+        book.add(order(3, Buy, 25, 120));
+        book.add(order(2, Sell, 20, 120));
+        let buyer = book.ledger().unwrap().account(2);
+        assert_eq!(buyer.position, -5);
+        assert!((buyer.realized_pnl - 250.0).abs() < 1e-9);
+        assert_eq!(buyer.average_price, 120.0);
+        assert_eq!(buyer.cash, 850);
+        assert_eq!(book.ledger().unwrap().accounts().count(), 3);
+    }
+
+    #[test]
+    fn test_buying_power() {
+        let mut book = OrderBook::new();
+        let mut ledger = Ledger::new();
+        ledger.set_enforce_buying_power(true);
+        ledger.deposit(1, 1000);
+        book.attach_ledger(ledger).unwrap();
+        assert_eq!(book.buying_power(1), Some(1000));
+        let mut ledger = Ledger::new();
+        ledger.deposit(3, i64::MAX);
+        ledger.deposit(3, 1);
+        assert_eq!(ledger.account(3).cash, i64::MAX);
+        book.try_add(order(1, Buy, 6, 100)).unwrap();
+        assert_eq!(book.buying_power(1), Some(400));
+        assert_eq!(
+            book.try_add(order(1, Buy, 5, 100)).unwrap_err(),
+            RejectReason::BuyingPower {
+                required: 500,
+                available: 400
+            }
+        );
+        // Account 2 has nothing to sell.
+        assert_eq!(
+            book.try_add(order(2, Sell, 1, 100)).unwrap_err(),
+            RejectReason::BuyingPower {
+                required: 1,
+                available: 0
+            }
+        );
+        book.set_enforce_buying_power(false).unwrap();
+        book.try_add(order(2, Sell, 4, 100)).unwrap();
+        book.try_add(order(2, Sell, 4, 105)).unwrap();
+        book.set_enforce_buying_power(true).unwrap();
+        // The fill spent 400 of the reserved 600.
+        assert_eq!(book.ledger().unwrap().account(1).cash, 600);
+        assert_eq!(book.buying_power(1), Some(400));
+        let market = Order {
+            account: 1,
+            ..Order::new(Buy, 4, 0, Market)
+        };
+        assert_eq!(
+            book.try_add(market).unwrap_err(),
+            RejectReason::BuyingPower {
+                required: 420,
+                available: 400
+            }
+        );
+        // Account 1 holds 4 and may sell them back, but no more.
+        assert!(book.try_add(order(1, Sell, 5, 200)).is_err());
+        book.try_add(order(1, Sell, 4, 200)).unwrap();
+        assert!(book.try_add(order(1, Sell, 1, 200)).is_err());
+    }
+
+    #[test]
+    fn test_reservations_follow_the_book() {
+        let mut book = OrderBook::new();
+        let mut ledger = Ledger::new();
+        ledger.deposit(1, 1000);
+        book.attach_ledger(ledger).unwrap();
+        let first = book.add(order(1, Buy, 6, 100)).0;
+        let second = book.add(order(1, Buy, 2, 50)).0;
+        assert_eq!(book.buying_power(1), Some(300));
+        book.add(order(2, Sell, 4, 100));
+        // 400 spent, 200 of the first bid and all of the second still held.
+        assert_eq!(book.buying_power(1), Some(300));
+        let reduced = Order {
+            size: 1,
+            ..book.order(first).unwrap()
+        };
+        book.replace(reduced).unwrap().unwrap();
+        assert_eq!(book.buying_power(1), Some(400));
+        // A replace cannot grow an order or move it to another account, which
+        // would skip the buying power check.
+        for amended in [
+            Order { size: 9, ..reduced },
+            Order {
+                account: 2,
+                ..reduced
+            },
+        ] {
+            assert_eq!(book.replace(amended), Err(RejectReason::Amend));
+        }
+        assert_eq!(book.buying_power(1), Some(400));
+        assert_eq!(book.cancel(second).unwrap().unwrap().size, 2);
+        assert_eq!(book.buying_power(1), Some(500));
+        // The ledger comes back with the book.
+        let mut restored = OrderBook::restore(&book.snapshot()).unwrap();
+        assert_eq!(restored.ledger(), book.ledger());
+        assert_eq!(restored.buying_power(1), Some(500));
+        assert_eq!(restored.order(first).unwrap().size, 1);
+        restored.cancel(first).unwrap();
+        assert_eq!(restored.buying_power(1), Some(600));
+    }
+}
@@ -0,0 +1,1118 @@
+//! Point-in-time copies of an `OrderBook` that can be stored and restored exactly.
+//! Two encodings are provided: a compact little-endian binary form and JSON.  Both
+//! carry a format version so that older snapshots remain readable.
+use super::{
+    Account, BandPolicy, BandReference, FeeSchedule, FeeTier, Ledger, Order, OrderBook, OrderSide,
+    OrderType, PriceBand, SessionState,
+};
+use crate::json::{self, Value};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"OLSN";
+// Version 2 added `Order::notional`, version 3 `Order::account`, version 4
+// `Order::session_id`, version 5 the fee schedule, traded notionals and fill
+// count, version 6 the ledger and version 7 cancel-on-disconnect.
+const VERSION: u16 = 7;
+
+/// Everything needed to rebuild an `OrderBook`.  Bid prices are stored as positive
+/// prices, and both sides are listed best first, in time priority within a level.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct BookSnapshot {
+    pub bids: Vec<Order>,
+    pub offers: Vec<Order>,
+    pub counter: i64,
+    pub session: SessionState,
+    pub price_band: Option<PriceBand>,
+    pub last_trade: Option<i64>,
+    pub market_protection: Option<i64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fees: Option<FeeSchedule>,
+    /// Notional traded per account since the last reset, as `(account, notional)`
+    /// in account order.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub traded: Vec<(i64, i64)>,
+    /// Fills made so far, so that `Fill::fill_id` carries on where it left off.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fill_count: i64,
+    /// The attached ledger, so that buying power is the same after a restore.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub ledger: Option<Ledger>,
+    /// The cancel-on-disconnect grace period, if on.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub cancel_on_disconnect: Option<i64>,
+    /// Sessions whose orders are due to be cancelled, as `(session_id, deadline)`
+    /// in session order.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub disconnected: Vec<(i64, i64)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SnapshotError {
+    /// The data does not start with the snapshot magic bytes.
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// The binary data ended early.
+    Truncated,
+    /// A field held a value that does not map onto the book's types.
+    Invalid(&'static str),
+    /// The JSON could not be parsed; holds the byte offset of the problem.
+    Json(usize),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a book snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::Truncated => write!(f, "snapshot data is truncated"),
+            SnapshotError::Invalid(field) => write!(f, "invalid value for {}", field),
+            SnapshotError::Json(pos) => write!(f, "malformed JSON at byte {}", pos),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl OrderBook {
+    /// Captures the full state of the book.  Pending session events are not included.
+    pub fn snapshot(&self) -> BookSnapshot {
+        BookSnapshot {
+            bids: self
+                .buy_orders
+                .iter()
+                .map(|order| Order {
+                    price: -order.price,
+                    ..*order
+                })
+                .collect(),
+            offers: self.sell_orders.iter().copied().collect(),
+            counter: self.counter,
+            session: self.session,
+            price_band: self.price_band,
+            last_trade: self.last_trade,
+            market_protection: self.market_protection,
+            fees: self.fees.clone(),
+            traded: {
+                let mut traded: Vec<(i64, i64)> =
+                    self.traded.iter().map(|(k, v)| (*k, *v)).collect();
+                traded.sort_unstable();
+                traded
+            },
+            fill_count: self.fill_count,
+            ledger: self.ledger.clone(),
+            cancel_on_disconnect: self.cancel_on_disconnect,
+            disconnected: {
+                let mut disconnected: Vec<(i64, i64)> =
+                    self.disconnected.iter().map(|(k, v)| (*k, *v)).collect();
+                disconnected.sort_unstable();
+                disconnected
+            },
+        }
+    }
+
+    /// Rebuilds a book from a snapshot.  Orders keep their order numbers and
+    /// timestamps, so time priority is exactly as it was.  Fails if the snapshot
+    /// does not describe a consistent book: an order on the wrong side, two
+    /// orders with the same number, a counter that would hand out a number
+    /// already in use, fee tiers out of order, an account traded twice, a
+    /// negative fill count, or a session disconnected twice or while
+    /// cancel-on-disconnect is off.
+    pub fn restore(snapshot: &BookSnapshot) -> Result<OrderBook, SnapshotError> {
+        let mut numbers = HashSet::with_capacity(snapshot.bids.len() + snapshot.offers.len());
+        for (orders, side, field) in [
+            (&snapshot.bids, OrderSide::Buy, "bids"),
+            (&snapshot.offers, OrderSide::Sell, "offers"),
+        ] {
+            for order in orders.iter() {
+                if order.order_side != side {
+                    return Err(SnapshotError::Invalid(field));
+                }
+                if !numbers.insert(order.order_number) {
+                    return Err(SnapshotError::Invalid("order_number"));
+                }
+                if order.order_number >= snapshot.counter {
+                    return Err(SnapshotError::Invalid("counter"));
+                }
+                // The same bounds `check` puts on an incoming order, and bids are
+                // stored with their price negated.
+                if order.size <= 0 {
+                    return Err(SnapshotError::Invalid("size"));
+                }
+                if order.size.checked_mul(order.price).is_none() || order.price == i64::MIN {
+                    return Err(SnapshotError::Invalid("price"));
+                }
+            }
+        }
+        if let Some(fees) = snapshot.fees.as_ref() {
+            if fees
+                .tiers
+                .windows(2)
+                .any(|pair| pair[0].volume >= pair[1].volume)
+            {
+                return Err(SnapshotError::Invalid("fees"));
+            }
+        }
+        if snapshot.fill_count < 0 {
+            return Err(SnapshotError::Invalid("fill_count"));
+        }
+        let traded: HashMap<i64, i64> = snapshot.traded.iter().copied().collect();
+        if traded.len() != snapshot.traded.len() {
+            return Err(SnapshotError::Invalid("traded"));
+        }
+        let disconnected: HashMap<i64, i64> = snapshot.disconnected.iter().copied().collect();
+        if disconnected.len() != snapshot.disconnected.len()
+            || (snapshot.cancel_on_disconnect.is_none() && !disconnected.is_empty())
+        {
+            return Err(SnapshotError::Invalid("disconnected"));
+        }
+        let mut book = OrderBook::new();
+        book.buy_orders
+            .extend(snapshot.bids.iter().map(|order| Order {
+                price: -order.price,
+                ..*order
+            }));
+        book.sell_orders.extend(snapshot.offers.iter().copied());
+        book.counter = snapshot.counter;
+        book.session = snapshot.session;
+        book.price_band = snapshot.price_band;
+        book.last_trade = snapshot.last_trade;
+        book.market_protection = snapshot.market_protection;
+        book.fees = snapshot.fees.clone();
+        book.traded = traded;
+        book.fill_count = snapshot.fill_count;
+        book.ledger = snapshot.ledger.clone();
+        book.cancel_on_disconnect = snapshot.cancel_on_disconnect;
+        book.disconnected = disconnected;
+        book.update_bbo();
+        Ok(book)
+    }
+}
+
+/// With the `serde` feature a book serializes as its `BookSnapshot`.
+#[cfg(feature = "serde")]
+impl Serialize for OrderBook {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.snapshot().serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for OrderBook {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<OrderBook, D::Error> {
+        let snapshot = BookSnapshot::deserialize(deserializer)?;
+        OrderBook::restore(&snapshot).map_err(serde::de::Error::custom)
+    }
+}
+
+impl BookSnapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(32 + 50 * (self.bids.len() + self.offers.len()));
+        out.extend_from_slice(MAGIC);
+        out.extend_from_slice(&VERSION.to_le_bytes());
+        out.extend_from_slice(&self.counter.to_le_bytes());
+        out.push(session_code(self.session));
+        put_option(&mut out, self.last_trade);
+        put_option(&mut out, self.market_protection);
+        match self.price_band {
+            None => out.push(0),
+            Some(band) => {
+                out.push(1);
+                match band.reference {
+                    BandReference::LastTrade => out.push(0),
+                    BandReference::Static(price) => {
+                        out.push(1);
+                        out.extend_from_slice(&price.to_le_bytes());
+                    }
+                }
+                out.extend_from_slice(&band.width.to_le_bytes());
+                out.push(match band.policy {
+                    BandPolicy::Cancel => 0,
+                    BandPolicy::Rest => 1,
+                });
+            }
+        }
+        for side in [&self.bids, &self.offers] {
+            out.extend_from_slice(&(side.len() as u32).to_le_bytes());
+            for order in side.iter() {
+                put_order(&mut out, order);
+            }
+        }
+        match &self.fees {
+            None => out.push(0),
+            Some(fees) => {
+                out.push(1);
+                out.extend_from_slice(&fees.minimum.to_le_bytes());
+                out.extend_from_slice(&(fees.tiers.len() as u32).to_le_bytes());
+                for tier in fees.tiers.iter() {
+                    for value in [tier.volume, tier.taker_rate, tier.maker_rate] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                }
+            }
+        }
+        out.extend_from_slice(&(self.traded.len() as u32).to_le_bytes());
+        for (account, notional) in self.traded.iter() {
+            out.extend_from_slice(&account.to_le_bytes());
+            out.extend_from_slice(&notional.to_le_bytes());
+        }
+        out.extend_from_slice(&self.fill_count.to_le_bytes());
+        match &self.ledger {
+            None => out.push(0),
+            Some(ledger) => {
+                out.push(1);
+                out.push(u8::from(ledger.enforces_buying_power()));
+                let accounts = ledger.sorted_accounts();
+                out.extend_from_slice(&(accounts.len() as u32).to_le_bytes());
+                for (id, account) in accounts {
+                    for value in [id, account.cash, account.position] {
+                        out.extend_from_slice(&value.to_le_bytes());
+                    }
+                    out.extend_from_slice(&account.average_price.to_le_bytes());
+                    out.extend_from_slice(&account.realized_pnl.to_le_bytes());
+                    out.extend_from_slice(&account.fees.to_le_bytes());
+                }
+            }
+        }
+        put_option(&mut out, self.cancel_on_disconnect);
+        out.extend_from_slice(&(self.disconnected.len() as u32).to_le_bytes());
+        for (session_id, deadline) in self.disconnected.iter() {
+            out.extend_from_slice(&session_id.to_le_bytes());
+            out.extend_from_slice(&deadline.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<BookSnapshot, SnapshotError> {
+        let mut reader = Reader {
+            bytes,
+            pos: 0,
+            version: VERSION,
+        };
+        if reader.take(4)? != MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+        reader.version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if !(1..=VERSION).contains(&reader.version) {
+            return Err(SnapshotError::UnsupportedVersion(reader.version));
+        }
+        let counter = reader.i64()?;
+        let session = session_from_code(reader.u8()?)?;
+        let last_trade = reader.option()?;
+        let market_protection = reader.option()?;
+        let price_band = match reader.u8()? {
+            0 => None,
+            1 => {
+                let reference = match reader.u8()? {
+                    0 => BandReference::LastTrade,
+                    1 => BandReference::Static(reader.i64()?),
+                    _ => return Err(SnapshotError::Invalid("price_band.reference")),
+                };
+                let width = reader.i64()?;
+                let policy = match reader.u8()? {
+                    0 => BandPolicy::Cancel,
+                    1 => BandPolicy::Rest,
+                    _ => return Err(SnapshotError::Invalid("price_band.policy")),
+                };
+                Some(PriceBand {
+                    reference,
+                    width,
+                    policy,
+                })
+            }
+            _ => return Err(SnapshotError::Invalid("price_band")),
+        };
+        let bids = reader.orders()?;
+        let offers = reader.orders()?;
+        let (fees, traded, fill_count) = if reader.version >= 5 {
+            let fees = match reader.u8()? {
+                0 => None,
+                1 => {
+                    let minimum = reader.i64()?;
+                    let count = reader.u32()? as usize;
+                    let mut tiers = Vec::with_capacity(count.min(bytes.len() / 24));
+                    for _ in 0..count {
+                        tiers.push(FeeTier {
+                            volume: reader.i64()?,
+                            taker_rate: reader.i64()?,
+                            maker_rate: reader.i64()?,
+                        });
+                    }
+                    Some(FeeSchedule { tiers, minimum })
+                }
+                _ => return Err(SnapshotError::Invalid("fees")),
+            };
+            let count = reader.u32()? as usize;
+            let mut traded = Vec::with_capacity(count.min(bytes.len() / 16));
+            for _ in 0..count {
+                traded.push((reader.i64()?, reader.i64()?));
+            }
+            (fees, traded, reader.i64()?)
+        } else {
+            (None, Vec::new(), 0)
+        };
+        let ledger = if reader.version >= 6 {
+            match reader.u8()? {
+                0 => None,
+                1 => {
+                    let mut ledger = Ledger::new();
+                    ledger.set_enforce_buying_power(match reader.u8()? {
+                        0 => false,
+                        1 => true,
+                        _ => return Err(SnapshotError::Invalid("ledger")),
+                    });
+                    for _ in 0..reader.u32()? {
+                        let id = reader.i64()?;
+                        let account = Account {
+                            cash: reader.i64()?,
+                            position: reader.i64()?,
+                            average_price: reader.f64()?,
+                            realized_pnl: reader.f64()?,
+                            fees: reader.i64()?,
+                        };
+                        ledger.set_account(id, account);
+                    }
+                    Some(ledger)
+                }
+                _ => return Err(SnapshotError::Invalid("ledger")),
+            }
+        } else {
+            None
+        };
+        let (cancel_on_disconnect, disconnected) = if reader.version >= 7 {
+            let grace = reader.option()?;
+            let count = reader.u32()? as usize;
+            let mut disconnected = Vec::with_capacity(count.min(bytes.len() / 16));
+            for _ in 0..count {
+                disconnected.push((reader.i64()?, reader.i64()?));
+            }
+            (grace, disconnected)
+        } else {
+            (None, Vec::new())
+        };
+        if reader.pos != bytes.len() {
+            return Err(SnapshotError::Invalid("trailing data"));
+        }
+        Ok(BookSnapshot {
+            bids,
+            offers,
+            counter,
+            session,
+            price_band,
+            last_trade,
+            market_protection,
+            fees,
+            traded,
+            fill_count,
+            ledger,
+            cancel_on_disconnect,
+            disconnected,
+        })
+    }
+
+    /// Encodes the snapshot as a single JSON object.
+    pub fn to_json(&self) -> String {
+        let band = match self.price_band {
+            None => Value::Null,
+            Some(band) => Value::Object(vec![
+                (
+                    "reference".to_string(),
+                    match band.reference {
+                        BandReference::LastTrade => "LastTrade".into(),
+                        BandReference::Static(price) => {
+                            Value::Object(vec![("Static".to_string(), price.into())])
+                        }
+                    },
+                ),
+                ("width".to_string(), band.width.into()),
+                (
+                    "policy".to_string(),
+                    format!("{:?}", band.policy).as_str().into(),
+                ),
+            ]),
+        };
+        let value = Value::Object(vec![
+            ("version".to_string(), i64::from(VERSION).into()),
+            ("counter".to_string(), self.counter.into()),
+            (
+                "session".to_string(),
+                format!("{:?}", self.session).as_str().into(),
+            ),
+            ("last_trade".to_string(), self.last_trade.into()),
+            (
+                "market_protection".to_string(),
+                self.market_protection.into(),
+            ),
+            ("price_band".to_string(), band),
+            (
+                "fees".to_string(),
+                self.fees.as_ref().map_or(Value::Null, fees_to_json),
+            ),
+            ("traded".to_string(), pairs_to_json(&self.traded)),
+            ("fill_count".to_string(), self.fill_count.into()),
+            (
+                "ledger".to_string(),
+                self.ledger.as_ref().map_or(Value::Null, ledger_to_json),
+            ),
+            (
+                "cancel_on_disconnect".to_string(),
+                self.cancel_on_disconnect.into(),
+            ),
+            (
+                "disconnected".to_string(),
+                pairs_to_json(&self.disconnected),
+            ),
+            (
+                "bids".to_string(),
+                Value::Array(self.bids.iter().map(order_to_json).collect()),
+            ),
+            (
+                "offers".to_string(),
+                Value::Array(self.offers.iter().map(order_to_json).collect()),
+            ),
+        ]);
+        let mut out = String::new();
+        value.write(&mut out);
+        out
+    }
+
+    pub fn from_json(text: &str) -> Result<BookSnapshot, SnapshotError> {
+        let value = json::parse(text).map_err(SnapshotError::Json)?;
+        let version = int_field(&value, "version")?;
+        if !(1..=i64::from(VERSION)).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version as u16));
+        }
+        let price_band = match value.get("price_band") {
+            None => None,
+            Some(band) if band.is_null() => None,
+            Some(band) => {
+                let reference = match band.get("reference") {
+                    Some(Value::String(name)) if name == "LastTrade" => BandReference::LastTrade,
+                    Some(reference) => BandReference::Static(
+                        reference
+                            .get("Static")
+                            .and_then(Value::as_i64)
+                            .ok_or(SnapshotError::Invalid("price_band.reference"))?,
+                    ),
+                    None => return Err(SnapshotError::Invalid("price_band.reference")),
+                };
+                let policy = match band.get("policy").and_then(Value::as_str) {
+                    Some("Cancel") => BandPolicy::Cancel,
+                    Some("Rest") => BandPolicy::Rest,
+                    _ => return Err(SnapshotError::Invalid("price_band.policy")),
+                };
+                Some(PriceBand {
+                    reference,
+                    width: int_field(band, "width")?,
+                    policy,
+                })
+            }
+        };
+        Ok(BookSnapshot {
+            bids: orders_field(&value, "bids")?,
+            offers: orders_field(&value, "offers")?,
+            counter: int_field(&value, "counter")?,
+            session: value
+                .get("session")
+                .and_then(Value::as_str)
+                .and_then(session_from_name)
+                .ok_or(SnapshotError::Invalid("session"))?,
+            price_band,
+            last_trade: optional_int_field(&value, "last_trade")?,
+            market_protection: optional_int_field(&value, "market_protection")?,
+            fees: match value.get("fees") {
+                None => None,
+                Some(fees) if fees.is_null() => None,
+                Some(fees) => Some(fees_from_json(fees)?),
+            },
+            traded: pairs_field(&value, "traded")?,
+            fill_count: optional_int_field(&value, "fill_count")?.unwrap_or(0),
+            ledger: match value.get("ledger") {
+                None => None,
+                Some(ledger) if ledger.is_null() => None,
+                Some(ledger) => Some(ledger_from_json(ledger)?),
+            },
+            cancel_on_disconnect: optional_int_field(&value, "cancel_on_disconnect")?,
+            disconnected: pairs_field(&value, "disconnected")?,
+        })
+    }
+}
+
+const SESSION_STATES: [SessionState; 5] = [
+    SessionState::PreOpen,
+    SessionState::OpeningAuction,
+    SessionState::Continuous,
+    SessionState::Halted,
+    SessionState::Closed,
+];
+
+const ORDER_TYPES: [OrderType; 5] = [
+    OrderType::Limit,
+    OrderType::Market,
+    OrderType::Fok,
+    OrderType::Ioc,
+    OrderType::Aon,
+];
+
+fn session_code(session: SessionState) -> u8 {
+    SESSION_STATES.iter().position(|s| *s == session).unwrap() as u8
+}
+
+fn session_from_code(code: u8) -> Result<SessionState, SnapshotError> {
+    SESSION_STATES
+        .get(code as usize)
+        .copied()
+        .ok_or(SnapshotError::Invalid("session"))
+}
+
+pub(super) fn session_from_name(name: &str) -> Option<SessionState> {
+    SESSION_STATES
+        .iter()
+        .copied()
+        .find(|s| format!("{:?}", s) == name)
+}
+
+fn order_type_code(order_type: OrderType) -> u8 {
+    ORDER_TYPES.iter().position(|t| *t == order_type).unwrap() as u8
+}
+
+pub(super) fn order_type_from_name(name: &str) -> Option<OrderType> {
+    ORDER_TYPES
+        .iter()
+        .copied()
+        .find(|t| format!("{:?}", t) == name)
+}
+
+fn put_option(out: &mut Vec<u8>, value: Option<i64>) {
+    match value {
+        None => out.push(0),
+        Some(v) => {
+            out.push(1);
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+}
+
+fn put_order(out: &mut Vec<u8>, order: &Order) {
+    out.extend_from_slice(&order.order_id.to_le_bytes());
+    out.extend_from_slice(&order.order_number.to_le_bytes());
+    out.push(match order.order_side {
+        OrderSide::Buy => 0,
+        OrderSide::Sell => 1,
+    });
+    out.extend_from_slice(&order.size.to_le_bytes());
+    out.extend_from_slice(&order.price.to_le_bytes());
+    out.extend_from_slice(&order.timestamp.to_le_bytes());
+    out.push(order_type_code(order.order_type));
+    out.extend_from_slice(&order.notional.to_le_bytes());
+    out.extend_from_slice(&order.account.to_le_bytes());
+    out.extend_from_slice(&order.session_id.to_le_bytes());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    version: u16,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or(SnapshotError::Truncated)?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn i64(&mut self) -> Result<i64, SnapshotError> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64, SnapshotError> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn option(&mut self) -> Result<Option<i64>, SnapshotError> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.i64()?)),
+            _ => Err(SnapshotError::Invalid("option tag")),
+        }
+    }
+
+    fn orders(&mut self) -> Result<Vec<Order>, SnapshotError> {
+        let count = self.u32()? as usize;
+        let mut orders = Vec::with_capacity(count.min(self.bytes.len() / 42));
+        for _ in 0..count {
+            let order_id = self.i64()?;
+            let order_number = self.i64()?;
+            let order_side = match self.u8()? {
+                0 => OrderSide::Buy,
+                1 => OrderSide::Sell,
+                _ => return Err(SnapshotError::Invalid("order_side")),
+            };
+            let size = self.i64()?;
+            let price = self.i64()?;
+            let timestamp = self.i64()?;
+            let order_type = *ORDER_TYPES
+                .get(self.u8()? as usize)
+                .ok_or(SnapshotError::Invalid("order_type"))?;
+            let notional = if self.version >= 2 { self.i64()? } else { 0 };
+            let account = if self.version >= 3 { self.i64()? } else { 0 };
+            let session_id = if self.version >= 4 { self.i64()? } else { 0 };
+            orders.push(Order {
+                order_id,
+                order_number,
+                order_side,
+                size,
+                price,
+                timestamp,
+                order_type,
+                notional,
+                account,
+                session_id,
+            });
+        }
+        Ok(orders)
+    }
+}
+
+fn order_to_json(order: &Order) -> Value {
+    Value::Object(vec![
+        ("order_id".to_string(), order.order_id.into()),
+        ("order_number".to_string(), order.order_number.into()),
+        (
+            "order_side".to_string(),
+            format!("{:?}", order.order_side).as_str().into(),
+        ),
+        ("size".to_string(), order.size.into()),
+        ("price".to_string(), order.price.into()),
+        ("timestamp".to_string(), order.timestamp.into()),
+        (
+            "order_type".to_string(),
+            format!("{:?}", order.order_type).as_str().into(),
+        ),
+        ("notional".to_string(), order.notional.into()),
+        ("account".to_string(), order.account.into()),
+        ("session_id".to_string(), order.session_id.into()),
+    ])
+}
+
+fn order_from_json(value: &Value) -> Result<Order, SnapshotError> {
+    Ok(Order {
+        order_id: int_field(value, "order_id")?,
+        order_number: int_field(value, "order_number")?,
+        order_side: match value.get("order_side").and_then(Value::as_str) {
+            Some("Buy") => OrderSide::Buy,
+            Some("Sell") => OrderSide::Sell,
+            _ => return Err(SnapshotError::Invalid("order_side")),
+        },
+        size: int_field(value, "size")?,
+        price: int_field(value, "price")?,
+        timestamp: int_field(value, "timestamp")?,
+        order_type: value
+            .get("order_type")
+            .and_then(Value::as_str)
+            .and_then(order_type_from_name)
+            .ok_or(SnapshotError::Invalid("order_type"))?,
+        notional: optional_int_field(value, "notional")?.unwrap_or(0),
+        account: optional_int_field(value, "account")?.unwrap_or(0),
+        session_id: optional_int_field(value, "session_id")?.unwrap_or(0),
+    })
+}
+
+fn fees_to_json(fees: &FeeSchedule) -> Value {
+    let tiers = fees
+        .tiers
+        .iter()
+        .map(|tier| {
+            Value::Object(vec![
+                ("volume".to_string(), tier.volume.into()),
+                ("taker_rate".to_string(), tier.taker_rate.into()),
+                ("maker_rate".to_string(), tier.maker_rate.into()),
+            ])
+        })
+        .collect();
+    Value::Object(vec![
+        ("tiers".to_string(), Value::Array(tiers)),
+        ("minimum".to_string(), fees.minimum.into()),
+    ])
+}
+
+fn fees_from_json(value: &Value) -> Result<FeeSchedule, SnapshotError> {
+    let tiers = value
+        .get("tiers")
+        .and_then(Value::as_array)
+        .ok_or(SnapshotError::Invalid("fees.tiers"))?
+        .iter()
+        .map(|tier| {
+            Ok(FeeTier {
+                volume: int_field(tier, "volume")?,
+                taker_rate: int_field(tier, "taker_rate")?,
+                maker_rate: int_field(tier, "maker_rate")?,
+            })
+        })
+        .collect::<Result<_, SnapshotError>>()?;
+    Ok(FeeSchedule {
+        tiers,
+        minimum: int_field(value, "minimum")?,
+    })
+}
+
+// The same shape serde gives a `Ledger`: accounts keyed by id as a string.
+fn ledger_to_json(ledger: &Ledger) -> Value {
+    let accounts = ledger
+        .sorted_accounts()
+        .into_iter()
+        .map(|(id, account)| {
+            (
+                id.to_string(),
+                Value::Object(vec![
+                    ("cash".to_string(), account.cash.into()),
+                    ("position".to_string(), account.position.into()),
+                    ("average_price".to_string(), account.average_price.into()),
+                    ("realized_pnl".to_string(), account.realized_pnl.into()),
+                    ("fees".to_string(), account.fees.into()),
+                ]),
+            )
+        })
+        .collect();
+    Value::Object(vec![
+        ("accounts".to_string(), Value::Object(accounts)),
+        (
+            "enforce_buying_power".to_string(),
+            ledger.enforces_buying_power().into(),
+        ),
+    ])
+}
+
+fn ledger_from_json(value: &Value) -> Result<Ledger, SnapshotError> {
+    let mut ledger = Ledger::new();
+    ledger.set_enforce_buying_power(
+        value
+            .get("enforce_buying_power")
+            .and_then(Value::as_bool)
+            .ok_or(SnapshotError::Invalid("ledger.enforce_buying_power"))?,
+    );
+    let Some(Value::Object(accounts)) = value.get("accounts") else {
+        return Err(SnapshotError::Invalid("ledger.accounts"));
+    };
+    for (id, account) in accounts.iter() {
+        let float = |key: &'static str| {
+            account
+                .get(key)
+                .and_then(Value::as_f64)
+                .ok_or(SnapshotError::Invalid(key))
+        };
+        ledger.set_account(
+            id.parse()
+                .map_err(|_| SnapshotError::Invalid("ledger.accounts"))?,
+            Account {
+                cash: int_field(account, "cash")?,
+                position: int_field(account, "position")?,
+                average_price: float("average_price")?,
+                realized_pnl: float("realized_pnl")?,
+                fees: int_field(account, "fees")?,
+            },
+        );
+    }
+    Ok(ledger)
+}
+
+fn orders_field(value: &Value, key: &'static str) -> Result<Vec<Order>, SnapshotError> {
+    value
+        .get(key)
+        .and_then(Value::as_array)
+        .ok_or(SnapshotError::Invalid(key))?
+        .iter()
+        .map(order_from_json)
+        .collect()
+}
+
+fn pairs_to_json(pairs: &[(i64, i64)]) -> Value {
+    Value::Array(
+        pairs
+            .iter()
+            .map(|(a, b)| Value::Array(vec![(*a).into(), (*b).into()]))
+            .collect(),
+    )
+}
+
+// A list of two-integer arrays, empty if absent.
+fn pairs_field(value: &Value, key: &'static str) -> Result<Vec<(i64, i64)>, SnapshotError> {
+    let Some(pairs) = value.get(key) else {
+        return Ok(Vec::new());
+    };
+    pairs
+        .as_array()
+        .ok_or(SnapshotError::Invalid(key))?
+        .iter()
+        .map(|pair| match pair.as_array() {
+            Some([a, b]) => a
+                .as_i64()
+                .zip(b.as_i64())
+                .ok_or(SnapshotError::Invalid(key)),
+            _ => Err(SnapshotError::Invalid(key)),
+        })
+        .collect()
+}
+
+fn int_field(value: &Value, key: &'static str) -> Result<i64, SnapshotError> {
+    value
+        .get(key)
+        .and_then(Value::as_i64)
+        .ok_or(SnapshotError::Invalid(key))
+}
+
+fn optional_int_field(value: &Value, key: &'static str) -> Result<Option<i64>, SnapshotError> {
+    match value.get(key) {
+        None => Ok(None),
+        Some(v) if v.is_null() => Ok(None),
+        Some(v) => v.as_i64().map(Some).ok_or(SnapshotError::Invalid(key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BookSnapshot, SnapshotError};
+    use crate::orderlib::{
+        BandPolicy, BandReference, FeeSchedule, Ledger, Order, OrderBook, OrderSide::Buy,
+        OrderSide::Sell, OrderType::Aon, OrderType::Limit, PriceBand, SessionState,
+    };
+
+    fn sample_book() -> OrderBook {
+        let mut order_book = OrderBook::new();
+        order_book
+            .set_fee_schedule(Some(FeeSchedule::flat(110, -20)))
+            .unwrap();
+        order_book.attach_ledger(Ledger::new()).unwrap();
+        order_book.deposit(3, 1_000).unwrap();
+        order_book.add(Order::new(Buy, 20, 100, Limit));
+        order_book.add(Order::new(Buy, 15, 100, Limit));
+        order_book.add(Order::new(Buy, 20, 99, Aon));
+        order_book.add(Order {
+            account: 9,
+            session_id: 4,
+            ..Order::new(Sell, 10, 102, Limit)
+        });
+        order_book.add(Order::new(Sell, 5, 101, Limit));
+        order_book.add(Order {
+            account: 3,
+            ..Order::new(Buy, 5, 101, Limit)
+        });
+        order_book.set_market_protection(Some(3)).unwrap();
+        order_book
+            .set_price_band(Some(PriceBand {
+                reference: BandReference::Static(100),
+                width: 10,
+                policy: BandPolicy::Rest,
+            }))
+            .unwrap();
+        order_book.set_cancel_on_disconnect(Some(500)).unwrap();
+        order_book.disconnect(4, 1_000).unwrap();
+        order_book.transition(SessionState::Halted).unwrap();
+        order_book
+    }
+
+    #[test]
+    fn test_restore_matches_original() {
+        let original = sample_book();
+        let snapshot = original.snapshot();
+        assert_eq!(snapshot.bids[0].price, 100);
+        assert_eq!(snapshot.bids.len(), 3);
+        assert_eq!(snapshot.offers.len(), 1);
+        assert_eq!(snapshot.last_trade, Some(101));
+        assert_eq!(snapshot.traded, vec![(0, 505), (3, 505)]);
+        assert_eq!(snapshot.fill_count, 1);
+        let mut restored = OrderBook::restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+        assert_eq!(restored.traded_notional(3), 505);
+        assert_eq!(restored.fee_schedule(), original.fee_schedule());
+        assert_eq!(restored.ledger().unwrap().account(3).average_price, 101.0);
+        assert_eq!(restored.buying_power(3), original.buying_power(3));
+        assert_eq!(snapshot.disconnected, vec![(4, 1_500)]);
+        assert_eq!(restored.cancel_on_disconnect(), Some(500));
+        assert_eq!(restored.best_bid().unwrap().price, 100);
+        assert_eq!(restored.session(), SessionState::Halted);
+        let cancelled = restored.expire_disconnects(1_500);
+        assert_eq!((cancelled.len(), cancelled[0].session_id), (1, 4));
+    }
+
+    #[test]
+    fn test_restore_rejects_inconsistent_books() {
+        let snapshot = sample_book().snapshot();
+        let mut wrong_side = snapshot.clone();
+        wrong_side.offers[0].order_side = Buy;
+        assert_eq!(
+            OrderBook::restore(&wrong_side).unwrap_err(),
+            SnapshotError::Invalid("offers")
+        );
+        let mut duplicate = snapshot.clone();
+        duplicate.bids[1].order_number = duplicate.offers[0].order_number;
+        assert_eq!(
+            OrderBook::restore(&duplicate).unwrap_err(),
+            SnapshotError::Invalid("order_number")
+        );
+        let mut twice = snapshot.clone();
+        twice.traded.push((3, 1));
+        assert_eq!(
+            OrderBook::restore(&twice).unwrap_err(),
+            SnapshotError::Invalid("traded")
+        );
+        let mut off = snapshot.clone();
+        off.cancel_on_disconnect = None;
+        assert_eq!(
+            OrderBook::restore(&off).unwrap_err(),
+            SnapshotError::Invalid("disconnected")
+        );
+        let mut stale_counter = snapshot.clone();
+        stale_counter.counter = snapshot.bids[0].order_number;
+        assert_eq!(
+            OrderBook::restore(&stale_counter).unwrap_err(),
+            SnapshotError::Invalid("counter")
+        );
+        for size in [0, -5] {
+            let mut empty = snapshot.clone();
+            empty.offers[0].size = size;
+            assert_eq!(
+                OrderBook::restore(&empty).unwrap_err(),
+                SnapshotError::Invalid("size")
+            );
+        }
+        let mut overflowing = snapshot.clone();
+        overflowing.offers[0].size = 2;
+        overflowing.offers[0].price = i64::MAX;
+        let mut unnegatable = snapshot.clone();
+        unnegatable.bids[0].size = 1;
+        unnegatable.bids[0].price = i64::MIN;
+        for huge in [overflowing, unnegatable] {
+            assert_eq!(
+                OrderBook::restore(&huge).unwrap_err(),
+                SnapshotError::Invalid("price")
+            );
+        }
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        let snapshot = sample_book().snapshot();
+        let bytes = snapshot.to_bytes();
+        assert_eq!(BookSnapshot::from_bytes(&bytes).unwrap(), snapshot);
+        let restored = BookSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            (restored.offers[0].account, restored.offers[0].session_id),
+            (9, 4)
+        );
+        assert_eq!(
+            BookSnapshot::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(SnapshotError::Truncated)
+        );
+        assert_eq!(
+            BookSnapshot::from_bytes(b"nope"),
+            Err(SnapshotError::BadMagic)
+        );
+    }
+
+    #[test]
+    fn test_reads_version_1() {
+        let mut book = OrderBook::new();
+        book.add(Order::new(Sell, 10, 102, Limit));
+        let snapshot = book.snapshot();
+        // Version 1 orders end at the order type, without the fields added since,
+        // and the book has no fee state or ledger after them: here an absent
+        // schedule (1 byte), no traded accounts (4), the fill count (8), an
+        // absent ledger (1), cancel-on-disconnect off (1) and no pending
+        // disconnects (4).
+        let mut bytes = snapshot.to_bytes();
+        bytes[4..6].copy_from_slice(&1u16.to_le_bytes());
+        bytes.truncate(bytes.len() - 19 - 24);
+        let restored = BookSnapshot::from_bytes(&bytes).unwrap();
+        assert_eq!(restored, snapshot);
+        assert_eq!(restored.offers[0].size, 10);
+        let text = snapshot
+            .to_json()
+            .replace("\"version\":7", "\"version\":1")
+            .replace(
+                "\"fees\":null,\"traded\":[],\"fill_count\":0,\"ledger\":null,\
+                 \"cancel_on_disconnect\":null,\"disconnected\":[],",
+                "",
+            )
+            .replace(",\"notional\":0,\"account\":0,\"session_id\":0", "");
+        assert_eq!(
+            BookSnapshot::from_json(&text).unwrap().offers[0].notional,
+            0
+        );
+        bytes[4..6].copy_from_slice(&8u16.to_le_bytes());
+        assert_eq!(
+            BookSnapshot::from_bytes(&bytes),
+            Err(SnapshotError::UnsupportedVersion(8))
+        );
+    }
+
+    #[test]
+    fn test_json_round_trip() {
+        let snapshot = sample_book().snapshot();
+        let text = snapshot.to_json();
+        assert!(text.contains("\"reference\":{\"Static\":100}"));
+        assert!(text.contains("\"traded\":[[0,505],[3,505]]"));
+        assert_eq!(BookSnapshot::from_json(&text).unwrap(), snapshot);
+        let restored = BookSnapshot::from_json(&text).unwrap();
+        assert_eq!(
+            (restored.offers[0].account, restored.offers[0].session_id),
+            (9, 4)
+        );
+        assert_eq!(
+            BookSnapshot::from_json(&OrderBook::new().snapshot().to_json()).unwrap(),
+            OrderBook::new().snapshot()
+        );
+        assert!(matches!(
+            BookSnapshot::from_json("{\"version\":1,"),
+            Err(SnapshotError::Json(_))
+        ));
+        // Deep nesting is refused rather than recursed into.
+        assert_eq!(
+            BookSnapshot::from_json(&"[".repeat(100_000)),
+            Err(SnapshotError::Json(128))
+        );
+    }
+
+    #[test]
+    fn test_restored_book_keeps_time_priority() {
+        let mut restored = OrderBook::restore(&sample_book().snapshot()).unwrap();
+        restored.transition(SessionState::Continuous).unwrap();
+        let report = restored.try_add(Order::new(Sell, 20, 100, Limit)).unwrap();
+        let mut original = sample_book();
+        original.transition(SessionState::Continuous).unwrap();
+        let expected = original.try_add(Order::new(Sell, 20, 100, Limit)).unwrap();
+        assert_eq!(report.order_number, expected.order_number);
+        assert_eq!(report.fills.len(), expected.fills.len());
+        for (a, b) in report.fills.iter().zip(expected.fills.iter()) {
+            assert_eq!((a.size, a.price), (b.size, b.price));
+        }
+        assert_eq!(restored.snapshot(), original.snapshot());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde_reads_snapshot_json() {
+        let snapshot = sample_book().snapshot();
+        let parsed: BookSnapshot = serde_json::from_str(&snapshot.to_json()).unwrap();
+        assert_eq!(parsed, snapshot);
+        let mut value = serde_json::to_value(&snapshot).unwrap();
+        value["version"] = 7.into();
+        let text = serde_json::to_string(&value).unwrap();
+        assert_eq!(BookSnapshot::from_json(&text).unwrap(), snapshot);
+    }
+}
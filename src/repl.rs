@@ -0,0 +1,290 @@
+//! A line-oriented command interpreter for driving an `OrderBook` by hand.
+//!
+//! ```text
+//! buy 20 @ 100 limit     sell 31 market     cancel 1231
+//! buy $5000              book [levels]      vwap sell 30
+//! size buy 102.5         impact buy 500     session continuous
+//! help
+//! ```
+use crate::orderlib::{
+    Fill, ImpactReport, LimitReport, Order, OrderBook, OrderSide, OrderType, SessionState,
+};
+use std::fmt::Write;
+
+pub const HELP: &str = "\
+commands:
+  buy|sell SIZE [@ PRICE] [limit|market|ioc|fok|aon]   submit an order (default limit)
+  buy|sell $AMOUNT [market]                            market order spending up to AMOUNT
+  cancel ORDER_NUMBER                                  remove a resting order
+  book [LEVELS]                                        show depth (default 5 levels)
+  vwap buy|sell SIZE                                   average price to fill SIZE (limit_at_size)
+  impact buy|sell SIZE                                 slippage and depth check for a market order of SIZE
+  size buy|sell PRICE                                  size fillable at average PRICE (size_at_limit)
+  session preopen|auction|continuous|halted|closed     change the trading session
+  help                                                 show this text
+  quit                                                 leave";
+
+/// Runs one command against the book and returns the text to show.  Blank lines
+/// produce an empty string; malformed commands return a usage message as `Err`.
+pub fn execute(book: &mut OrderBook, line: &str) -> Result<String, String> {
+    let words: Vec<String> = line
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect();
+    let words: Vec<&str> = words.iter().map(String::as_str).collect();
+    match words.as_slice() {
+        [] => Ok(String::new()),
+        ["help"] => Ok(HELP.to_string()),
+        [side @ ("buy" | "sell"), rest @ ..] => submit(book, side_of(side), rest),
+        ["cancel", number] => {
+            let number: i64 = number
+                .parse()
+                .map_err(|_| "usage: cancel ORDER_NUMBER".to_string())?;
+            match book.cancel(number) {
+                Ok(Some(order)) => Ok(format!(
+                    "cancelled #{}: {:?} {} @ {}",
+                    number, order.order_side, order.size, order.price
+                )),
+                Ok(None) => Err(format!("no resting order #{}", number)),
+                Err(reason) => Err(reason.to_string()),
+            }
+        }
+        ["book"] => Ok(show_book(book, 5)),
+        ["book", levels] => {
+            let levels = levels
+                .parse()
+                .map_err(|_| "usage: book [LEVELS]".to_string())?;
+            Ok(show_book(book, levels))
+        }
+        ["vwap", side @ ("buy" | "sell"), size] => {
+            let size: i64 = size
+                .parse()
+                .map_err(|_| "usage: vwap buy|sell SIZE".to_string())?;
+            Ok(show_report(book.limit_at_size(side_of(side), size)))
+        }
+        ["impact", side @ ("buy" | "sell"), size] => {
+            let size: i64 = size
+                .parse()
+                .map_err(|_| "usage: impact buy|sell SIZE".to_string())?;
+            Ok(show_impact(book.estimate_impact(side_of(side), size)))
+        }
+        ["size", side @ ("buy" | "sell"), price] => {
+            let price: f64 = price
+                .parse()
+                .map_err(|_| "usage: size buy|sell PRICE".to_string())?;
+            Ok(show_report(book.size_at_limit(side_of(side), price)))
+        }
+        ["session", state] => {
+            let to = match *state {
+                "preopen" => SessionState::PreOpen,
+                "auction" => SessionState::OpeningAuction,
+                "continuous" => SessionState::Continuous,
+                "halted" => SessionState::Halted,
+                "closed" => SessionState::Closed,
+                _ => return Err("usage: session preopen|auction|continuous|halted|closed".into()),
+            };
+            let fills = book.transition(to).map_err(|error| error.to_string())?;
+            let mut out = format!("session is now {:?}", to);
+            show_fills(&mut out, &fills);
+            Ok(out)
+        }
+        _ => Err(format!("unknown command: {}  (try `help`)", line.trim())),
+    }
+}
+
+fn side_of(word: &str) -> OrderSide {
+    if word == "buy" {
+        OrderSide::Buy
+    } else {
+        OrderSide::Sell
+    }
+}
+
+fn submit(book: &mut OrderBook, side: OrderSide, words: &[&str]) -> Result<String, String> {
+    let usage = || "usage: buy|sell SIZE [@ PRICE] [limit|market|ioc|fok|aon]".to_string();
+    let (size, rest) = words.split_first().ok_or_else(usage)?;
+    if let Some(amount) = size.strip_prefix('$') {
+        let amount: i64 = amount
+            .parse()
+            .ok()
+            .filter(|a| *a > 0)
+            .ok_or("usage: buy|sell $AMOUNT [market]")?;
+        if !matches!(rest, [] | ["market"]) {
+            return Err("orders by amount are always market orders".into());
+        }
+        return place(book, Order::market_notional(side, amount));
+    }
+    let size: i64 = size.parse().ok().filter(|s| *s > 0).ok_or_else(usage)?;
+    let (price, rest) = match rest {
+        ["@", price, rest @ ..] => (Some(price.parse::<i64>().map_err(|_| usage())?), rest),
+        _ => (None, rest),
+    };
+    let order_type = match rest {
+        [] | ["limit"] => OrderType::Limit,
+        ["market"] => OrderType::Market,
+        ["ioc"] => OrderType::Ioc,
+        ["fok"] => OrderType::Fok,
+        ["aon"] => OrderType::Aon,
+        _ => return Err(usage()),
+    };
+    let price = match (price, order_type) {
+        (Some(price), _) => price,
+        (None, OrderType::Market) => 0,
+        (None, _) => return Err("a price is required unless the order is market".into()),
+    };
+    place(book, Order::new(side, size, price, order_type))
+}
+
+fn place(book: &mut OrderBook, order: Order) -> Result<String, String> {
+    let report = book
+        .try_add(order)
+        .map_err(|reason| format!("rejected: {}", reason))?;
+    let mut out = format!(
+        "order #{}: {} filled, {} resting, {} cancelled",
+        report.order_number,
+        report.fills.iter().map(|fill| fill.size).sum::<i64>(),
+        report.remaining,
+        report.cancelled
+    );
+    show_fills(&mut out, &report.fills);
+    Ok(out)
+}
+
+fn show_fills(out: &mut String, fills: &[Fill]) {
+    for fill in fills.iter() {
+        let _ = write!(
+            out,
+            "\n  fill {} @ {} against #{}",
+            fill.size, fill.price, fill.passive_number
+        );
+    }
+}
+
+fn show_report(report: Option<LimitReport>) -> String {
+    match report {
+        Some(report) if report.size > 0 => format!("{} @ avg {}", report.size, report.price),
+        _ => "no liquidity".to_string(),
+    }
+}
+
+fn show_impact(report: Option<ImpactReport>) -> String {
+    let Some(report) = report else {
+        return "no liquidity".to_string();
+    };
+    let mut out = format!(
+        "{} @ avg {}, worst {} over {} levels, slippage {} vs best",
+        report.filled, report.vwap, report.worst_price, report.levels, report.slippage_vs_best
+    );
+    if let Some(slippage) = report.slippage_vs_mid {
+        let _ = write!(out, ", {} vs mid", slippage);
+    }
+    if !report.sufficient {
+        let _ = write!(out, " (book too thin for {})", report.requested);
+    }
+    out
+}
+
+fn show_book(book: &OrderBook, levels: usize) -> String {
+    let mut out = format!("session {:?}", book.session());
+    let offers = book.depth(OrderSide::Sell, levels);
+    for level in offers.iter().rev() {
+        let _ = write!(
+            out,
+            "\n{:>12} | {:<8} {:>8}  ({} orders)",
+            "", level.price, level.size, level.orders
+        );
+    }
+    let _ = write!(out, "\n{:->12}-+-{:-<19}", "", "");
+    for level in book.depth(OrderSide::Buy, levels).iter() {
+        let _ = write!(
+            out,
+            "\n{:>12} | {:<8}           ({} orders)",
+            level.size, level.price, level.orders
+        );
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+    use crate::orderlib::OrderBook;
+
+    #[test]
+    fn test_trading_session() {
+        let mut order_book = OrderBook::new();
+        assert_eq!(
+            execute(&mut order_book, "buy 20 @ 100 limit").unwrap(),
+            "order #1230: 0 filled, 20 resting, 0 cancelled"
+        );
+        execute(&mut order_book, "BUY 20 @ 101").unwrap();
+        assert_eq!(
+            execute(&mut order_book, "sell 31 market").unwrap(),
+            "order #1232: 31 filled, 0 resting, 0 cancelled\n  \
+             fill 20 @ 101 against #1231\n  fill 11 @ 100 against #1230"
+        );
+        assert_eq!(
+            execute(&mut order_book, "vwap sell 30").unwrap(),
+            "9 @ avg 100"
+        );
+        assert_eq!(
+            execute(&mut order_book, "size sell 100").unwrap(),
+            "9 @ avg 100"
+        );
+        assert_eq!(
+            execute(&mut order_book, "impact sell 30").unwrap(),
+            "9 @ avg 100, worst 100 over 1 levels, slippage 0 vs best (book too thin for 30)"
+        );
+        assert!(execute(&mut order_book, "book")
+            .unwrap()
+            .contains("           9 | 100"));
+        assert_eq!(
+            execute(&mut order_book, "cancel 1230").unwrap(),
+            "cancelled #1230: Buy 9 @ 100"
+        );
+        assert!(execute(&mut order_book, "cancel 1230").is_err());
+        assert_eq!(
+            execute(&mut order_book, "vwap buy 1").unwrap(),
+            "no liquidity"
+        );
+    }
+
+    #[test]
+    fn test_bad_input() {
+        let mut order_book = OrderBook::new();
+        assert_eq!(execute(&mut order_book, "   ").unwrap(), "");
+        assert!(execute(&mut order_book, "buy ten @ 100").is_err());
+        assert!(execute(&mut order_book, "sell 5 limit").is_err());
+        assert!(execute(&mut order_book, "frobnicate").is_err());
+        execute(&mut order_book, "session closed").unwrap();
+        assert_eq!(
+            execute(&mut order_book, "buy 5 @ 100").unwrap_err(),
+            "rejected: Limit orders are not accepted during Closed"
+        );
+    }
+
+    #[test]
+    fn test_order_by_amount() {
+        let mut order_book = OrderBook::new();
+        execute(&mut order_book, "sell 10 @ 100").unwrap();
+        execute(&mut order_book, "sell 10 @ 110").unwrap();
+        assert_eq!(
+            execute(&mut order_book, "buy $1500").unwrap(),
+            "order #1232: 14 filled, 0 resting, 0 cancelled\n  \
+             fill 10 @ 100 against #1230\n  fill 4 @ 110 against #1231"
+        );
+        assert!(execute(&mut order_book, "buy $100 ioc").is_err());
+        assert!(execute(&mut order_book, "buy $-5").is_err());
+    }
+
+    #[test]
+    fn test_market_remainder_is_cancelled() {
+        let mut order_book = OrderBook::new();
+        execute(&mut order_book, "sell 5 @ 100").unwrap();
+        assert_eq!(
+            execute(&mut order_book, "buy 8 market").unwrap(),
+            "order #1231: 5 filled, 0 resting, 3 cancelled\n  fill 5 @ 100 against #1230"
+        );
+        assert_eq!(order_book.len_bids(), 0);
+    }
+}
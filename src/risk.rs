@@ -0,0 +1,552 @@
+//! Pre-trade risk checks in front of an `OrderBook`.
+//!
+//! `RiskGate` owns the book and checks each order against its account's limits
+//! before it reaches `try_add`.  Every refusal comes back as a `RiskReject` saying
+//! which limit was hit.  The gate keeps each account's position and resting orders
+//! up to date from the fills and cancels it sees, so orders, and the disconnects
+//! that cancel them, must go through the gate rather than straight to the book.
+use crate::orderlib::{
+    get_epoch_ms, CancelFilter, ExecutionReport, Fill, Order, OrderBook, OrderSide, OrderType,
+    RejectReason, SessionState, TransitionError,
+};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+/// Limits for one account.  `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RiskLimits {
+    pub max_order_size: Option<i64>,
+    /// Largest size times price for a single order.
+    pub max_notional: Option<i64>,
+    pub max_open_orders: Option<usize>,
+    /// Largest absolute position, counting resting orders on the same side as the
+    /// new order as if they had filled.
+    pub max_position: Option<i64>,
+    /// Furthest a priced order may be from the mid, in ticks.  Not checked while
+    /// the book is one-sided.
+    pub max_price_distance: Option<i64>,
+    pub rate: Option<RateLimit>,
+}
+
+/// At most `orders` orders in any `window` milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub orders: usize,
+    pub window: i64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RiskReject {
+    OrderSize {
+        size: i64,
+        limit: i64,
+    },
+    Notional {
+        notional: i64,
+        limit: i64,
+    },
+    OpenOrders {
+        open: usize,
+        limit: usize,
+    },
+    Position {
+        projected: i64,
+        limit: i64,
+    },
+    PriceDistance {
+        price: i64,
+        mid: f64,
+        limit: i64,
+    },
+    RateLimit(RateLimit),
+    /// The checks passed but the book refused the order.
+    Book(RejectReason),
+}
+
+impl fmt::Display for RiskReject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RiskReject::OrderSize { size, limit } => {
+                write!(f, "order size {} exceeds limit {}", size, limit)
+            }
+            RiskReject::Notional { notional, limit } => {
+                write!(f, "order notional {} exceeds limit {}", notional, limit)
+            }
+            RiskReject::OpenOrders { open, limit } => {
+                write!(f, "{} open orders already, limit {}", open, limit)
+            }
+            RiskReject::Position { projected, limit } => {
+                write!(f, "position would reach {}, limit {}", projected, limit)
+            }
+            RiskReject::PriceDistance { price, mid, limit } => {
+                write!(f, "price {} is more than {} from mid {}", price, limit, mid)
+            }
+            RiskReject::RateLimit(rate) => {
+                write!(f, "more than {} orders in {} ms", rate.orders, rate.window)
+            }
+            RiskReject::Book(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for RiskReject {}
+
+#[derive(Clone, Debug, Default)]
+struct AccountState {
+    position: i64,
+    // Resting order numbers and their unfilled size.
+    open: HashMap<i64, (OrderSide, i64)>,
+    recent: VecDeque<i64>,
+}
+
+impl AccountState {
+    fn resting(&self, side: OrderSide) -> i64 {
+        self.open
+            .values()
+            .filter(|(s, _)| *s == side)
+            .fold(0i64, |sum, (_, size)| sum.saturating_add(*size))
+    }
+}
+
+#[derive(Debug)]
+pub struct RiskGate {
+    book: OrderBook,
+    defaults: RiskLimits,
+    limits: HashMap<i64, RiskLimits>,
+    accounts: HashMap<i64, AccountState>,
+    // Account of every resting order that went through the gate.
+    owners: HashMap<i64, i64>,
+}
+
+impl RiskGate {
+    /// `defaults` apply to any account without limits of its own.
+    pub fn new(book: OrderBook, defaults: RiskLimits) -> RiskGate {
+        RiskGate {
+            book,
+            defaults,
+            limits: HashMap::new(),
+            accounts: HashMap::new(),
+            owners: HashMap::new(),
+        }
+    }
+
+    pub fn book(&self) -> &OrderBook {
+        &self.book
+    }
+
+    pub fn set_limits(&mut self, account: i64, limits: RiskLimits) {
+        self.limits.insert(account, limits);
+    }
+
+    pub fn limits(&self, account: i64) -> RiskLimits {
+        self.limits.get(&account).copied().unwrap_or(self.defaults)
+    }
+
+    /// Net filled size: positive long, negative short.
+    pub fn position(&self, account: i64) -> i64 {
+        self.accounts
+            .get(&account)
+            .map_or(0, |state| state.position)
+    }
+
+    pub fn open_orders(&self, account: i64) -> usize {
+        self.accounts
+            .get(&account)
+            .map_or(0, |state| state.open.len())
+    }
+
+    /// Checks `order` against `account`'s limits and, if it passes, adds it to the
+    /// book as that account's order.  Every order that gets past the rate limit
+    /// counts towards it, even if a later check or the book refuses it.
+    pub fn add(&mut self, account: i64, order: Order) -> Result<ExecutionReport, RiskReject> {
+        self.add_at(account, order, get_epoch_ms())
+    }
+
+    fn add_at(
+        &mut self,
+        account: i64,
+        order: Order,
+        now: i64,
+    ) -> Result<ExecutionReport, RiskReject> {
+        let order = Order { account, ..order };
+        self.check(account, &order, now)?;
+        let report = self.book.try_add(order).map_err(RiskReject::Book)?;
+        // Own the order while settling so its fills count against the account.
+        self.owners.insert(report.order_number, account);
+        self.settle(&report.fills);
+        let state = self.accounts.entry(account).or_default();
+        if report.remaining > 0 {
+            state
+                .open
+                .insert(report.order_number, (order.order_side, report.remaining));
+        } else {
+            self.owners.remove(&report.order_number);
+        }
+        Ok(report)
+    }
+
+    /// Cancels one of `account`'s resting orders, returning it if it was found.
+    pub fn cancel(&mut self, account: i64, order_number: i64) -> Result<Option<Order>, RiskReject> {
+        if self.owners.get(&order_number) != Some(&account) {
+            return Ok(None);
+        }
+        let Some(order) = self.book.cancel(order_number).map_err(RiskReject::Book)? else {
+            return Ok(None);
+        };
+        self.forget(order_number);
+        Ok(Some(order))
+    }
+
+    /// Cancels `account`'s resting orders selected by `filter`; any account in the
+    /// filter is ignored.
+    pub fn cancel_all(&mut self, account: i64, filter: CancelFilter) -> Vec<Order> {
+        let cancelled = self.book.cancel_all(CancelFilter {
+            account: Some(account),
+            ..filter
+        });
+        for order in cancelled.iter() {
+            self.forget(order.order_number);
+        }
+        cancelled
+    }
+
+    /// Notes that a session dropped, for the book's cancel-on-disconnect, and
+    /// forgets whatever it cancels.
+    pub fn disconnect(&mut self, session_id: i64, now: i64) -> Result<Vec<Order>, RiskReject> {
+        let cancelled = self
+            .book
+            .disconnect(session_id, now)
+            .map_err(RiskReject::Book)?;
+        for order in cancelled.iter() {
+            self.forget(order.order_number);
+        }
+        Ok(cancelled)
+    }
+
+    pub fn reconnect(&mut self, session_id: i64) -> Result<bool, RiskReject> {
+        self.book.reconnect(session_id).map_err(RiskReject::Book)
+    }
+
+    /// Cancels the orders of sessions whose grace period has run out, as
+    /// `OrderBook::expire_disconnects` does, and forgets them.
+    pub fn expire_disconnects(&mut self, now: i64) -> Vec<Order> {
+        let cancelled = self.book.expire_disconnects(now);
+        for order in cancelled.iter() {
+            self.forget(order.order_number);
+        }
+        cancelled
+    }
+
+    /// Changes the session, applying any fills from the opening uncross.
+    pub fn transition(&mut self, to: SessionState) -> Result<Vec<Fill>, TransitionError> {
+        let fills = self.book.transition(to)?;
+        self.settle(&fills);
+        Ok(fills)
+    }
+
+    fn check(&mut self, account: i64, order: &Order, now: i64) -> Result<(), RiskReject> {
+        let limits = self.limits(account);
+        let state = self.accounts.entry(account).or_default();
+        if let Some(rate) = limits.rate {
+            while state
+                .recent
+                .front()
+                .is_some_and(|t| *t <= now - rate.window)
+            {
+                state.recent.pop_front();
+            }
+            if state.recent.len() >= rate.orders {
+                return Err(RiskReject::RateLimit(rate));
+            }
+            state.recent.push_back(now);
+        }
+        let state = &self.accounts[&account];
+        // Market orders have no price of their own, so size them from the book.
+        let (size, notional) = match order.order_type {
+            OrderType::Market if order.notional > 0 => (
+                self.book
+                    .size_for_notional(order.order_side, order.notional)
+                    .map_or(0, |report| report.size),
+                order.notional,
+            ),
+            OrderType::Market => (
+                order.size,
+                self.book
+                    .notional_for_size(order.order_side, order.size)
+                    .map_or(0, |report| report.notional),
+            ),
+            // Saturates, so an absurd order fails the notional limit, if any,
+            // rather than overflowing.
+            _ => (order.size, order.size.saturating_mul(order.price)),
+        };
+        if let Some(limit) = limits.max_order_size.filter(|limit| size > *limit) {
+            return Err(RiskReject::OrderSize { size, limit });
+        }
+        if let Some(limit) = limits.max_notional.filter(|limit| notional > *limit) {
+            return Err(RiskReject::Notional { notional, limit });
+        }
+        if let Some(limit) = limits.max_open_orders {
+            if state.open.len() >= limit {
+                return Err(RiskReject::OpenOrders {
+                    open: state.open.len(),
+                    limit,
+                });
+            }
+        }
+        if let Some(limit) = limits.max_position {
+            let exposure = state.resting(order.order_side).saturating_add(size);
+            let projected = match order.order_side {
+                OrderSide::Buy => state.position.saturating_add(exposure),
+                OrderSide::Sell => state.position.saturating_sub(exposure),
+            };
+            if projected.saturating_abs() > limit {
+                return Err(RiskReject::Position { projected, limit });
+            }
+        }
+        if let (Some(limit), Some(mid)) = (limits.max_price_distance, self.book.mid()) {
+            if order.order_type != OrderType::Market
+                && (order.price as f64 - mid).abs() > limit as f64
+            {
+                return Err(RiskReject::PriceDistance {
+                    price: order.price,
+                    mid,
+                    limit,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Updates positions and resting sizes for every fill involving a gated order.
+    fn settle(&mut self, fills: &[Fill]) {
+        for fill in fills.iter() {
+            for (number, side) in [
+                (fill.aggressor_number, fill.direction),
+                (fill.passive_number, opposite(fill.direction)),
+            ] {
+                let Some(&account) = self.owners.get(&number) else {
+                    continue;
+                };
+                let state = self.accounts.entry(account).or_default();
+                state.position += match side {
+                    OrderSide::Buy => fill.size,
+                    OrderSide::Sell => -fill.size,
+                };
+                if let Some((_, remaining)) = state.open.get_mut(&number) {
+                    *remaining -= fill.size;
+                    if *remaining <= 0 {
+                        self.forget(number);
+                    }
+                }
+            }
+        }
+    }
+
+    fn forget(&mut self, order_number: i64) {
+        if let Some(account) = self.owners.remove(&order_number) {
+            if let Some(state) = self.accounts.get_mut(&account) {
+                state.open.remove(&order_number);
+            }
+        }
+    }
+}
+
+fn opposite(side: OrderSide) -> OrderSide {
+    match side {
+        OrderSide::Buy => OrderSide::Sell,
+        OrderSide::Sell => OrderSide::Buy,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateLimit, RiskGate, RiskLimits, RiskReject};
+    use crate::orderlib::{
+        CancelFilter, Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Limit,
+        OrderType::Market, RejectReason, SessionState,
+    };
+
+    #[test]
+    fn test_order_limits() {
+        let limits = RiskLimits {
+            max_order_size: Some(100),
+            max_notional: Some(5000),
+            max_price_distance: Some(10),
+            ..RiskLimits::default()
+        };
+        let mut gate = RiskGate::new(OrderBook::new(), limits);
+        assert_eq!(
+            gate.add(1, Order::new(Buy, 101, 10, Limit)).unwrap_err(),
+            RiskReject::OrderSize {
+                size: 101,
+                limit: 100
+            }
+        );
+        assert_eq!(
+            gate.add(1, Order::new(Buy, 51, 100, Limit)).unwrap_err(),
+            RiskReject::Notional {
+                notional: 5100,
+                limit: 5000
+            }
+        );
+        // One-sided book: no mid, so any price goes.
+        gate.add(1, Order::new(Buy, 10, 100, Limit)).unwrap();
+        gate.add(2, Order::new(Sell, 40, 110, Limit)).unwrap();
+        let err = gate.add(1, Order::new(Buy, 10, 90, Limit)).unwrap_err();
+        assert_eq!(
+            err,
+            RiskReject::PriceDistance {
+                price: 90,
+                mid: 105.0,
+                limit: 10
+            }
+        );
+        assert_eq!(err.to_string(), "price 90 is more than 10 from mid 105");
+        gate.add(1, Order::new(Buy, 10, 95, Limit)).unwrap();
+        // Market orders are priced from the book.
+        gate.set_limits(
+            3,
+            RiskLimits {
+                max_notional: Some(4000),
+                ..limits
+            },
+        );
+        assert_eq!(
+            gate.add(3, Order::new(Buy, 40, 0, Market)).unwrap_err(),
+            RiskReject::Notional {
+                notional: 4400,
+                limit: 4000
+            }
+        );
+        assert_eq!(
+            gate.add(3, Order::market_notional(Buy, 4400)).unwrap_err(),
+            RiskReject::Notional {
+                notional: 4400,
+                limit: 4000
+            }
+        );
+        assert_eq!(
+            gate.add(3, Order::new(Buy, 30, 0, Market))
+                .unwrap()
+                .fills
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_positions_and_open_orders() {
+        let limits = RiskLimits {
+            max_open_orders: Some(2),
+            max_position: Some(15),
+            ..RiskLimits::default()
+        };
+        let mut gate = RiskGate::new(OrderBook::new(), limits);
+        let first = gate.add(1, Order::new(Buy, 5, 100, Limit)).unwrap();
+        gate.add(1, Order::new(Buy, 5, 99, Limit)).unwrap();
+        assert_eq!(
+            gate.add(1, Order::new(Buy, 1, 98, Limit)).unwrap_err(),
+            RiskReject::OpenOrders { open: 2, limit: 2 }
+        );
+        // Another account's orders do not count.
+        gate.add(2, Order::new(Sell, 7, 100, Limit)).unwrap();
+        assert_eq!(gate.position(1), 5);
+        assert_eq!(gate.position(2), -5);
+        assert_eq!(gate.open_orders(1), 1);
+        assert_eq!(gate.open_orders(2), 1);
+        // 5 long plus 5 resting plus 6 new would breach 15.
+        assert_eq!(
+            gate.add(1, Order::new(Buy, 6, 99, Limit)).unwrap_err(),
+            RiskReject::Position {
+                projected: 16,
+                limit: 15
+            }
+        );
+        assert_eq!(gate.cancel(2, first.order_number), Ok(None));
+        assert_eq!(gate.cancel(1, first.order_number), Ok(None));
+        assert_eq!(gate.open_orders(1), 1);
+        gate.add(1, Order::new(Buy, 5, 99, Limit)).unwrap();
+        assert_eq!(gate.book().depth(Buy, 1)[0].size, 10);
+        assert_eq!(gate.cancel_all(1, CancelFilter::default()).len(), 2);
+        assert_eq!(gate.open_orders(1), 0);
+        assert_eq!(gate.open_orders(2), 1);
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let rate = RateLimit {
+            orders: 2,
+            window: 1000,
+        };
+        let limits = RiskLimits {
+            rate: Some(rate),
+            max_order_size: Some(10),
+            ..RiskLimits::default()
+        };
+        let mut gate = RiskGate::new(OrderBook::new(), limits);
+        gate.add_at(1, Order::new(Buy, 1, 100, Limit), 0).unwrap();
+        // Refused orders still use up the allowance.
+        assert!(gate
+            .add_at(1, Order::new(Buy, 11, 100, Limit), 500)
+            .is_err());
+        assert_eq!(
+            gate.add_at(1, Order::new(Buy, 1, 100, Limit), 999)
+                .unwrap_err(),
+            RiskReject::RateLimit(rate)
+        );
+        gate.add_at(2, Order::new(Buy, 1, 100, Limit), 999).unwrap();
+        gate.add_at(1, Order::new(Buy, 1, 100, Limit), 1000)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_overflow_and_disconnects() {
+        let limits = RiskLimits {
+            max_notional: Some(1_000_000),
+            max_open_orders: Some(1),
+            ..RiskLimits::default()
+        };
+        let mut book = OrderBook::new();
+        book.set_cancel_on_disconnect(Some(100)).unwrap();
+        let mut gate = RiskGate::new(book, limits);
+        assert_eq!(
+            gate.add(1, Order::new(Buy, i64::MAX, 2, Limit))
+                .unwrap_err(),
+            RiskReject::Notional {
+                notional: i64::MAX,
+                limit: 1_000_000
+            }
+        );
+        let order = Order {
+            session_id: 7,
+            ..Order::new(Buy, 10, 100, Limit)
+        };
+        gate.add(1, order).unwrap();
+        assert_eq!(gate.disconnect(7, 0).unwrap().len(), 0);
+        assert_eq!(gate.expire_disconnects(100).len(), 1);
+        // The book cancelled the order, so it no longer counts as open.
+        assert_eq!(gate.open_orders(1), 0);
+        gate.add(1, order).unwrap();
+    }
+
+    #[test]
+    fn test_book_rejects_and_auction() {
+        let mut book = OrderBook::new();
+        book.transition(SessionState::Closed).unwrap();
+        book.transition(SessionState::PreOpen).unwrap();
+        let mut gate = RiskGate::new(book, RiskLimits::default());
+        assert_eq!(
+            gate.add(1, Order::new(Buy, 1, 0, Market)).unwrap_err(),
+            RiskReject::Book(RejectReason::Session(SessionState::PreOpen, Market))
+        );
+        gate.add(1, Order::new(Buy, 10, 101, Limit)).unwrap();
+        gate.add(2, Order::new(Sell, 4, 100, Limit)).unwrap();
+        gate.transition(SessionState::OpeningAuction).unwrap();
+        let fills = gate.transition(SessionState::Continuous).unwrap();
+        assert_eq!(fills.iter().map(|fill| fill.size).sum::<i64>(), 4);
+        assert_eq!(gate.position(1), 4);
+        assert_eq!(gate.position(2), -4);
+        assert_eq!(gate.open_orders(1), 1);
+        assert_eq!(gate.open_orders(2), 0);
+    }
+}
@@ -0,0 +1,413 @@
+//! An `OrderBook` owned by a dedicated matching thread, shared between threads
+//! without a lock.
+//!
+//! Commands reach the matching thread over an MPSC channel and are applied one at
+//! a time, in arrival order.  Each command carries its own reply channel, so a
+//! caller can fire off several commands and collect the replies later.  After every
+//! command the matching thread publishes the top of book through a seqlock, which
+//! readers poll without going through the queue or blocking the writer.
+use crate::orderlib::{
+    BookSnapshot, ExecutionReport, Fill, Level, Order, OrderBook, OrderSide, RejectReason,
+    SessionState, TransitionError,
+};
+use std::fmt;
+use std::sync::atomic::{fence, AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ServiceError {
+    /// The matching thread has shut down; the command was not applied.
+    Stopped,
+    Rejected(RejectReason),
+    Transition(TransitionError),
+}
+
+impl fmt::Display for ServiceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ServiceError::Stopped => write!(f, "order book service has stopped"),
+            ServiceError::Rejected(reason) => write!(f, "order rejected: {}", reason),
+            ServiceError::Transition(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for ServiceError {}
+
+/// A consistent view of the best levels.  `seq` goes up by one each time any of
+/// them changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TopOfBook {
+    pub bid: Option<Level>,
+    pub ask: Option<Level>,
+    pub last_trade: Option<i64>,
+    pub seq: u64,
+}
+
+/// The answer to a command, delivered once the matching thread has applied it.
+#[derive(Debug)]
+pub struct Reply<T> {
+    receiver: Receiver<Result<T, ServiceError>>,
+}
+
+impl<T> Reply<T> {
+    /// Blocks until the command has been applied.
+    pub fn wait(self) -> Result<T, ServiceError> {
+        self.receiver.recv().unwrap_or(Err(ServiceError::Stopped))
+    }
+
+    /// Returns `None` if the command has not been applied yet.
+    pub fn try_wait(&self) -> Option<Result<T, ServiceError>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Some(result),
+            Err(mpsc::TryRecvError::Empty) => None,
+            Err(mpsc::TryRecvError::Disconnected) => Some(Err(ServiceError::Stopped)),
+        }
+    }
+}
+
+type Respond<T> = Sender<Result<T, ServiceError>>;
+
+enum Request {
+    Add(Order, Respond<ExecutionReport>),
+    Cancel(i64, Respond<Option<Order>>),
+    Transition(SessionState, Respond<Vec<Fill>>),
+    Snapshot(Respond<BookSnapshot>),
+    Depth(OrderSide, usize, Respond<Vec<Level>>),
+    Shutdown,
+}
+
+/// Runs the matching thread.  Dropping the service stops the thread; use
+/// `shutdown` to get the book back.
+#[derive(Debug)]
+pub struct BookService {
+    handle: ServiceHandle,
+    thread: Option<JoinHandle<OrderBook>>,
+}
+
+impl BookService {
+    pub fn spawn(book: OrderBook) -> BookService {
+        let (sender, receiver) = mpsc::channel();
+        let top = Arc::new(SharedTop::default());
+        top.publish(&book);
+        let writer = Arc::clone(&top);
+        let thread = thread::Builder::new()
+            .name("orderlib-matching".to_string())
+            .spawn(move || run(book, receiver, &writer))
+            .expect("failed to spawn matching thread");
+        BookService {
+            handle: ServiceHandle { sender, top },
+            thread: Some(thread),
+        }
+    }
+
+    /// A cloneable handle for submitting commands from any thread.
+    pub fn handle(&self) -> ServiceHandle {
+        self.handle.clone()
+    }
+
+    /// Stops the matching thread once the commands already queued have been
+    /// applied, and returns the book.  Commands sent afterwards fail with
+    /// `ServiceError::Stopped`.
+    pub fn shutdown(mut self) -> OrderBook {
+        self.stop().expect("matching thread panicked")
+    }
+
+    fn stop(&mut self) -> Option<OrderBook> {
+        let _ = self.handle.sender.send(Request::Shutdown);
+        self.thread.take()?.join().ok()
+    }
+}
+
+impl Drop for BookService {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ServiceHandle {
+    sender: Sender<Request>,
+    top: Arc<SharedTop>,
+}
+
+impl ServiceHandle {
+    pub fn submit(&self, order: Order) -> Reply<ExecutionReport> {
+        self.request(|respond| Request::Add(order, respond))
+    }
+
+    pub fn cancel(&self, order_number: i64) -> Reply<Option<Order>> {
+        self.request(|respond| Request::Cancel(order_number, respond))
+    }
+
+    pub fn transition(&self, to: SessionState) -> Reply<Vec<Fill>> {
+        self.request(|respond| Request::Transition(to, respond))
+    }
+
+    pub fn snapshot(&self) -> Reply<BookSnapshot> {
+        self.request(Request::Snapshot)
+    }
+
+    pub fn depth(&self, side: OrderSide, levels: usize) -> Reply<Vec<Level>> {
+        self.request(|respond| Request::Depth(side, levels, respond))
+    }
+
+    /// The latest published top of book.  Never blocks the matching thread.
+    pub fn top(&self) -> TopOfBook {
+        self.top.read()
+    }
+
+    fn request<T>(&self, make: impl FnOnce(Respond<T>) -> Request) -> Reply<T> {
+        let (respond, receiver) = mpsc::channel();
+        // If the thread is gone the request, and with it `respond`, is dropped and
+        // the reply reports `Stopped`.
+        let _ = self.sender.send(make(respond));
+        Reply { receiver }
+    }
+}
+
+impl fmt::Debug for Request {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Request::Add(order, _) => write!(f, "Add({:?})", order),
+            Request::Cancel(number, _) => write!(f, "Cancel({})", number),
+            Request::Transition(to, _) => write!(f, "Transition({:?})", to),
+            Request::Snapshot(_) => write!(f, "Snapshot"),
+            Request::Depth(side, levels, _) => write!(f, "Depth({:?}, {})", side, levels),
+            Request::Shutdown => write!(f, "Shutdown"),
+        }
+    }
+}
+
+fn run(mut book: OrderBook, receiver: Receiver<Request>, top: &SharedTop) -> OrderBook {
+    for request in receiver.iter() {
+        // A caller that stopped waiting for its reply is not an error.
+        match request {
+            Request::Add(order, respond) => {
+                let result = book.try_add(order).map_err(ServiceError::Rejected);
+                top.publish(&book);
+                let _ = respond.send(result);
+            }
+            Request::Cancel(number, respond) => {
+                let result = book.cancel(number).map_err(ServiceError::Rejected);
+                top.publish(&book);
+                let _ = respond.send(result);
+            }
+            Request::Transition(to, respond) => {
+                let result = book.transition(to).map_err(ServiceError::Transition);
+                top.publish(&book);
+                let _ = respond.send(result);
+            }
+            Request::Snapshot(respond) => {
+                let _ = respond.send(Ok(book.snapshot()));
+            }
+            Request::Depth(side, levels, respond) => {
+                let _ = respond.send(Ok(book.depth(side, levels)));
+            }
+            Request::Shutdown => break,
+        }
+    }
+    book
+}
+
+// Seqlock over the top of book.  The sequence is odd while the single writer is
+// mid-update; readers retry until they see the same even sequence on both sides
+// of their reads.  Empty sides and a missing last trade are stored as `NONE`.
+#[derive(Debug, Default)]
+struct SharedTop {
+    seq: AtomicU64,
+    bid: [AtomicI64; 2],
+    bid_orders: AtomicUsize,
+    ask: [AtomicI64; 2],
+    ask_orders: AtomicUsize,
+    last_trade: AtomicI64,
+}
+
+const NONE: i64 = i64::MIN;
+
+impl SharedTop {
+    fn publish(&self, book: &OrderBook) {
+        let bid = book.depth(OrderSide::Buy, 1).first().copied();
+        let ask = book.depth(OrderSide::Sell, 1).first().copied();
+        let last_trade = book.last_trade();
+        let seq = self.seq.load(Ordering::Relaxed);
+        if seq > 0 {
+            let current = self.load();
+            if (current.0, current.1, current.2) == (bid, ask, last_trade) {
+                return;
+            }
+        }
+        self.seq.store(seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        store_level(&self.bid, &self.bid_orders, bid);
+        store_level(&self.ask, &self.ask_orders, ask);
+        self.last_trade
+            .store(last_trade.unwrap_or(NONE), Ordering::Relaxed);
+        self.seq.store(seq + 2, Ordering::Release);
+    }
+
+    fn read(&self) -> TopOfBook {
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let (bid, ask, last_trade) = self.load();
+            fence(Ordering::Acquire);
+            if self.seq.load(Ordering::Relaxed) == before {
+                return TopOfBook {
+                    bid,
+                    ask,
+                    last_trade,
+                    seq: before / 2,
+                };
+            }
+        }
+    }
+
+    fn load(&self) -> (Option<Level>, Option<Level>, Option<i64>) {
+        let last_trade = self.last_trade.load(Ordering::Relaxed);
+        (
+            load_level(&self.bid, &self.bid_orders),
+            load_level(&self.ask, &self.ask_orders),
+            (last_trade != NONE).then_some(last_trade),
+        )
+    }
+}
+
+fn store_level(price_size: &[AtomicI64; 2], orders: &AtomicUsize, level: Option<Level>) {
+    let (price, size, count) = level.map_or((NONE, 0, 0), |l| (l.price, l.size, l.orders));
+    price_size[0].store(price, Ordering::Relaxed);
+    price_size[1].store(size, Ordering::Relaxed);
+    orders.store(count, Ordering::Relaxed);
+}
+
+fn load_level(price_size: &[AtomicI64; 2], orders: &AtomicUsize) -> Option<Level> {
+    let price = price_size[0].load(Ordering::Relaxed);
+    (price != NONE).then(|| Level {
+        price,
+        size: price_size[1].load(Ordering::Relaxed),
+        orders: orders.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BookService, ServiceError};
+    use crate::orderlib::{
+        Level, Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Limit,
+        OrderType::Market, RejectReason, SessionState,
+    };
+    use std::thread;
+
+    #[test]
+    fn test_submit_and_top() {
+        let service = BookService::spawn(OrderBook::new());
+        let handle = service.handle();
+        let start = handle.top();
+        assert_eq!((start.bid, start.ask, start.last_trade), (None, None, None));
+        let bid = handle.submit(Order::new(Buy, 10, 100, Limit));
+        let ask = handle.submit(Order::new(Sell, 5, 102, Limit));
+        let bid = bid.wait().unwrap();
+        ask.wait().unwrap();
+        let fills = handle
+            .submit(Order::new(Sell, 4, 0, Market))
+            .wait()
+            .unwrap()
+            .fills;
+        assert_eq!(fills[0].passive_number, bid.order_number);
+        let top = handle.top();
+        let level = |price, size| {
+            Some(Level {
+                price,
+                size,
+                orders: 1,
+            })
+        };
+        assert_eq!(top.bid, level(100, 6));
+        assert_eq!(top.ask, level(102, 5));
+        assert_eq!(top.last_trade, Some(100));
+        assert_eq!(top.seq, start.seq + 3);
+        assert_eq!(
+            handle
+                .cancel(bid.order_number)
+                .wait()
+                .unwrap()
+                .unwrap()
+                .size,
+            6
+        );
+        assert_eq!(handle.top().bid, None);
+        let book = service.shutdown();
+        assert_eq!(book.len_offers(), 1);
+        assert_eq!(
+            handle
+                .submit(Order::new(Buy, 1, 1, Limit))
+                .wait()
+                .unwrap_err(),
+            ServiceError::Stopped
+        );
+    }
+
+    #[test]
+    fn test_rejections() {
+        let service = BookService::spawn(OrderBook::new());
+        let handle = service.handle();
+        handle.transition(SessionState::Halted).wait().unwrap();
+        assert_eq!(
+            handle
+                .submit(Order::new(Buy, 1, 1, Market))
+                .wait()
+                .unwrap_err(),
+            ServiceError::Rejected(RejectReason::Session(SessionState::Halted, Market))
+        );
+        assert!(matches!(
+            handle.transition(SessionState::PreOpen).wait(),
+            Err(ServiceError::Transition(_))
+        ));
+    }
+
+    #[test]
+    fn test_concurrent_writers_and_readers() {
+        let service = BookService::spawn(OrderBook::new());
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let handle = service.handle();
+                thread::spawn(move || {
+                    let replies: Vec<_> = (0..250)
+                        .map(|j| {
+                            let price = 100 + (i * 250 + j) % 50;
+                            handle.submit(Order::new(Buy, 1, price, Limit))
+                        })
+                        .collect();
+                    for reply in replies {
+                        reply.wait().unwrap();
+                    }
+                })
+            })
+            .collect();
+        let reader = service.handle();
+        let reader = thread::spawn(move || {
+            let mut last = 0;
+            for _ in 0..1000 {
+                let top = reader.top();
+                assert!(top.seq >= last);
+                last = top.seq;
+                if let Some(bid) = top.bid {
+                    assert!(bid.size >= 1 && bid.orders as i64 == bid.size);
+                }
+            }
+        });
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        reader.join().unwrap();
+        let snapshot = service.handle().snapshot().wait().unwrap();
+        assert_eq!(snapshot.bids.len(), 1000);
+        assert_eq!(service.handle().top().bid.unwrap().price, 149);
+        assert_eq!(service.handle().top().bid.unwrap().size, 20);
+    }
+}
@@ -0,0 +1,252 @@
+//! Running trade statistics and OHLCV bars built from the `Fill`s the book
+//! returns.
+use crate::orderlib::Fill;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+/// One OHLCV bar covering `[start, start + interval)` milliseconds.  `open` and
+/// `close` are the prices of its earliest and latest fills by timestamp, which
+/// `first` and `last` hold; of fills with the same timestamp, the one recorded
+/// later counts as later.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Bar {
+    pub start: i64,
+    pub open: i64,
+    pub high: i64,
+    pub low: i64,
+    pub close: i64,
+    pub volume: i64,
+    /// Sum of size times price, for the bar's VWAP.
+    pub notional: i64,
+    pub trades: usize,
+    pub first: i64,
+    pub last: i64,
+}
+
+impl Bar {
+    pub fn vwap(&self) -> f64 {
+        self.notional as f64 / self.volume as f64
+    }
+}
+
+/// Accumulates fills into totals and fixed-interval bars.  Bars are keyed by fill
+/// `timestamp`, so fills may arrive out of order; intervals without any fills get
+/// no bar.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TradeStats {
+    interval: i64,
+    // Price and timestamp of the latest fill by timestamp.
+    last_price: Option<i64>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    last_timestamp: Option<i64>,
+    volume: i64,
+    notional: i64,
+    trades: usize,
+    bars: Vec<Bar>,
+}
+
+impl TradeStats {
+    /// `interval` is the bar length in milliseconds and must be positive.
+    pub fn new(interval: i64) -> TradeStats {
+        assert!(interval > 0, "bar interval must be positive");
+        TradeStats {
+            interval,
+            last_price: None,
+            last_timestamp: None,
+            volume: 0,
+            notional: 0,
+            trades: 0,
+            bars: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, fill: &Fill) {
+        if self
+            .last_timestamp
+            .is_none_or(|last| fill.timestamp >= last)
+        {
+            self.last_price = Some(fill.price);
+            self.last_timestamp = Some(fill.timestamp);
+        }
+        self.volume += fill.size;
+        self.notional += fill.size * fill.price;
+        self.trades += 1;
+        let start = fill.timestamp - fill.timestamp.rem_euclid(self.interval);
+        // Fills nearly always land in the newest bar, so look there first.
+        let index = match self.bars.last() {
+            Some(bar) if bar.start == start => Ok(self.bars.len() - 1),
+            _ => self.bars.binary_search_by_key(&start, |bar| bar.start),
+        };
+        match index {
+            Ok(i) => {
+                let bar = &mut self.bars[i];
+                bar.high = bar.high.max(fill.price);
+                bar.low = bar.low.min(fill.price);
+                if fill.timestamp < bar.first {
+                    bar.open = fill.price;
+                    bar.first = fill.timestamp;
+                }
+                if fill.timestamp >= bar.last {
+                    bar.close = fill.price;
+                    bar.last = fill.timestamp;
+                }
+                bar.volume += fill.size;
+                bar.notional += fill.size * fill.price;
+                bar.trades += 1;
+            }
+            Err(i) => self.bars.insert(
+                i,
+                Bar {
+                    start,
+                    open: fill.price,
+                    high: fill.price,
+                    low: fill.price,
+                    close: fill.price,
+                    volume: fill.size,
+                    notional: fill.size * fill.price,
+                    trades: 1,
+                    first: fill.timestamp,
+                    last: fill.timestamp,
+                },
+            ),
+        }
+    }
+
+    pub fn interval(&self) -> i64 {
+        self.interval
+    }
+
+    /// Price of the latest fill by timestamp, whatever order fills arrived in.
+    pub fn last_price(&self) -> Option<i64> {
+        self.last_price
+    }
+
+    pub fn vwap(&self) -> Option<f64> {
+        (self.volume > 0).then(|| self.notional as f64 / self.volume as f64)
+    }
+
+    pub fn volume(&self) -> i64 {
+        self.volume
+    }
+
+    pub fn trade_count(&self) -> usize {
+        self.trades
+    }
+
+    /// All bars so far, oldest first.  The last one may still be filling.
+    pub fn bars(&self) -> &[Bar] {
+        &self.bars
+    }
+
+    /// Removes and returns the bars that start before `timestamp`'s interval, i.e.
+    /// those that can no longer change if fills arrive in order.
+    pub fn take_closed_bars(&mut self, timestamp: i64) -> Vec<Bar> {
+        let start = timestamp - timestamp.rem_euclid(self.interval);
+        let open = self.bars.partition_point(|bar| bar.start < start);
+        self.bars.drain(..open).collect()
+    }
+}
+
+impl<'a> Extend<&'a Fill> for TradeStats {
+    fn extend<I: IntoIterator<Item = &'a Fill>>(&mut self, fills: I) {
+        for fill in fills {
+            self.record(fill);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bar, TradeStats};
+    use crate::orderlib::{Fill, OrderSide::Buy};
+
+    fn fill(timestamp: i64, size: i64, price: i64) -> Fill {
+        Fill {
+            size,
+            price,
+            direction: Buy,
+            aggressor_id: 0,
+            passive_id: 0,
+            timestamp,
+            fill_id: 0,
+            aggressor_number: 0,
+            passive_number: 0,
+            aggressor_account: 0,
+            passive_account: 0,
+            aggressor_fee: 0,
+            passive_fee: 0,
+        }
+    }
+
+    #[test]
+    fn test_totals_and_bars() {
+        let mut stats = TradeStats::new(1000);
+        assert_eq!(stats.vwap(), None);
+        let fills = [
+            fill(1000, 10, 100),
+            fill(1500, 5, 103),
+            fill(1999, 5, 99),
+            fill(3200, 20, 101),
+            // Late fill for the first bar, which moves neither its close nor the
+            // last price.
+            fill(1700, 10, 104),
+        ];
+        stats.extend(fills.iter());
+        assert_eq!(stats.last_price(), Some(101));
+        assert_eq!(stats.volume(), 50);
+        assert_eq!(stats.trade_count(), 5);
+        assert_eq!(stats.vwap(), Some(5070.0 / 50.0));
+        assert_eq!(
+            stats.bars(),
+            &[
+                Bar {
+                    start: 1000,
+                    open: 100,
+                    high: 104,
+                    low: 99,
+                    close: 99,
+                    volume: 30,
+                    notional: 3050,
+                    trades: 4,
+                    first: 1000,
+                    last: 1999,
+                },
+                Bar {
+                    start: 3000,
+                    open: 101,
+                    high: 101,
+                    low: 101,
+                    close: 101,
+                    volume: 20,
+                    notional: 2020,
+                    trades: 1,
+                    first: 3200,
+                    last: 3200,
+                },
+            ]
+        );
+        assert_eq!(stats.bars()[1].vwap(), 101.0);
+        // An earlier fill arriving late becomes the bar's open.
+        stats.record(&fill(3100, 1, 97));
+        assert_eq!((stats.bars()[1].open, stats.bars()[1].close), (97, 101));
+        stats.record(&fill(2500, 1, 98));
+        assert_eq!(stats.last_price(), Some(101));
+        assert_eq!(stats.bars()[1].start, 2000);
+        let closed = stats.take_closed_bars(3999);
+        assert_eq!(
+            closed.iter().map(|bar| bar.start).collect::<Vec<_>>(),
+            [1000, 2000]
+        );
+        assert_eq!(stats.bars().len(), 1);
+        assert_eq!(stats.volume(), 52);
+    }
+
+    #[test]
+    fn test_negative_timestamps_floor() {
+        let mut stats = TradeStats::new(60_000);
+        stats.record(&fill(-1, 1, 100));
+        assert_eq!(stats.bars()[0].start, -60_000);
+    }
+}
@@ -0,0 +1,176 @@
+//! Fixed-width binary protocols for low-latency access to an `OrderBook`:
+//! `ouch` for order entry and `itch` for market data.  All integers are big-endian.
+//! Decoding works on borrowed byte slices and encoding writes into caller-owned
+//! buffers, so neither allocates.  Decoders never panic, whatever the input.
+use crate::orderlib::{OrderSide, OrderType, SessionState};
+use std::fmt;
+
+pub mod itch;
+pub mod ouch;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireError {
+    /// The input is shorter than the message it starts.
+    Incomplete,
+    /// The output buffer cannot hold the message.
+    BufferTooSmall,
+    UnknownType(u8),
+    /// A field holds a value outside its code set.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for WireError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WireError::Incomplete => write!(f, "incomplete message"),
+            WireError::BufferTooSmall => write!(f, "output buffer too small"),
+            WireError::UnknownType(t) => write!(f, "unknown message type {:#04x}", t),
+            WireError::Invalid(field) => write!(f, "invalid {}", field),
+        }
+    }
+}
+
+impl std::error::Error for WireError {}
+
+pub(crate) struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    /// Checks that a `len` byte message is available and skips its type byte.
+    pub(crate) fn new(buf: &'a [u8], len: usize) -> Result<Reader<'a>, WireError> {
+        if buf.len() < len {
+            return Err(WireError::Incomplete);
+        }
+        Ok(Reader { buf, pos: 1 })
+    }
+
+    fn take<const N: usize>(&mut self) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        bytes.copy_from_slice(&self.buf[self.pos..self.pos + N]);
+        self.pos += N;
+        bytes
+    }
+
+    pub(crate) fn u8(&mut self) -> u8 {
+        self.take::<1>()[0]
+    }
+
+    pub(crate) fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.take())
+    }
+
+    pub(crate) fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.take())
+    }
+
+    pub(crate) fn i64(&mut self) -> i64 {
+        i64::from_be_bytes(self.take())
+    }
+}
+
+pub(crate) struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> Writer<'a> {
+    /// Checks that `len` bytes fit and writes the type byte.
+    pub(crate) fn new(buf: &'a mut [u8], len: usize, kind: u8) -> Result<Writer<'a>, WireError> {
+        if buf.len() < len {
+            return Err(WireError::BufferTooSmall);
+        }
+        buf[0] = kind;
+        Ok(Writer { buf, pos: 1 })
+    }
+
+    fn put(&mut self, bytes: &[u8]) -> &mut Self {
+        self.buf[self.pos..self.pos + bytes.len()].copy_from_slice(bytes);
+        self.pos += bytes.len();
+        self
+    }
+
+    pub(crate) fn u8(&mut self, v: u8) -> &mut Self {
+        self.put(&[v])
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) -> &mut Self {
+        self.put(&v.to_be_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, v: u64) -> &mut Self {
+        self.put(&v.to_be_bytes())
+    }
+
+    pub(crate) fn i64(&mut self, v: i64) -> &mut Self {
+        self.put(&v.to_be_bytes())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.pos
+    }
+}
+
+// Converts a book value into a wire field's type, or names the field that
+// cannot hold it.
+pub(crate) fn narrow<T: TryFrom<i64>>(value: i64, field: &'static str) -> Result<T, WireError> {
+    T::try_from(value).map_err(|_| WireError::Invalid(field))
+}
+
+pub(crate) fn side_code(side: OrderSide) -> u8 {
+    match side {
+        OrderSide::Buy => b'B',
+        OrderSide::Sell => b'S',
+    }
+}
+
+pub(crate) fn side_from_code(code: u8) -> Result<OrderSide, WireError> {
+    match code {
+        b'B' => Ok(OrderSide::Buy),
+        b'S' => Ok(OrderSide::Sell),
+        _ => Err(WireError::Invalid("side")),
+    }
+}
+
+pub(crate) fn order_type_code(order_type: OrderType) -> u8 {
+    match order_type {
+        OrderType::Limit => b'L',
+        OrderType::Market => b'M',
+        OrderType::Fok => b'F',
+        OrderType::Ioc => b'I',
+        OrderType::Aon => b'A',
+    }
+}
+
+pub(crate) fn order_type_from_code(code: u8) -> Result<OrderType, WireError> {
+    match code {
+        b'L' => Ok(OrderType::Limit),
+        b'M' => Ok(OrderType::Market),
+        b'F' => Ok(OrderType::Fok),
+        b'I' => Ok(OrderType::Ioc),
+        b'A' => Ok(OrderType::Aon),
+        _ => Err(WireError::Invalid("order type")),
+    }
+}
+
+pub(crate) fn session_code(state: SessionState) -> u8 {
+    match state {
+        SessionState::PreOpen => b'P',
+        SessionState::OpeningAuction => b'O',
+        SessionState::Continuous => b'Q',
+        SessionState::Halted => b'H',
+        SessionState::Closed => b'C',
+    }
+}
+
+pub(crate) fn session_from_code(code: u8) -> Result<SessionState, WireError> {
+    match code {
+        b'P' => Ok(SessionState::PreOpen),
+        b'O' => Ok(SessionState::OpeningAuction),
+        b'Q' => Ok(SessionState::Continuous),
+        b'H' => Ok(SessionState::Halted),
+        b'C' => Ok(SessionState::Closed),
+        _ => Err(WireError::Invalid("session state")),
+    }
+}
@@ -0,0 +1,234 @@
+//! ITCH-style market data: a public, order-by-order view of the book keyed by
+//! the book's order numbers.
+//!
+//! | Message       | Type | Layout after the type byte                                   | Len |
+//! |---------------|------|--------------------------------------------------------------|-----|
+//! | SystemEvent   | `S`  | time u64, state u8                                           | 10  |
+//! | AddOrder      | `A`  | time u64, order number u64, side u8, quantity u32, price i64 | 30  |
+//! | OrderExecuted | `E`  | time u64, order number u64, quantity u32, price i64, match number u64 | 37 |
+//! | OrderDelete   | `D`  | time u64, order number u64                                   | 17  |
+use super::{narrow, session_code, session_from_code, side_code, side_from_code};
+use super::{Reader, WireError, Writer};
+use crate::orderlib::{Fill, Order, OrderSide, SessionEvent, SessionState};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarketData {
+    SystemEvent {
+        timestamp: u64,
+        state: SessionState,
+    },
+    AddOrder {
+        timestamp: u64,
+        order_number: u64,
+        side: OrderSide,
+        quantity: u32,
+        price: i64,
+    },
+    OrderExecuted {
+        timestamp: u64,
+        order_number: u64,
+        quantity: u32,
+        price: i64,
+        match_number: u64,
+    },
+    OrderDelete {
+        timestamp: u64,
+        order_number: u64,
+    },
+}
+
+impl MarketData {
+    /// The constructors fail with `WireError::Invalid` if a value does not fit
+    /// its field, e.g. a size over `u32::MAX` or a negative timestamp.
+    pub fn system_event(event: &SessionEvent) -> Result<MarketData, WireError> {
+        Ok(MarketData::SystemEvent {
+            timestamp: narrow(event.timestamp, "timestamp")?,
+            state: event.to,
+        })
+    }
+
+    /// A newly resting order, e.g. the `remaining` part of an `ExecutionReport`.
+    pub fn add_order(order: &Order, resting_size: i64) -> Result<MarketData, WireError> {
+        Ok(MarketData::AddOrder {
+            timestamp: narrow(order.timestamp, "timestamp")?,
+            order_number: narrow(order.order_number, "order number")?,
+            side: order.order_side,
+            quantity: narrow(resting_size, "quantity")?,
+            price: order.price,
+        })
+    }
+
+    /// An execution against a resting order.
+    pub fn order_executed(fill: &Fill) -> Result<MarketData, WireError> {
+        Ok(MarketData::OrderExecuted {
+            timestamp: narrow(fill.timestamp, "timestamp")?,
+            order_number: narrow(fill.passive_number, "order number")?,
+            quantity: narrow(fill.size, "quantity")?,
+            price: fill.price,
+            match_number: narrow(fill.fill_id, "match number")?,
+        })
+    }
+
+    pub fn order_delete(order: &Order, timestamp: i64) -> Result<MarketData, WireError> {
+        Ok(MarketData::OrderDelete {
+            timestamp: narrow(timestamp, "timestamp")?,
+            order_number: narrow(order.order_number, "order number")?,
+        })
+    }
+
+    /// Decodes the message at the front of `buf`, returning it and its length.
+    pub fn decode(buf: &[u8]) -> Result<(MarketData, usize), WireError> {
+        match buf.first() {
+            None => Err(WireError::Incomplete),
+            Some(b'S') => {
+                let mut r = Reader::new(buf, 10)?;
+                let timestamp = r.u64();
+                let state = session_from_code(r.u8())?;
+                Ok((MarketData::SystemEvent { timestamp, state }, 10))
+            }
+            Some(b'A') => {
+                let mut r = Reader::new(buf, 30)?;
+                let timestamp = r.u64();
+                let order_number = r.u64();
+                let side = side_from_code(r.u8())?;
+                let message = MarketData::AddOrder {
+                    timestamp,
+                    order_number,
+                    side,
+                    quantity: r.u32(),
+                    price: r.i64(),
+                };
+                Ok((message, 30))
+            }
+            Some(b'E') => {
+                let mut r = Reader::new(buf, 37)?;
+                let message = MarketData::OrderExecuted {
+                    timestamp: r.u64(),
+                    order_number: r.u64(),
+                    quantity: r.u32(),
+                    price: r.i64(),
+                    match_number: r.u64(),
+                };
+                Ok((message, 37))
+            }
+            Some(b'D') => {
+                let mut r = Reader::new(buf, 17)?;
+                let message = MarketData::OrderDelete {
+                    timestamp: r.u64(),
+                    order_number: r.u64(),
+                };
+                Ok((message, 17))
+            }
+            Some(other) => Err(WireError::UnknownType(*other)),
+        }
+    }
+
+    /// Encodes the message into `out`, returning the number of bytes written.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, WireError> {
+        let written = match *self {
+            MarketData::SystemEvent { timestamp, state } => {
+                let mut w = Writer::new(out, 10, b'S')?;
+                w.u64(timestamp).u8(session_code(state));
+                w.len()
+            }
+            MarketData::AddOrder {
+                timestamp,
+                order_number,
+                side,
+                quantity,
+                price,
+            } => {
+                let mut w = Writer::new(out, 30, b'A')?;
+                w.u64(timestamp)
+                    .u64(order_number)
+                    .u8(side_code(side))
+                    .u32(quantity)
+                    .i64(price);
+                w.len()
+            }
+            MarketData::OrderExecuted {
+                timestamp,
+                order_number,
+                quantity,
+                price,
+                match_number,
+            } => {
+                let mut w = Writer::new(out, 37, b'E')?;
+                w.u64(timestamp)
+                    .u64(order_number)
+                    .u32(quantity)
+                    .i64(price)
+                    .u64(match_number);
+                w.len()
+            }
+            MarketData::OrderDelete {
+                timestamp,
+                order_number,
+            } => {
+                let mut w = Writer::new(out, 17, b'D')?;
+                w.u64(timestamp).u64(order_number);
+                w.len()
+            }
+        };
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MarketData;
+    use crate::orderlib::{
+        Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Limit, SessionState,
+    };
+    use crate::wire::WireError;
+
+    #[test]
+    fn test_book_events_round_trip() {
+        let mut order_book = OrderBook::new();
+        let resting_number = order_book.add(Order::new(Buy, 20, 100, Limit)).0;
+        let incoming = Order::new(Sell, 30, 100, Limit);
+        let report = order_book.try_add(incoming).unwrap();
+        order_book.transition(SessionState::Halted).unwrap();
+        let resting = order_book.best_offer().unwrap();
+        let messages = [
+            MarketData::order_executed(&report.fills[0]).unwrap(),
+            MarketData::add_order(&resting, report.remaining).unwrap(),
+            MarketData::order_delete(&resting, 5).unwrap(),
+            MarketData::system_event(&order_book.drain_session_events()[0]).unwrap(),
+        ];
+        match messages[0] {
+            MarketData::OrderExecuted {
+                order_number,
+                quantity,
+                match_number,
+                ..
+            } => assert_eq!(
+                (order_number, quantity, match_number),
+                (resting_number as u64, 20, 1)
+            ),
+            other => panic!("unexpected {:?}", other),
+        }
+        // Values that do not fit a field are refused, not truncated.
+        assert_eq!(
+            MarketData::add_order(&resting, 1 << 32),
+            Err(WireError::Invalid("quantity"))
+        );
+        assert_eq!(
+            MarketData::order_delete(&resting, -1),
+            Err(WireError::Invalid("timestamp"))
+        );
+        let mut buf = [0u8; 160];
+        let mut len = 0;
+        for message in messages.iter() {
+            len += message.encode(&mut buf[len..]).unwrap();
+        }
+        let mut pos = 0;
+        for message in messages.iter() {
+            let (decoded, used) = MarketData::decode(&buf[pos..len]).unwrap();
+            assert_eq!(decoded, *message);
+            pos += used;
+        }
+        assert_eq!(pos, len);
+        assert_eq!(MarketData::decode(&buf[..9]), Err(WireError::Incomplete));
+    }
+}
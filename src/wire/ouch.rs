@@ -0,0 +1,528 @@
+//! OUCH-style order entry.  Clients name their orders with a `token`, which
+//! travels through the book as `Order::order_id` and comes back on every
+//! outbound message about the order.
+//!
+//! | Message        | Dir | Type | Layout after the type byte                                  | Len |
+//! |----------------|-----|------|-------------------------------------------------------------|-----|
+//! | EnterOrder     | in  | `O`  | token u64, side u8, type u8, quantity u32, price i64        | 23  |
+//! | CancelOrder    | in  | `X`  | token u64                                                   | 9   |
+//! | ReplaceOrder   | in  | `U`  | token u64, new token u64, quantity u32, price i64           | 29  |
+//! | Accepted       | out | `A`  | time u64, token u64, side u8, type u8, quantity u32, price i64, order number u64 | 39 |
+//! | Executed       | out | `E`  | time u64, token u64, quantity u32, price i64, match number u64 | 37 |
+//! | Canceled       | out | `C`  | time u64, token u64, quantity u32, reason u8                | 22  |
+//! | Replaced       | out | `U`  | time u64, token u64, previous token u64, quantity u32, price i64, order number u64 | 45 |
+//! | Rejected       | out | `J`  | time u64, token u64, reason u8                              | 18  |
+use super::{narrow, order_type_code, order_type_from_code, side_code, side_from_code};
+use super::{Reader, WireError, Writer};
+use crate::orderlib::{ExecutionReport, Fill, Order, OrderSide, OrderType, RejectReason};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EnterOrder {
+    pub token: u64,
+    pub side: OrderSide,
+    pub order_type: OrderType,
+    pub quantity: u32,
+    pub price: i64,
+}
+
+impl EnterOrder {
+    /// The order to add to the book.  Fails with `WireError::Invalid` if the token
+    /// does not fit an `Order::order_id`.
+    pub fn to_order(&self) -> Result<Order, WireError> {
+        let mut order = Order::new(
+            self.side,
+            i64::from(self.quantity),
+            self.price,
+            self.order_type,
+        );
+        order.order_id = i64::try_from(self.token).map_err(|_| WireError::Invalid("token"))?;
+        Ok(order)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CancelOrder {
+    pub token: u64,
+}
+
+/// Replaces a resting order with a new one under `new_token`.  The replacement
+/// loses time priority.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReplaceOrder {
+    pub token: u64,
+    pub new_token: u64,
+    pub quantity: u32,
+    pub price: i64,
+}
+
+/// A message from a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Inbound {
+    Enter(EnterOrder),
+    Cancel(CancelOrder),
+    Replace(ReplaceOrder),
+}
+
+impl Inbound {
+    /// Decodes the message at the front of `buf`, returning it and its length.
+    pub fn decode(buf: &[u8]) -> Result<(Inbound, usize), WireError> {
+        match buf.first() {
+            None => Err(WireError::Incomplete),
+            Some(b'O') => {
+                let mut r = Reader::new(buf, 23)?;
+                let token = r.u64();
+                let side = side_from_code(r.u8())?;
+                let order_type = order_type_from_code(r.u8())?;
+                let message = EnterOrder {
+                    token,
+                    side,
+                    order_type,
+                    quantity: r.u32(),
+                    price: r.i64(),
+                };
+                Ok((Inbound::Enter(message), 23))
+            }
+            Some(b'X') => {
+                let mut r = Reader::new(buf, 9)?;
+                Ok((Inbound::Cancel(CancelOrder { token: r.u64() }), 9))
+            }
+            Some(b'U') => {
+                let mut r = Reader::new(buf, 29)?;
+                let message = ReplaceOrder {
+                    token: r.u64(),
+                    new_token: r.u64(),
+                    quantity: r.u32(),
+                    price: r.i64(),
+                };
+                Ok((Inbound::Replace(message), 29))
+            }
+            Some(other) => Err(WireError::UnknownType(*other)),
+        }
+    }
+
+    /// Encodes the message into `out`, returning the number of bytes written.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, WireError> {
+        let w = match self {
+            Inbound::Enter(m) => {
+                let mut w = Writer::new(out, 23, b'O')?;
+                w.u64(m.token)
+                    .u8(side_code(m.side))
+                    .u8(order_type_code(m.order_type))
+                    .u32(m.quantity)
+                    .i64(m.price);
+                w.len()
+            }
+            Inbound::Cancel(m) => {
+                let mut w = Writer::new(out, 9, b'X')?;
+                w.u64(m.token);
+                w.len()
+            }
+            Inbound::Replace(m) => {
+                let mut w = Writer::new(out, 29, b'U')?;
+                w.u64(m.token).u64(m.new_token).u32(m.quantity).i64(m.price);
+                w.len()
+            }
+        };
+        Ok(w)
+    }
+}
+
+/// Why an order was refused or taken off the book.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reason {
+    /// Cancelled at the client's request.
+    User,
+    /// Not filled immediately and not allowed to rest, e.g. an `Ioc` remainder.
+    Immediate,
+    /// Refused in the current trading session state.
+    Session,
+    /// The book's journal could not be written.
+    Journal,
+    /// The account cannot cover the order.
+    BuyingPower,
+    /// Quantity times price is out of range.
+    Overflow,
+    /// A replace did more than reduce the order's quantity.
+    Amend,
+    /// The token does not name a live order.
+    UnknownToken,
+}
+
+impl Reason {
+    fn code(self) -> u8 {
+        match self {
+            Reason::User => b'U',
+            Reason::Immediate => b'I',
+            Reason::Session => b'S',
+            Reason::Journal => b'J',
+            Reason::BuyingPower => b'B',
+            Reason::Overflow => b'O',
+            Reason::Amend => b'A',
+            Reason::UnknownToken => b'T',
+        }
+    }
+
+    fn from_code(code: u8) -> Result<Reason, WireError> {
+        match code {
+            b'U' => Ok(Reason::User),
+            b'I' => Ok(Reason::Immediate),
+            b'S' => Ok(Reason::Session),
+            b'J' => Ok(Reason::Journal),
+            b'B' => Ok(Reason::BuyingPower),
+            b'O' => Ok(Reason::Overflow),
+            b'A' => Ok(Reason::Amend),
+            b'T' => Ok(Reason::UnknownToken),
+            _ => Err(WireError::Invalid("reason")),
+        }
+    }
+}
+
+impl From<RejectReason> for Reason {
+    fn from(reason: RejectReason) -> Reason {
+        match reason {
+            RejectReason::Session(..) => Reason::Session,
+            RejectReason::Journal => Reason::Journal,
+            RejectReason::BuyingPower { .. } => Reason::BuyingPower,
+            RejectReason::Overflow => Reason::Overflow,
+            RejectReason::Amend => Reason::Amend,
+        }
+    }
+}
+
+/// A message to a client.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outbound {
+    Accepted {
+        timestamp: u64,
+        token: u64,
+        side: OrderSide,
+        order_type: OrderType,
+        quantity: u32,
+        price: i64,
+        order_number: u64,
+    },
+    Executed {
+        timestamp: u64,
+        token: u64,
+        quantity: u32,
+        price: i64,
+        match_number: u64,
+    },
+    Canceled {
+        timestamp: u64,
+        token: u64,
+        quantity: u32,
+        reason: Reason,
+    },
+    Replaced {
+        timestamp: u64,
+        token: u64,
+        previous_token: u64,
+        quantity: u32,
+        price: i64,
+        order_number: u64,
+    },
+    Rejected {
+        timestamp: u64,
+        token: u64,
+        reason: Reason,
+    },
+}
+
+impl Outbound {
+    /// The accepted message for an order the book has numbered.  Fails with
+    /// `WireError::Invalid` if a value does not fit its field, e.g. a size over
+    /// `u32::MAX`.
+    pub fn accepted(order: &Order, report: &ExecutionReport) -> Result<Outbound, WireError> {
+        Ok(Outbound::Accepted {
+            timestamp: narrow(report.timestamp, "timestamp")?,
+            token: narrow(order.order_id, "token")?,
+            side: order.order_side,
+            order_type: order.order_type,
+            quantity: narrow(order.size, "quantity")?,
+            price: order.price,
+            order_number: narrow(report.order_number, "order number")?,
+        })
+    }
+
+    /// The execution as seen by the incoming order.
+    pub fn aggressor_executed(fill: &Fill) -> Result<Outbound, WireError> {
+        Outbound::executed(fill, fill.aggressor_id)
+    }
+
+    /// The execution as seen by the resting order.
+    pub fn passive_executed(fill: &Fill) -> Result<Outbound, WireError> {
+        Outbound::executed(fill, fill.passive_id)
+    }
+
+    fn executed(fill: &Fill, token: i64) -> Result<Outbound, WireError> {
+        Ok(Outbound::Executed {
+            timestamp: narrow(fill.timestamp, "timestamp")?,
+            token: narrow(token, "token")?,
+            quantity: narrow(fill.size, "quantity")?,
+            price: fill.price,
+            match_number: narrow(fill.fill_id, "match number")?,
+        })
+    }
+
+    /// Decodes the message at the front of `buf`, returning it and its length.
+    pub fn decode(buf: &[u8]) -> Result<(Outbound, usize), WireError> {
+        match buf.first() {
+            None => Err(WireError::Incomplete),
+            Some(b'A') => {
+                let mut r = Reader::new(buf, 39)?;
+                let timestamp = r.u64();
+                let token = r.u64();
+                let side = side_from_code(r.u8())?;
+                let order_type = order_type_from_code(r.u8())?;
+                let message = Outbound::Accepted {
+                    timestamp,
+                    token,
+                    side,
+                    order_type,
+                    quantity: r.u32(),
+                    price: r.i64(),
+                    order_number: r.u64(),
+                };
+                Ok((message, 39))
+            }
+            Some(b'E') => {
+                let mut r = Reader::new(buf, 37)?;
+                let message = Outbound::Executed {
+                    timestamp: r.u64(),
+                    token: r.u64(),
+                    quantity: r.u32(),
+                    price: r.i64(),
+                    match_number: r.u64(),
+                };
+                Ok((message, 37))
+            }
+            Some(b'C') => {
+                let mut r = Reader::new(buf, 22)?;
+                let message = Outbound::Canceled {
+                    timestamp: r.u64(),
+                    token: r.u64(),
+                    quantity: r.u32(),
+                    reason: Reason::from_code(r.u8())?,
+                };
+                Ok((message, 22))
+            }
+            Some(b'U') => {
+                let mut r = Reader::new(buf, 45)?;
+                let message = Outbound::Replaced {
+                    timestamp: r.u64(),
+                    token: r.u64(),
+                    previous_token: r.u64(),
+                    quantity: r.u32(),
+                    price: r.i64(),
+                    order_number: r.u64(),
+                };
+                Ok((message, 45))
+            }
+            Some(b'J') => {
+                let mut r = Reader::new(buf, 18)?;
+                let message = Outbound::Rejected {
+                    timestamp: r.u64(),
+                    token: r.u64(),
+                    reason: Reason::from_code(r.u8())?,
+                };
+                Ok((message, 18))
+            }
+            Some(other) => Err(WireError::UnknownType(*other)),
+        }
+    }
+
+    /// Encodes the message into `out`, returning the number of bytes written.
+    pub fn encode(&self, out: &mut [u8]) -> Result<usize, WireError> {
+        let written = match *self {
+            Outbound::Accepted {
+                timestamp,
+                token,
+                side,
+                order_type,
+                quantity,
+                price,
+                order_number,
+            } => {
+                let mut w = Writer::new(out, 39, b'A')?;
+                w.u64(timestamp)
+                    .u64(token)
+                    .u8(side_code(side))
+                    .u8(order_type_code(order_type))
+                    .u32(quantity)
+                    .i64(price)
+                    .u64(order_number);
+                w.len()
+            }
+            Outbound::Executed {
+                timestamp,
+                token,
+                quantity,
+                price,
+                match_number,
+            } => {
+                let mut w = Writer::new(out, 37, b'E')?;
+                w.u64(timestamp)
+                    .u64(token)
+                    .u32(quantity)
+                    .i64(price)
+                    .u64(match_number);
+                w.len()
+            }
+            Outbound::Canceled {
+                timestamp,
+                token,
+                quantity,
+                reason,
+            } => {
+                let mut w = Writer::new(out, 22, b'C')?;
+                w.u64(timestamp).u64(token).u32(quantity).u8(reason.code());
+                w.len()
+            }
+            Outbound::Replaced {
+                timestamp,
+                token,
+                previous_token,
+                quantity,
+                price,
+                order_number,
+            } => {
+                let mut w = Writer::new(out, 45, b'U')?;
+                w.u64(timestamp)
+                    .u64(token)
+                    .u64(previous_token)
+                    .u32(quantity)
+                    .i64(price)
+                    .u64(order_number);
+                w.len()
+            }
+            Outbound::Rejected {
+                timestamp,
+                token,
+                reason,
+            } => {
+                let mut w = Writer::new(out, 18, b'J')?;
+                w.u64(timestamp).u64(token).u8(reason.code());
+                w.len()
+            }
+        };
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CancelOrder, EnterOrder, Inbound, Outbound, Reason, ReplaceOrder};
+    use crate::orderlib::{
+        Order, OrderBook, OrderSide::Buy, OrderSide::Sell, OrderType::Ioc, OrderType::Limit,
+    };
+    use crate::wire::WireError;
+
+    #[test]
+    fn test_inbound_round_trip() {
+        let messages = [
+            Inbound::Enter(EnterOrder {
+                token: 42,
+                side: Sell,
+                order_type: Ioc,
+                quantity: 300,
+                price: -7,
+            }),
+            Inbound::Cancel(CancelOrder { token: 42 }),
+            Inbound::Replace(ReplaceOrder {
+                token: 42,
+                new_token: 43,
+                quantity: 100,
+                price: 101,
+            }),
+        ];
+        let mut buf = [0u8; 64];
+        for message in messages.iter() {
+            let len = message.encode(&mut buf).unwrap();
+            assert_eq!(Inbound::decode(&buf[..len]), Ok((*message, len)));
+            assert_eq!(Inbound::decode(&buf[..len - 1]), Err(WireError::Incomplete));
+            assert_eq!(
+                message.encode(&mut buf[..len - 1]),
+                Err(WireError::BufferTooSmall)
+            );
+        }
+        assert_eq!(Inbound::decode(b"Z"), Err(WireError::UnknownType(b'Z')));
+        messages[0].encode(&mut buf).unwrap();
+        buf[9] = b'?';
+        assert_eq!(Inbound::decode(&buf), Err(WireError::Invalid("side")));
+    }
+
+    #[test]
+    fn test_decoders_survive_arbitrary_input() {
+        let mut seed: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut buf = [0u8; 48];
+        for _ in 0..10_000 {
+            for byte in buf.iter_mut() {
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                *byte = seed as u8;
+            }
+            buf[0] = b"OXUAECJ"[(seed % 7) as usize];
+            let len = (seed >> 8) as usize % buf.len();
+            let _ = Inbound::decode(&buf[..len]);
+            let _ = Outbound::decode(&buf[..len]);
+        }
+    }
+
+    #[test]
+    fn test_maps_book_results() {
+        let mut order_book = OrderBook::new();
+        let mut resting = Order::new(Buy, 20, 100, Limit);
+        resting.order_id = 7;
+        order_book.add(resting);
+        let enter = EnterOrder {
+            token: 9,
+            side: Sell,
+            order_type: Ioc,
+            quantity: 30,
+            price: 100,
+        };
+        let too_big = EnterOrder {
+            token: u64::MAX,
+            ..enter
+        };
+        assert_eq!(too_big.to_order(), Err(WireError::Invalid("token")));
+        let order = enter.to_order().unwrap();
+        let report = order_book.try_add(order).unwrap();
+        let mut buf = [0u8; 64];
+        match Outbound::accepted(&order, &report).unwrap() {
+            Outbound::Accepted {
+                timestamp,
+                token,
+                order_number,
+                ..
+            } => {
+                assert!(timestamp > 0);
+                assert_eq!(timestamp, report.timestamp as u64);
+                assert_eq!((token, order_number), (9, report.order_number as u64));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let executed = Outbound::passive_executed(&report.fills[0]).unwrap();
+        let len = executed.encode(&mut buf).unwrap();
+        assert_eq!(len, 37);
+        match Outbound::decode(&buf).unwrap().0 {
+            Outbound::Executed {
+                token,
+                quantity,
+                price,
+                ..
+            } => assert_eq!((token, quantity, price), (7, 20, 100)),
+            other => panic!("unexpected {:?}", other),
+        }
+        let canceled = Outbound::Canceled {
+            timestamp: 1,
+            token: 9,
+            quantity: u32::try_from(report.cancelled).unwrap(),
+            reason: Reason::Immediate,
+        };
+        let len = canceled.encode(&mut buf).unwrap();
+        assert_eq!(Outbound::decode(&buf[..len]), Ok((canceled, 22)));
+    }
+}